@@ -0,0 +1,95 @@
+//How many keep-alive connections get answered with a small pool, in the blocking mode and
+//the event mode. Run it with
+// cargo bench --bench connections > /dev/null
+//Every client connects, sends one request and then keeps the connection open, like a browser
+//does. In the blocking mode each of the first few connections holds on to a worker while it
+//waits for a next request, so the rest are stuck in the queue until those time out. In the
+//event mode the waiting is done by the poller and every one of them gets its answer.
+//
+//Each connection takes a file descriptor on both ends, so this needs `ulimit -n` to be a
+//good bit above twice CONNECTIONS. The results go to stderr like in the scheduler bench.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{ConnectionConfig, IoMode, Request, Response, Router, Server};
+
+const WORKERS: usize = 4;
+const CONNECTIONS: usize = 1000;
+//How long the clients wait for their answers.
+const DEADLINE: Duration = Duration::from_secs(2);
+
+struct Outcome {
+    answered: usize,
+    //How long it took until the last answer came in.
+    elapsed: Duration,
+}
+
+fn run(mode: IoMode) -> Outcome {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello\n"));
+    //Long enough that no connection is let go while the clients are waiting.
+    let config = ConnectionConfig { idle_timeout: Some(Duration::from_secs(30)), ..ConnectionConfig::default() };
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .workers(WORKERS)
+        .queue_capacity(CONNECTIONS)
+        .connection_config(config)
+        .grace_period(Duration::from_secs(1))
+        .io_mode(mode);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    let outcome = measure(addr);
+
+    handle.shutdown();
+    running.join().unwrap();
+    outcome
+}
+
+fn measure(addr: SocketAddr) -> Outcome {
+    let mut clients = Vec::with_capacity(CONNECTIONS);
+    for _ in 0..CONNECTIONS {
+        let mut client = TcpStream::connect(addr).expect("can't connect, is ulimit -n high enough?");
+        client.write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n").unwrap();
+        client.set_nonblocking(true).unwrap();
+        clients.push((client, false));
+    }
+
+    //Go round the clients picking up whatever answers have come in until they all have one
+    //or time's up. The responses are tiny so the status line arrives in the first read.
+    let start = Instant::now();
+    let mut answered = 0;
+    let mut elapsed = Duration::from_secs(0);
+    let mut buf = [0; 1024];
+    while answered < CONNECTIONS && start.elapsed() < DEADLINE {
+        for &mut (ref mut client, ref mut done) in clients.iter_mut().filter(|&&mut (_, done)| !done) {
+            match client.read(&mut buf) {
+                Ok(n) if n > 0 => {
+                    assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"), "{}", String::from_utf8_lossy(&buf[..n]));
+                    *done = true;
+                    answered += 1;
+                    elapsed = start.elapsed();
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                other => panic!("the connection broke: {:?}", other),
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    Outcome { answered, elapsed }
+}
+
+fn main() {
+    eprintln!("{} keep-alive connections, {} workers, {:?} to answer", CONNECTIONS, WORKERS, DEADLINE);
+    eprintln!("{:<10} {:>10} {:>12}", "mode", "answered", "last answer");
+    for &(name, mode) in &[("blocking", IoMode::Blocking), ("event", IoMode::Event)] {
+        let outcome = run(mode);
+        eprintln!("{:<10} {:>10} {:>10.1}ms", name, outcome.answered, outcome.elapsed.as_secs_f64() * 1000.0);
+    }
+}
@@ -0,0 +1,68 @@
+//Compares the shared queue against work stealing. Run it with
+// cargo bench --bench scheduler > /dev/null
+//There's no benchmark harness on stable Rust so this just times a batch of jobs a few
+//times over and prints the best run of each. The results go to stderr so the workers'
+//chatter on stdout can be thrown away with > /dev/null.
+extern crate mt_server;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{Scheduler, ThreadPool};
+
+const WORKERS: usize = 8;
+const RUNS: usize = 5;
+
+type Job = fn(u64) -> u64;
+
+//Lots of tiny jobs, which is where fighting over the queue hurts the most.
+fn cpu_job(n: u64) -> u64 {
+    let mut x = n;
+    for _ in 0..200 {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    }
+    x
+}
+
+//Jobs that mostly wait, standing in for a request that talks to a disk or the network.
+fn io_job(_: u64) -> u64 {
+    thread::sleep(Duration::from_millis(1));
+    0
+}
+
+fn run(scheduler: Scheduler, jobs: u64, job: Job) -> Duration {
+    let pool = ThreadPool::with_scheduler(WORKERS, scheduler);
+    let (sender, receiver) = mpsc::channel();
+    let start = Instant::now();
+    for i in 0..jobs {
+        let sender = sender.clone();
+        pool.execute(move || sender.send(job(i)).unwrap()).unwrap();
+    }
+    for _ in 0..jobs {
+        receiver.recv().unwrap();
+    }
+    start.elapsed()
+}
+
+fn best_of(scheduler: Scheduler, jobs: u64, job: Job) -> Duration {
+    (0..RUNS).map(|_| run(scheduler, jobs, job)).min().unwrap()
+}
+
+fn main() {
+    let workloads: [(&str, u64, Job); 2] = [("cpu-bound", 200_000, cpu_job), ("io-bound", 4_000, io_job)];
+
+    eprintln!("{:<10} {:>8} {:>14} {:>14} {:>8}", "workload", "jobs", "shared", "work stealing", "ratio");
+    for &(name, jobs, job) in &workloads {
+        let shared = best_of(Scheduler::Shared, jobs, job);
+        let stealing = best_of(Scheduler::WorkStealing, jobs, job);
+        eprintln!(
+            "{:<10} {:>8} {:>12.1}ms {:>12.1}ms {:>7.2}x",
+            name,
+            jobs,
+            shared.as_secs_f64() * 1000.0,
+            stealing.as_secs_f64() * 1000.0,
+            shared.as_secs_f64() / stealing.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,360 @@
+//The only trace a request used to leave was the worker's "got a job" println, which doesn't
+//even say what was asked for. connection::serve now fills in a LogEntry for every response
+//it sends and hands it to an AccessLog, if the server has one. FileLog is the AccessLog that
+//comes with the crate: it writes every entry as one line in the Common or Combined Log Format
+//or as JSON, and moves on to a fresh file once the current one has grown too big.
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use date;
+use request::{Method, Request, Version};
+
+/// Everything that gets logged about one request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// When the request finished arriving.
+    pub time: SystemTime,
+    /// The client's address, if the socket still knew it.
+    pub peer: Option<SocketAddr>,
+    /// The pool worker that served the request.
+    pub worker: Option<usize>,
+    pub method: Method,
+    /// The request target exactly as it was sent, query string included.
+    pub target: String,
+    pub version: Version,
+    pub status: u16,
+    /// The size of the response body that was sent, not counting the headers.
+    pub bytes: u64,
+    /// How long the request took from when it was read until the response was written.
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl LogEntry {
+    //Filled in before the handler gets the request; the rest comes once the response is out.
+    pub(crate) fn begin(request: &Request, peer: Option<SocketAddr>) -> LogEntry {
+        LogEntry {
+            time: SystemTime::now(),
+            peer,
+            worker: ::current_worker(),
+            method: request.method.clone(),
+            target: request.target.clone(),
+            version: request.version,
+            status: 0,
+            bytes: 0,
+            duration: Duration::from_secs(0),
+            referer: request.headers.get("Referer").map(String::from),
+            user_agent: request.headers.get("User-Agent").map(String::from),
+        }
+    }
+
+    /// Render the entry as a single line without the trailing newline.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => self.common(),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                quoted(self.referer.as_deref().unwrap_or("-")),
+                quoted(self.user_agent.as_deref().unwrap_or("-"))
+            ),
+            LogFormat::Json => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let host = self.peer.map_or("-".to_string(), |peer| peer.ip().to_string());
+        //The format wants a dash instead of a zero for an empty body.
+        let bytes = if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            host,
+            date::format_clf_date(self.time),
+            self.method,
+            quoted(&self.target),
+            self.version,
+            self.status,
+            bytes
+        )
+    }
+
+    fn json(&self) -> String {
+        let mut out = String::from("{");
+        let _ = write!(out, "\"time\":\"{}\"", date::format_rfc3339(self.time));
+        out.push_str(",\"peer\":");
+        json_string(&mut out, self.peer.map(|peer| peer.to_string()).as_deref());
+        out.push_str(",\"worker\":");
+        match self.worker {
+            Some(worker) => { let _ = write!(out, "{}", worker); }
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"method\":");
+        json_string(&mut out, Some(self.method.as_str()));
+        out.push_str(",\"target\":");
+        json_string(&mut out, Some(&self.target));
+        let _ = write!(
+            out,
+            ",\"version\":\"{}\",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3}",
+            self.version,
+            self.status,
+            self.bytes,
+            self.duration.as_secs_f64() * 1000.0
+        );
+        out.push_str(",\"referer\":");
+        json_string(&mut out, self.referer.as_deref());
+        out.push_str(",\"user_agent\":");
+        json_string(&mut out, self.user_agent.as_deref());
+        out.push('}');
+        out
+    }
+}
+
+//Quotes inside of a quoted log field are escaped the same way Apache does it.
+fn quoted(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn json_string(out: &mut String, s: Option<&str>) {
+    let s = match s {
+        Some(s) => s,
+        None => {
+            out.push_str("null");
+            return;
+        }
+    };
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// How `FileLog` writes its entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `host - - [time] "request line" status bytes`
+    Common,
+    /// The Common Log Format followed by the Referer and User-Agent.
+    Combined,
+    /// One JSON object per line with every field of the `LogEntry`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    /// Parses `common`, `combined` or `json`, in any case.
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match &s.to_ascii_lowercase()[..] {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected common, combined or json", s)),
+        }
+    }
+}
+
+/// Somewhere for the server to record the requests it served.
+///
+/// Called from the worker that served the request right after the response is written,
+/// so it should be quick. Closures taking a `&LogEntry` work as well.
+pub trait AccessLog: Send + Sync + 'static {
+    fn log(&self, entry: &LogEntry);
+}
+
+impl<F> AccessLog for F
+    where
+        F: Fn(&LogEntry) + Send + Sync + 'static
+{
+    fn log(&self, entry: &LogEntry) {
+        self(entry)
+    }
+}
+
+/// Writes the access log to a file, one line per request.
+///
+/// Once the file would grow past the rotation size it's renamed to `<path>.1`, what was
+/// `<path>.1` becomes `<path>.2` and so on, and a new file is started. Only the newest
+/// `keep` old files are kept around.
+pub struct FileLog {
+    path: PathBuf,
+    format: LogFormat,
+    max_size: Option<u64>,
+    keep: usize,
+    file: Mutex<OpenFile>,
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &Path) -> io::Result<OpenFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(OpenFile { file, size })
+}
+
+impl FileLog {
+    /// Start logging to `path`, adding to the end of it if it already exists. Files
+    /// aren't rotated until `rotate_at` is set.
+    pub fn open<P: AsRef<Path>>(path: P, format: LogFormat) -> io::Result<FileLog> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        Ok(FileLog {
+            path,
+            format,
+            max_size: None,
+            keep: 5,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Start a new file once the current one would grow past `bytes`.
+    pub fn rotate_at(mut self, bytes: u64) -> FileLog {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// How many rotated files to keep. Defaults to 5 and is always at least 1.
+    pub fn keep(mut self, files: usize) -> FileLog {
+        self.keep = files.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self, open: &mut OpenFile) -> io::Result<()> {
+        //The oldest one falls off of the end.
+        for n in (1..self.keep).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *open = open_append(&self.path)?;
+        Ok(())
+    }
+
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        //A panic can't happen while the file is being written so a poisoned lock is fine.
+        let mut open = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = line.len() as u64;
+        if let Some(max_size) = self.max_size {
+            if open.size > 0 && open.size + len > max_size {
+                self.rotate(&mut open)?;
+            }
+        }
+        //One write per line so lines from different workers never end up mixed together.
+        open.file.write_all(line.as_bytes())?;
+        open.size += len;
+        Ok(())
+    }
+}
+
+impl AccessLog for FileLog {
+    fn log(&self, entry: &LogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        if let Err(e) = self.write_line(&line) {
+            println!("Failed to write to the access log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    use test_util::TempDir;
+
+    fn entry() -> LogEntry {
+        LogEntry {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            peer: Some("127.0.0.1:5000".parse().unwrap()),
+            worker: Some(3),
+            method: Method::Get,
+            target: "/apache_pb.gif".to_string(),
+            version: Version::Http10,
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+            referer: Some("http://www.example.com/start.html".to_string()),
+            user_agent: Some("Mozilla/4.08 \"quoted\"".to_string()),
+        }
+    }
+
+    #[test]
+    fn common_and_combined_formats() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(LogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326"
+        );
+        assert_eq!(
+            entry.format(LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 \
+             \"http://www.example.com/start.html\" \"Mozilla/4.08 \\\"quoted\\\"\""
+        );
+
+        let empty = LogEntry { peer: None, bytes: 0, referer: None, ..entry };
+        assert!(empty.format(LogFormat::Combined).starts_with("- - - ["));
+        assert!(empty.format(LogFormat::Combined).contains("\" 200 - \"-\" \"Mozilla"));
+    }
+
+    #[test]
+    fn json_format() {
+        let entry = LogEntry { referer: None, ..entry() };
+        assert_eq!(
+            entry.format(LogFormat::Json),
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"peer\":\"127.0.0.1:5000\",\"worker\":3,\
+             \"method\":\"GET\",\"target\":\"/apache_pb.gif\",\"version\":\"HTTP/1.0\",\
+             \"status\":200,\"bytes\":2326,\"duration_ms\":1.500,\"referer\":null,\
+             \"user_agent\":\"Mozilla/4.08 \\\"quoted\\\"\"}"
+        );
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = TempDir::new("log");
+        let path = dir.join("access.log");
+        let line_len = entry().format(LogFormat::Common).len() as u64 + 1;
+        //Room for two lines per file, and only two old files are kept.
+        let log = FileLog::open(&path, LogFormat::Common).unwrap().rotate_at(line_len * 2).keep(2);
+        for _ in 0..7 {
+            log.log(&entry());
+        }
+
+        let lines = |p: &Path| fs::read_to_string(p).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&log.rotated(1)), 2);
+        assert_eq!(lines(&log.rotated(2)), 2);
+        assert!(!log.rotated(3).exists());
+    }
+}
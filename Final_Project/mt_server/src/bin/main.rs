@@ -1,98 +1,163 @@
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
-
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
 use std::thread;
 use std::time::Duration;
 
 extern crate mt_server;
-use mt_server::ThreadPool;
+use mt_server::config::{self, Config, ConfigError, RateConfig};
+use mt_server::{Cgi, Chain, Compression, FileLog, Handler, IoMode, Priority, Proxy, RateLimit, Request, RequestId, Response, Router, SecurityHeaders, Server, StaticFiles, Timeout, TlsConfig};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-    //We're now going to introduce a thread pool to handle the request so that we don't run out of the
-    //number of threads. An async method with a set thread pool would probably still be the best method for this.
-    //We still need to create a method for this though.
-    let pool = ThreadPool::new(4);
+    //Settings come from mt_server.toml (or the file given with --config) and the command line.
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            print!("{}", config::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("mt_server: {}", e);
+            process::exit(2);
+        }
+    };
+
+    let files = match StaticFiles::new(&config.root) {
+        Ok(files) => files,
+        Err(e) => fail(format!("Can't serve files from {}: {}", config.root.display(), e)),
+    };
 
-    //In practice we wouldn't have this shut down after 2 requests but this shows how it can shut down gracefully.
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
-        //Previous example didn't really read any request from the server.
-        // handle_connection(stream);
-        //Here we're going to explore having the code create a new thread for every
-        //connection. However, it should be noted that the problems with this route are that
-        //it has the potential to spawn an infinite number of threads which might crash the system.
-        //thread::spawn just creates a new thread and runs the code in the closure. 
-        // thread::spawn(|| {
-        //     handle_connection(stream);
-        // });
-        //We replace the thread::spawn with the pool.execute which works similar to the thread spawn method.
-        //We need to create this method first.
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    //The server keeps accepting connections until it gets Ctrl-C (or SIGTERM), then lets the
+    //requests it's in the middle of finish before the pool is dropped.
+    //Every response gets a request id and the usual security headers.
+    //A client going over its rate limit gets a 429 before any of the work is done.
+    let mut app = Chain::new(routes(files, &config))
+        .with(RequestId::new())
+        .with(SecurityHeaders::new());
+    if let Some(rate) = config.limits.rate {
+        app = app.with(RateLimit::new(rate, config.limits.burst));
+    }
+    let app = app.with(Compression::new());
+
+    let mut server = match Server::bind(&config.listen[..], app) {
+        Ok(server) => server
+            .workers(config.workers)
+            .connection_config(config.connection())
+            .grace_period(config.shutdown_timeout)
+            .io_mode(config.io_mode)
+            .handle_signals(true),
+        Err(e) => fail(format!("Can't listen on {}: {}", config.listen, e)),
+    };
+    if let Some(capacity) = config.queue_capacity {
+        server = server.queue_capacity(capacity);
+    }
+    if let Some(limit) = config.limits.connections_per_ip {
+        server = server.max_connections_per_ip(limit);
+    }
+    if let Some(ref path) = config.metrics_path {
+        server = server.metrics_path(path.clone());
+        //The metrics are what tells us the server is healthy, so they shouldn't wait behind a
+        //queue of /sleep requests. Only the event I/O mode reads a request before it's queued,
+        //so in the default blocking mode they still wait their turn. --help says so too.
+        if config.io_mode == IoMode::Event {
+            server = server.priority(path.clone(), Priority::High);
+        }
+    }
+    if let Some(ref path) = config.log.path {
+        let log = match FileLog::open(path, config.log.format) {
+            Ok(log) => log.keep(config.log.keep),
+            Err(e) => fail(format!("Can't open {}: {}", path.display(), e)),
+        };
+        server = match config.log.rotate_at {
+            Some(bytes) => server.access_log(log.rotate_at(bytes)),
+            None => server.access_log(log),
+        };
+    }
+    if let (Some(ref cert), Some(ref key)) = (&config.https.cert, &config.https.key) {
+        let https = TlsConfig::from_pem_files(cert, key).and_then(|tls| server.https(&config.https.listen[..], tls));
+        server = match https {
+            Ok(server) => server,
+            Err(e) => fail(format!("Can't serve HTTPS on {}: {}", config.https.listen, e)),
+        };
     }
 
+    //run fails if the pool's threads can't be started as well as on socket errors.
+    match server.run() {
+        Ok(true) => {}
+        Ok(false) => println!("Some requests didn't finish in time."),
+        Err(e) => fail(format!("Server error: {}", e)),
+    }
     println!("Shutting Down.");
 }
 
-//We've made the stream mutable in this example but it wasn't mutable above...
-//It apparently needs to be mutable because of the fact that state changing can be going on.
-fn handle_connection(mut stream: TcpStream) {
-    //This declares the buffer on the stack as being 512 bytes
-    //If arbitary buffer size was needed this would become more complex.
-    let mut buffer = [0; 512];
-    //This reads the bytes from TcpStream and put them into the buffer
-    stream.read(&mut buffer).unwrap();
-    // //The String::from_utf8_lossy takes in a &[u8] and returns a String. The lossy part
-    // //makes it return a  � anytime invalid characters are seen.
-    // println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
 
-    //We are now checking to see if the URI returned is /
+//Pages are registered here instead of being an if/else chain inside of handle_connection.
+//Routes that have a limit of their own in the config get it here, and so do the routes that
+//are proxied to backends or answered by CGI programs.
+fn routes(files: StaticFiles, config: &Config) -> Router {
+    let (limits, proxies, programs) = (&config.limits.routes, &config.proxies, &config.cgi);
+    let root = files.root().to_path_buf();
+    let missing = root.clone();
+    let files = files.index("hello.html").not_found(move |_: Request| page(404, &missing, "404.html"));
 
-    let get = b"GET / HTTP/1.1\r\n";
+    let mut router = Router::new();
     //Here we're going to simulate what a slow request to a single threaded server is like
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    //We've reduced the amount of repeated code by using a let if-else statement
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
+    //It's the one page that can take a while, so it's the one that gets the request timeout.
+    //Proxies and CGI programs have timeouts of their own.
+    let sleep = limited("/sleep", limits, move |_: Request| {
         //Here we're forcing our thread to sleep for 5 seconds.
         thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } 
-    else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
-
-    let mut file = File::open(filename).unwrap();
-    let mut contents = String::new();
-
-    file.read_to_string(&mut contents).unwrap();
-
-    let response = format!("{}{}", status_line, contents);
+        page(200, &root, "hello.html")
+    });
+    router.get("/sleep", sleep.with(Timeout::new(config.request_timeout)));
+    //Everything else comes out of the document root.
+    router.get("/*path", limited("/*path", limits, files));
+    for (pattern, config) in proxies {
+        let mut proxy = Proxy::new(config.upstreams[0].clone())
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.timeout);
+        for upstream in &config.upstreams[1..] {
+            proxy = proxy.upstream(upstream.clone());
+        }
+        router.any(pattern, limited(pattern, limits, proxy));
+    }
+    for (pattern, config) in programs {
+        let cgi = Cgi::new(&config.program).args(config.args.clone()).timeout(config.timeout);
+        router.any(pattern, limited(pattern, limits, cgi));
+    }
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    let known = |route: &String| {
+        route == "/sleep" || route == "/*path" || proxies.contains_key(route) || programs.contains_key(route)
+    };
+    for route in limits.keys().filter(|r| !known(r)) {
+        println!("There's a limit for {} but no such route.", route);
+    }
+    router
 }
 
-//The browser is taking a request of this form
-// Method Request-URI HTTP-Version CRLF
-// headers CRLF
-// message-body
-//The Method says whether is getting or posting something
-//Request-URI is pretty similar to URL
-//HTTP-Version is exactly what it sounds like
-//CRLF is either a line feed or a carriage return
-//The rest is the request data.
-
-//Responses take the following format:
-// HTTP-Version Status-Code Reason-Phrase CRLF
-// headers CRLF
-// message-body
+fn limited<H: Handler>(pattern: &str, limits: &BTreeMap<String, RateConfig>, handler: H) -> Chain {
+    let chain = Chain::new(handler);
+    match limits.get(pattern) {
+        Some(limit) => chain.with(RateLimit::new(limit.rate, limit.burst)),
+        None => chain,
+    }
+}
 
-//So we have the HTTP-Version used in the response, a numeric status code summarizing the results,
-//and a reason phrase that provides a text description of the status code.
\ No newline at end of file
+fn page(status: u16, root: &Path, filename: &str) -> Response {
+    let path = root.join(filename);
+    match fs::read(&path) {
+        Ok(contents) => Response::new(status)
+            .with_header("Content-Type", mt_server::mime::from_path(&path))
+            .with_body(contents),
+        Err(e) => {
+            println!("Failed to read {}: {}", path.display(), e);
+            Response::new(500).with_body("Internal Server Error")
+        }
+    }
+}
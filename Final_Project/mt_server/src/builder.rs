@@ -0,0 +1,211 @@
+//ThreadPool::new only takes a size and panics when something is wrong with it, and every
+//new option meant yet another with_* constructor. The builder collects all of the options
+//and build() reports problems as a PoolCreationError instead of panicking. That includes
+//the OS refusing to start a thread, which thread::spawn would have panicked on.
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use queue::{QueuePolicy, Scheduler};
+use ThreadPool;
+
+/// Why a `ThreadPool` couldn't be created or resized.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The pool was asked to have no workers at all.
+    ZeroSize,
+    /// The minimum number of workers is larger than the maximum.
+    MinAboveMax { min: usize, max: usize },
+    /// The queue was given room for zero jobs.
+    ZeroCapacity,
+    /// The OS wouldn't start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolCreationError::ZeroSize => f.write_str("a thread pool needs at least one worker"),
+            PoolCreationError::MinAboveMax { min, max } => {
+                write!(f, "the minimum of {} workers is above the maximum of {}", min, max)
+            }
+            PoolCreationError::ZeroCapacity => f.write_str("the job queue needs room for at least one job"),
+            PoolCreationError::Spawn(ref e) => write!(f, "couldn't start a worker thread: {}", e),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PoolCreationError::Spawn(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+//The server reports everything as an io::Error, so a failed spawn keeps its original error.
+impl From<PoolCreationError> for io::Error {
+    fn from(e: PoolCreationError) -> io::Error {
+        match e {
+            PoolCreationError::Spawn(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidInput, e),
+        }
+    }
+}
+
+/// Sets up a `ThreadPool`. Get one from `ThreadPool::builder()`.
+///
+/// By default the pool has one worker per CPU, an unlimited queue shared by every
+/// worker, a starvation limit of one second, and threads named `worker-0`, `worker-1`
+/// and so on.
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+    pub(crate) size: usize,
+    pub(crate) max_size: Option<usize>,
+    pub(crate) keep_alive: Option<Duration>,
+    pub(crate) capacity: Option<usize>,
+    pub(crate) policy: QueuePolicy,
+    pub(crate) scheduler: Scheduler,
+    pub(crate) starvation_limit: Duration,
+    pub(crate) name_prefix: String,
+    pub(crate) stack_size: Option<usize>,
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size: thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            max_size: None,
+            keep_alive: None,
+            capacity: None,
+            policy: QueuePolicy::Block,
+            scheduler: Scheduler::Shared,
+            starvation_limit: Duration::from_secs(1),
+            name_prefix: "worker-".to_string(),
+            stack_size: None,
+        }
+    }
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// The number of workers the pool starts with and never goes below.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.size = size;
+        self
+    }
+
+    /// Let the pool add workers, up to `max_size`, whenever jobs are queued faster than
+    /// the workers it has can take them. Defaults to `size`, which keeps the pool fixed.
+    pub fn max_size(mut self, max_size: usize) -> ThreadPoolBuilder {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// How long a worker above `size` waits for a job before it retires. Without a
+    /// keep-alive the extra workers stay until the pool is dropped or resized.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Limit how many jobs can wait for a worker. Unlimited by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// What `execute` does once the queue is full. `QueuePolicy::Block` by default.
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> ThreadPoolBuilder {
+        self.policy = policy;
+        self
+    }
+
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ThreadPoolBuilder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// How long a job may wait before it's taken ahead of more urgent jobs. Keeps a busy pool
+    /// from putting off `Priority::Low` and `Priority::Normal` jobs forever.
+    pub fn starvation_limit(mut self, limit: Duration) -> ThreadPoolBuilder {
+        self.starvation_limit = limit;
+        self
+    }
+
+    /// Worker threads are named this followed by their id, which shows up in panic
+    /// messages and debuggers.
+    pub fn name_prefix<S: Into<String>>(mut self, prefix: S) -> ThreadPoolBuilder {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    /// The stack size of every worker thread in bytes. Uses the standard library's
+    /// default when it isn't set.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    pub(crate) fn bounds(&self) -> Result<(usize, usize), PoolCreationError> {
+        let max = self.max_size.unwrap_or(self.size);
+        check_bounds(self.size, max)?;
+        Ok((self.size, max))
+    }
+
+    /// Start the workers.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+        ThreadPool::create(self)
+    }
+}
+
+pub(crate) fn check_bounds(min: usize, max: usize) -> Result<(), PoolCreationError> {
+    if max == 0 {
+        Err(PoolCreationError::ZeroSize)
+    } else if min > max {
+        Err(PoolCreationError::MinAboveMax { min, max })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_sizes_are_errors() {
+        assert!(matches!(ThreadPool::builder().size(0).build(), Err(PoolCreationError::ZeroSize)));
+        assert!(matches!(
+            ThreadPool::builder().size(3).max_size(2).build(),
+            Err(PoolCreationError::MinAboveMax { min: 3, max: 2 })
+        ));
+        assert!(matches!(ThreadPool::builder().queue_capacity(0).build(), Err(PoolCreationError::ZeroCapacity)));
+        //No minimum is fine as long as the pool can grow.
+        assert!(ThreadPool::builder().size(0).max_size(1).build().is_ok());
+    }
+
+    #[test]
+    fn workers_are_named() {
+        let pool = ThreadPool::builder().size(1).name_prefix("test-pool-").stack_size(256 * 1024).build().unwrap();
+        let name = pool.spawn(|| thread::current().name().map(String::from)).unwrap().join().unwrap();
+        assert_eq!(name.as_ref().map(|s| &s[..]), Some("test-pool-0"));
+    }
+
+    #[test]
+    fn spawn_failures_keep_the_os_error() {
+        let e: io::Error = PoolCreationError::Spawn(io::Error::from_raw_os_error(11)).into();
+        assert_eq!(e.raw_os_error(), Some(11));
+        let e: io::Error = PoolCreationError::ZeroSize.into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,371 @@
+//A Cgi handler runs a program for every request the way CGI/1.1 (RFC 3875) describes, so a
+//script can make pages without the server being recompiled. The request is described to it
+//in environment variables (REQUEST_METHOD, QUERY_STRING, HTTP_* for the headers and so on)
+//and the body comes in on stdin. The program writes a few headers, a blank line and then
+//the body to stdout. A Status header sets the status, a Location without one makes it a
+//302, and the rest of the headers go to the client as they are.
+//
+//The whole output is read before anything is sent, so a program that takes longer than the
+//timeout can be killed and answered with a 504 instead. It's started in a process group of
+//its own and the whole group is killed, which takes care of anything a shell script started
+//too. Output that isn't valid CGI gets a 502, like a broken upstream does for the Proxy.
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use request::Request;
+use response::{Response, Status};
+use router::Handler;
+
+/// A handler that answers requests with the output of a CGI program.
+///
+/// If the route has a `*path_info` wildcard, what it matched is passed as `PATH_INFO`
+/// and the part of the path before it as `SCRIPT_NAME`.
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    program: PathBuf,
+    args: Vec<String>,
+    dir: Option<PathBuf>,
+    timeout: Duration,
+    max_output: usize,
+}
+
+impl Cgi {
+    pub fn new<P: Into<PathBuf>>(program: P) -> Cgi {
+        Cgi {
+            program: program.into(),
+            args: Vec::new(),
+            dir: None,
+            timeout: Duration::from_secs(30),
+            max_output: 16 * 1024 * 1024,
+        }
+    }
+
+    /// Arguments to run the program with, like the script for an interpreter.
+    pub fn args<I, S>(mut self, args: I) -> Cgi
+        where
+            I: IntoIterator<Item = S>,
+            S: Into<String>
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The directory the program runs in. The server's own by default.
+    pub fn current_dir<P: Into<PathBuf>>(mut self, dir: P) -> Cgi {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// How long the program gets before it's killed. 30 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The most output the program may write, headers included. More than that is a 502.
+    /// 16 MiB by default.
+    pub fn max_output(mut self, bytes: usize) -> Cgi {
+        self.max_output = bytes;
+        self
+    }
+
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    fn spawn(&self, request: &Request) -> io::Result<Child> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env_clear()
+            .envs(environment(request))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        //Programs are looked for in PATH, and an interpreter on a #! line needs it too.
+        if let Some(path) = env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        if let Some(ref dir) = self.dir {
+            command.current_dir(dir);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        command.spawn()
+    }
+
+    //Runs the program and collects its output, or says why there isn't any.
+    fn run(&self, request: &Request) -> Result<Vec<u8>, Status> {
+        let mut child = self.spawn(request).map_err(|e| {
+            println!("Can't run {}: {}", self.program.display(), e);
+            Status::InternalServerError
+        })?;
+        let deadline = Instant::now() + self.timeout;
+
+        //Feeding stdin and draining stdout and stderr all happen at once, otherwise a program
+        //that writes before it's done reading could get stuck on a full pipe, and so could we.
+        let mut stdin = child.stdin.take();
+        let body = request.body.clone();
+        thread::spawn(move || {
+            //A program that doesn't read its stdin closes the pipe on us, which is fine.
+            let _ = stdin.as_mut().map(|stdin| stdin.write_all(&body));
+        });
+        let program = self.program.display().to_string();
+        if let Some(stderr) = child.stderr.take() {
+            thread::spawn(move || {
+                for line in io::BufReader::new(stderr).lines().map_while(Result::ok) {
+                    println!("{}: {}", program, line);
+                }
+            });
+        }
+        let (sender, output) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            let limit = self.max_output as u64 + 1;
+            thread::spawn(move || {
+                let mut out = Vec::new();
+                let read = stdout.take(limit).read_to_end(&mut out).map(|_| out);
+                let _ = sender.send(read);
+            });
+        }
+
+        let left = deadline.saturating_duration_since(Instant::now());
+        let out = match output.recv_timeout(left) {
+            Ok(Ok(out)) if out.len() <= self.max_output => out,
+            Ok(Ok(_)) => {
+                println!("{} wrote more than {} bytes", self.program.display(), self.max_output);
+                kill(&mut child);
+                return Err(Status::BadGateway);
+            }
+            Ok(Err(e)) => {
+                println!("Can't read the output of {}: {}", self.program.display(), e);
+                kill(&mut child);
+                return Err(Status::BadGateway);
+            }
+            Err(_) => {
+                println!("{} took longer than {:?}", self.program.display(), self.timeout);
+                kill(&mut child);
+                return Err(Status::GatewayTimeout);
+            }
+        };
+
+        //Closing stdout usually means it's done, but it doesn't have to be.
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        println!("{} exited with {}", self.program.display(), status);
+                    }
+                    return Ok(out);
+                }
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                Ok(None) => {
+                    kill(&mut child);
+                    return Ok(out);
+                }
+                Err(e) => {
+                    println!("Can't wait for {}: {}", self.program.display(), e);
+                    return Ok(out);
+                }
+            }
+        }
+    }
+}
+
+impl Handler for Cgi {
+    fn handle(&self, request: Request) -> Response {
+        let parsed = self.run(&request).and_then(|out| {
+            parse_output(out).map_err(|problem| {
+                println!("Bad output from {}: {}", self.program.display(), problem);
+                Status::BadGateway
+            })
+        });
+        parsed.unwrap_or_else(|status| Response::from_status(status).with_body(status.reason()))
+    }
+}
+
+//Kills the program along with anything it started, and reaps it.
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        //The program leads its own process group, whose id is its pid.
+        let _ = unsafe { ::libc::kill(-(child.id() as ::libc::pid_t), ::libc::SIGKILL) };
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+//The CGI meta-variables for `request`.
+fn environment(request: &Request) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    let mut set = |name: &str, value: String| {
+        vars.insert(name.to_string(), value);
+    };
+    set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    set("SERVER_SOFTWARE", format!("mt_server/{}", env!("CARGO_PKG_VERSION")));
+    set("SERVER_PROTOCOL", request.version.to_string());
+    set("REQUEST_METHOD", request.method.to_string());
+    set("REQUEST_URI", request.target.clone());
+    set("QUERY_STRING", request.query().unwrap_or("").to_string());
+
+    let path = request.path();
+    match request.param("path_info") {
+        //The wildcard is always the end of the path, so SCRIPT_NAME is what's in front of it.
+        Some(info) if !info.is_empty() => {
+            let info = format!("/{}", info.trim_start_matches('/'));
+            let script = path.strip_suffix(&info[1..]).unwrap_or(path).trim_end_matches('/');
+            set("SCRIPT_NAME", script.to_string());
+            set("PATH_INFO", info);
+        }
+        _ => set("SCRIPT_NAME", path.trim_end_matches('/').to_string()),
+    }
+
+    let host = request.headers.get("Host").unwrap_or("");
+    let (name, port) = match host.rfind(':') {
+        Some(colon) if !host.ends_with(']') => (&host[..colon], &host[colon + 1..]),
+        _ => (host, "80"),
+    };
+    set("SERVER_NAME", name.to_string());
+    set("SERVER_PORT", port.to_string());
+    if let Some(peer) = request.peer {
+        set("REMOTE_ADDR", peer.ip().to_string());
+        set("REMOTE_PORT", peer.port().to_string());
+    }
+
+    if !request.body.is_empty() || request.headers.contains("Content-Length") {
+        set("CONTENT_LENGTH", request.body.len().to_string());
+    }
+    for (name, value) in &request.headers {
+        let var = match &name.to_ascii_uppercase().replace('-', "_")[..] {
+            "CONTENT_TYPE" => "CONTENT_TYPE".to_string(),
+            //The body has been decoded already, so how it was sent doesn't matter anymore.
+            "CONTENT_LENGTH" | "TRANSFER_ENCODING" => continue,
+            //Credentials aren't passed on, and HTTP_PROXY would look like the program's own
+            //proxy setting to lots of HTTP libraries (httpoxy).
+            "AUTHORIZATION" | "PROXY_AUTHORIZATION" | "PROXY" => continue,
+            other => format!("HTTP_{}", other),
+        };
+        //Repeated headers are joined the way they'd be folded into one.
+        match vars.get_mut(&var) {
+            Some(existing) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => {
+                vars.insert(var, value.to_string());
+            }
+        }
+    }
+    vars
+}
+
+//Turns what the program wrote into a response.
+fn parse_output(out: Vec<u8>) -> Result<Response, &'static str> {
+    //The header lines may end with just a LF, lots of scripts do that.
+    let (head_len, body_start) = match (find(&out, b"\r\n\r\n"), find(&out, b"\n\n")) {
+        (Some(crlf), Some(lf)) if lf < crlf => (lf, lf + 2),
+        (Some(crlf), _) => (crlf, crlf + 4),
+        (None, Some(lf)) => (lf, lf + 2),
+        (None, None) => return Err("no blank line after the headers"),
+    };
+    let head = std::str::from_utf8(&out[..head_len]).map_err(|_| "headers aren't UTF-8")?;
+
+    let mut response = Response::ok();
+    let mut status = None;
+    for line in head.lines() {
+        let line = line.trim_end_matches('\r');
+        let colon = line.find(':').filter(|&i| i > 0).ok_or("a header line without a colon")?;
+        let (name, value) = (&line[..colon], line[colon + 1..].trim());
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value.split(' ').next().and_then(|code| code.parse::<u16>().ok());
+            status = Some(code.filter(|code| (200..600).contains(code)).ok_or("a bad Status header")?);
+        } else {
+            response.headers.append(name, value);
+        }
+    }
+    if response.headers.is_empty() && status.is_none() {
+        return Err("no headers");
+    }
+    response.status = match status {
+        Some(status) => status,
+        None if response.headers.contains("Location") => 302,
+        None => 200,
+    };
+    Ok(response.with_body(out[body_start..].to_vec()))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::Limits;
+    use router::Router;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn describes_the_request_in_the_environment() {
+        let raw = "POST /cgi/report/2024/q1?x=1&y=2 HTTP/1.1\r\nHost: example.com:8080\r\nContent-Type: text/plain\r\n\
+                   X-Thing: a\r\nX-Thing: b\r\nProxy: evil\r\nAuthorization: secret\r\nContent-Length: 3\r\n\r\nabc";
+        let mut request = Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0;
+        request.peer = Some("10.0.0.7:4321".parse().unwrap());
+        //The router fills in the params, so the environment is made where it would be.
+        let vars = Arc::new(Mutex::new(HashMap::new()));
+        let mut router = Router::new();
+        let seen = vars.clone();
+        router.any("/cgi/report/*path_info", move |request: Request| {
+            *seen.lock().unwrap() = environment(&request);
+            Response::ok()
+        });
+        router.handle(request);
+        let vars = vars.lock().unwrap();
+        let var = |name: &str| vars.get(name).map(String::as_str);
+
+        assert_eq!(var("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(var("SERVER_PROTOCOL"), Some("HTTP/1.1"));
+        assert_eq!(var("QUERY_STRING"), Some("x=1&y=2"));
+        assert_eq!(var("SCRIPT_NAME"), Some("/cgi/report"));
+        assert_eq!(var("PATH_INFO"), Some("/2024/q1"));
+        assert_eq!(var("SERVER_NAME"), Some("example.com"));
+        assert_eq!(var("SERVER_PORT"), Some("8080"));
+        assert_eq!(var("REMOTE_ADDR"), Some("10.0.0.7"));
+        assert_eq!(var("CONTENT_LENGTH"), Some("3"));
+        assert_eq!(var("CONTENT_TYPE"), Some("text/plain"));
+        assert_eq!(var("HTTP_X_THING"), Some("a, b"));
+        assert_eq!(var("HTTP_HOST"), Some("example.com:8080"));
+        assert_eq!(var("HTTP_PROXY"), None);
+        assert_eq!(var("HTTP_AUTHORIZATION"), None);
+        assert_eq!(var("HTTP_CONTENT_LENGTH"), None);
+    }
+
+    #[test]
+    fn parses_program_output() {
+        let response = parse_output(b"Content-Type: text/plain\nX-A: 1\n\nhello\n\nworld".to_vec()).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(response.body, b"hello\n\nworld");
+
+        let response = parse_output(b"Status: 404 Not Found\r\nContent-Type: text/html\r\n\r\ngone".to_vec()).unwrap();
+        assert_eq!(response.status, 404);
+        assert!(!response.headers.contains("Status"));
+
+        let response = parse_output(b"Location: /elsewhere\n\n".to_vec()).unwrap();
+        assert_eq!((response.status, response.headers.get("Location")), (302, Some("/elsewhere")));
+
+        assert!(parse_output(b"just some text".to_vec()).is_err());
+        assert!(parse_output(b"no colon here\n\nbody".to_vec()).is_err());
+        assert!(parse_output(b"Status: lots\n\n".to_vec()).is_err());
+        assert!(parse_output(b"\nbody".to_vec()).is_err());
+    }
+}
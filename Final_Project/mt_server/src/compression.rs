@@ -0,0 +1,311 @@
+//Text compresses really well and pages and JSON were going out as is. Compression is a
+//middleware that looks at the client's Accept-Encoding on the way in and, if the response
+//that comes back is worth it, compresses it with gzip or deflate on the way out. Types that
+//are compressed already (images, video, archives) aren't on the list so they're left alone,
+//and so are tiny bodies where the gzip header would eat up most of the savings.
+use std::io::prelude::*;
+use std::mem;
+
+use flate2;
+use flate2::read::{DeflateEncoder, GzEncoder};
+use flate2::write;
+
+use middleware::{Middleware, Next};
+use request::Request;
+use response::{Body, Response};
+
+/// The content codings `Compression` knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Pick the coding to use for a request's Accept-Encoding header, if any. Gzip wins
+/// when the client likes both the same.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut any = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        //A missing or broken q-value counts as 1, which is what the client most likely meant.
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        match &coding[..] {
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "deflate" => deflate = Some(q),
+            "*" => any = Some(q),
+            _ => {}
+        }
+    }
+    //Codings that aren't mentioned get the q-value of *, if there is one.
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Compresses responses for clients that accept gzip or deflate.
+///
+/// Only responses with one of the listed Content-Types and a body of at least the
+/// minimum size get compressed. Streamed bodies of unknown length always do, since
+/// they're usually big. Every response that could have been compressed gets
+/// `Vary: Accept-Encoding` so caches keep the versions apart.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+    types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        let types = [
+            "text/",
+            "application/json",
+            "application/javascript",
+            "application/xml",
+            "application/xhtml+xml",
+            "application/wasm",
+            "image/svg+xml",
+        ];
+        Compression {
+            min_size: 1024,
+            level: 6,
+            types: types.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression::default()
+    }
+
+    /// Bodies smaller than this are sent as they are. 1024 bytes by default.
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// The compression level from 0 (none) to 9 (smallest). 6 by default.
+    pub fn level(mut self, level: u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Also compress this Content-Type. A type ending in `/` matches everything under it.
+    pub fn compress_type<S: Into<String>>(mut self, content_type: S) -> Compression {
+        self.types.push(content_type.into());
+        self
+    }
+
+    fn compressible_type(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.types.iter().any(|t| {
+            if t.ends_with('/') {
+                mime.starts_with(&t[..])
+            } else {
+                mime == *t
+            }
+        })
+    }
+
+    //Whether a response is the kind we'd compress for a client that wants it.
+    fn eligible(&self, response: &Response) -> bool {
+        let ok_status = response.status >= 200 && response.status < 300
+            && response.status != 204 && response.status != 206;
+        ok_status
+            && !response.headers.contains("Content-Encoding")
+            && !response.headers.contains("Content-Range")
+            && !response.headers.has_token("Cache-Control", "no-transform")
+            && response.headers.get("Content-Type").is_some_and(|t| self.compressible_type(t))
+    }
+
+    fn compress(&self, body: Body, encoding: Encoding) -> Body {
+        let level = flate2::Compression::new(self.level);
+        match body {
+            //Bodies that are in memory are compressed right away so they keep their Content-Length.
+            Body::Bytes(bytes) => {
+                let compressed = match encoding {
+                    Encoding::Gzip => {
+                        let mut encoder = write::GzEncoder::new(Vec::new(), level);
+                        encoder.write_all(&bytes).and_then(|_| encoder.finish())
+                    }
+                    Encoding::Deflate => {
+                        let mut encoder = write::DeflateEncoder::new(Vec::new(), level);
+                        encoder.write_all(&bytes).and_then(|_| encoder.finish())
+                    }
+                };
+                //Writing to a Vec can't fail.
+                Body::Bytes(compressed.unwrap_or_default())
+            }
+            //Readers are compressed as they're sent, so the length isn't known anymore.
+            Body::Reader { reader, len } => {
+                let reader: Box<dyn Read + Send> = match len {
+                    Some(len) => Box::new(reader.take(len)),
+                    None => reader,
+                };
+                let reader: Box<dyn Read + Send> = match encoding {
+                    Encoding::Gzip => Box::new(GzEncoder::new(reader, level)),
+                    Encoding::Deflate => Box::new(DeflateEncoder::new(reader, level)),
+                };
+                Body::Reader { reader, len: None }
+            }
+        }
+    }
+}
+
+impl Middleware for Compression {
+    fn call(&self, request: Request, next: Next) -> Response {
+        let encoding = request.headers.get("Accept-Encoding").and_then(negotiate);
+        let mut response = next.run(request);
+        if !self.eligible(&response) {
+            return response;
+        }
+        if !response.headers.has_token("Vary", "Accept-Encoding") {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        if response.body.len().is_some_and(|len| len < self.min_size) {
+            return response;
+        }
+
+        let body = mem::take(&mut response.body);
+        response.body = self.compress(body, encoding);
+        response.headers.insert("Content-Encoding", encoding.as_str());
+        //The compressed bytes aren't the same representation anymore, so a strong ETag
+        //would be lying. A weak one still works for If-None-Match.
+        let weakened = match response.headers.get("ETag") {
+            Some(etag) if !etag.starts_with("W/") => Some(format!("W/{}", etag)),
+            _ => None,
+        };
+        if let Some(etag) = weakened {
+            response.headers.insert("ETag", etag);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    use flate2::read::{DeflateDecoder, GzDecoder};
+
+    use middleware::Chain;
+    use request::Limits;
+    use router::Handler;
+
+    fn get(handler: &Chain, headers: &str) -> Response {
+        let raw = format!("GET / HTTP/1.1\r\nHost: test\r\n{}\r\n", headers);
+        handler.handle(Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0)
+    }
+
+    fn page() -> String {
+        "<p>Hello from Rust!</p>\n".repeat(100)
+    }
+
+    fn app(content_type: &'static str) -> Chain {
+        Chain::new(move |_: Request| {
+            Response::ok()
+                .with_header("Content-Type", content_type)
+                .with_header("ETag", "\"abc\"")
+                .with_body(page())
+        })
+            .with(Compression::new())
+    }
+
+    fn gunzip(body: Body) -> String {
+        let mut out = String::new();
+        GzDecoder::new(&body.into_bytes().unwrap()[..]).read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn negotiates_accept_encoding() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0.5, deflate;q=0.8"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.1, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br, identity"), None);
+    }
+
+    #[test]
+    fn compresses_text_with_gzip() {
+        let response = get(&app("text/html; charset=utf-8"), "Accept-Encoding: gzip, deflate\r\n");
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"abc\""));
+        assert!(response.body.len().unwrap() < page().len() as u64);
+        assert_eq!(gunzip(response.body), page());
+    }
+
+    #[test]
+    fn compresses_with_deflate() {
+        let response = get(&app("application/json"), "Accept-Encoding: deflate\r\n");
+        assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+        let mut out = String::new();
+        DeflateDecoder::new(&response.body.into_bytes().unwrap()[..]).read_to_string(&mut out).unwrap();
+        assert_eq!(out, page());
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        //The client doesn't want it, but caches still need to know it could have been different.
+        let response = get(&app("text/plain"), "");
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.into_bytes().unwrap(), page().into_bytes());
+
+        //Already compressed.
+        let response = get(&app("image/png"), "Accept-Encoding: gzip\r\n");
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert!(!response.headers.contains("Vary"));
+
+        //Too small.
+        let small = Chain::new(|_: Request| Response::ok().with_header("Content-Type", "text/plain").with_body("hi"))
+            .with(Compression::new());
+        let response = get(&small, "Accept-Encoding: gzip\r\n");
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(response.body, b"hi");
+    }
+
+    #[test]
+    fn streams_are_compressed_as_they_go() {
+        let app = Chain::new(|_: Request| {
+            Response::ok().with_header("Content-Type", "text/plain").with_reader(io::Cursor::new(page()), None)
+        })
+            .with(Compression::new());
+        let response = get(&app, "Accept-Encoding: gzip\r\n");
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.body.len(), None);
+        assert_eq!(gunzip(response.body), page());
+    }
+}
@@ -0,0 +1,840 @@
+//The address, the pool size and the file names used to be written into main.rs. A Config is
+//everything the binary can be told, built up in layers: the defaults, then whatever is in the
+//TOML file and then the command line flags, so a flag always wins over the file. Both the file
+//and the flags are read into the same Settings layer (every field optional) and merged into the
+//Config the same way. Nothing is judged while merging. Once all layers are in, the result is
+//checked and every problem is reported at once instead of one per run.
+//
+//A config file with every setting looks like this:
+//
+//    listen = "127.0.0.1:8080"
+//    workers = 4
+//    queue_capacity = 64        # 0 queues without a limit
+//    root = "public"
+//    metrics_path = "/metrics"  # "" turns the endpoint off, it's high priority with io_mode = "event"
+//    max_requests = 100         # per connection
+//    io_mode = "blocking"       # or "event" to keep idle connections off the workers and to
+//                               # read requests before they're queued, which priorities need
+//
+//    [timeouts]                 # in seconds
+//    idle = 5
+//    request = 30
+//    shutdown = 30
+//
+//    [https]                    # only served if cert and key are given
+//    listen = "127.0.0.1:8443"
+//    cert = "cert.pem"
+//    key = "key.pem"
+//
+//    [log]
+//    path = "access.log"        # "" turns the access log off
+//    format = "combined"        # common, combined or json
+//    rotate_at = 10485760       # bytes, 0 never rotates
+//    keep = 5
+//
+//    [limits]                   # per client IP
+//    connections_per_ip = 0     # 0 for no limit
+//    rate = 0                   # requests a second, 0 for no limit
+//    burst = 10                 # requests that may come at once
+//
+//    [limits.routes."/sleep"]   # a route's own limit on top of the one above
+//    rate = 0.2
+//    burst = 1                  # 1 if left out
+//
+//    [proxy."/api/*rest"]       # every method on the route goes to a backend
+//    upstreams = ["127.0.0.1:3000", "127.0.0.1:3001"]  # taking turns
+//    connect_timeout = 5        # in seconds
+//    timeout = 30
+//
+//    [cgi."/report/*path_info"] # every method on the route runs a program
+//    program = "scripts/report.sh"
+//    args = []
+//    timeout = 10               # in seconds, it's killed after that
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use toml;
+
+use access_log::LogFormat;
+use connection::ConnectionConfig;
+use server::IoMode;
+
+/// The config file the binary reads when `--config` isn't given. It doesn't have to exist.
+pub const DEFAULT_PATH: &str = "mt_server.toml";
+
+/// What `--help` prints.
+pub const USAGE: &str = "\
+Usage: mt_server [OPTIONS] [ROOT]
+
+Serves the files in ROOT (./public by default). Settings are read from mt_server.toml
+if it exists, and flags override the file.
+
+Options:
+  -c, --config FILE           read settings from FILE instead of mt_server.toml
+      --listen ADDR           address for plain HTTP [127.0.0.1:8080]
+      --https-listen ADDR     address for HTTPS [127.0.0.1:8443]
+      --cert FILE             PEM certificate chain, turns HTTPS on together with --key
+      --key FILE              PEM private key
+  -w, --workers N             threads in the pool [4]
+      --queue-capacity N      connections that may wait for a worker, 0 for no limit [64]
+      --root DIR              the document root, same as ROOT
+      --metrics-path PATH     where the pool's metrics are served, \"\" for nowhere [/metrics]
+                              (only with --io-mode event do they skip ahead of slow requests)
+      --max-requests N        requests served on one connection [100]
+      --io-mode MODE          blocking or event [blocking]; request priorities need event
+      --idle-timeout SECS     how long an idle connection is kept open [5]
+      --request-timeout SECS  how long a request may take [30]
+      --shutdown-timeout SECS how long requests get to finish on shutdown [30]
+      --access-log FILE       where requests are logged, \"\" for nowhere [access.log]
+      --log-format FORMAT     common, combined or json [combined]
+      --log-rotate-at BYTES   start a new log file at this size, 0 for never [10485760]
+      --log-keep N            rotated log files to keep [5]
+      --connections-per-ip N  connections one client may have open, 0 for no limit [0]
+      --rate-limit RATE       requests a second one client may send, 0 for no limit [0]
+      --rate-burst N          requests one client may send at once [10]
+  -h, --help                  print this and exit
+";
+
+/// Where and how requests are logged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    /// `None` turns the access log off.
+    pub path: Option<PathBuf>,
+    pub format: LogFormat,
+    /// `None` never rotates.
+    pub rotate_at: Option<u64>,
+    pub keep: usize,
+}
+
+/// A token bucket for `RateLimit`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateConfig {
+    /// Requests a second.
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// Limits on what a single client IP can do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitsConfig {
+    /// `None` doesn't limit connections.
+    pub connections_per_ip: Option<usize>,
+    /// Requests a second for every request. `None` doesn't limit requests.
+    pub rate: Option<f64>,
+    pub burst: u32,
+    /// Limits for single routes by their pattern, applied on top of `rate`.
+    pub routes: BTreeMap<String, RateConfig>,
+}
+
+/// A route that's forwarded to other servers by a `Proxy`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    /// `host:port`s that take turns.
+    pub upstreams: Vec<String>,
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+}
+
+/// A route that's answered by a program through `Cgi`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CgiConfig {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub timeout: Duration,
+}
+
+/// The HTTPS listener, which is only started when both a certificate and a key are given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpsConfig {
+    pub listen: String,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl HttpsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some() || self.key.is_some()
+    }
+}
+
+/// Everything the `mt_server` binary can be configured with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: String,
+    pub https: HttpsConfig,
+    pub workers: usize,
+    /// `None` queues without a limit.
+    pub queue_capacity: Option<usize>,
+    pub root: PathBuf,
+    /// `None` doesn't serve the metrics.
+    pub metrics_path: Option<String>,
+    pub max_requests: usize,
+    pub io_mode: IoMode,
+    pub idle_timeout: Duration,
+    pub request_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub log: LogConfig,
+    pub limits: LimitsConfig,
+    /// Proxied routes by their pattern.
+    pub proxies: BTreeMap<String, ProxyConfig>,
+    /// Routes answered by CGI programs by their pattern.
+    pub cgi: BTreeMap<String, CgiConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "127.0.0.1:8080".to_string(),
+            https: HttpsConfig {
+                listen: "127.0.0.1:8443".to_string(),
+                cert: None,
+                key: None,
+            },
+            workers: 4,
+            queue_capacity: Some(64),
+            root: PathBuf::from("public"),
+            metrics_path: Some("/metrics".to_string()),
+            max_requests: 100,
+            io_mode: IoMode::Blocking,
+            idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            shutdown_timeout: Duration::from_secs(30),
+            log: LogConfig {
+                path: Some(PathBuf::from("access.log")),
+                format: LogFormat::Combined,
+                rotate_at: Some(10 * 1024 * 1024),
+                keep: 5,
+            },
+            limits: LimitsConfig {
+                connections_per_ip: None,
+                rate: None,
+                burst: 10,
+                routes: BTreeMap::new(),
+            },
+            proxies: BTreeMap::new(),
+            cgi: BTreeMap::new(),
+        }
+    }
+}
+
+/// Why there's no `Config` to run with.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Read { path: PathBuf, error: io::Error },
+    /// The config file isn't TOML, or has settings we don't know or of the wrong type.
+    Parse { path: PathBuf, message: String },
+    /// A flag we don't know, one that's missing its value or whose value doesn't parse.
+    Usage(String),
+    /// Settings that were read fine but can't be used, all of them.
+    Invalid(Vec<String>),
+    /// `--help` was given, so the caller should print `USAGE` rather than run.
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Read { ref path, ref error } => write!(f, "can't read {}: {}", path.display(), error),
+            ConfigError::Parse { ref path, ref message } => write!(f, "{} is not a valid config file: {}", path.display(), message.trim_end()),
+            ConfigError::Usage(ref message) => write!(f, "{} (see --help)", message),
+            ConfigError::Invalid(ref problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            ConfigError::Help => f.write_str(USAGE),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConfigError::Read { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+//One layer of settings, from the file or from the flags. Anything left out keeps the value
+//from the layer below.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    listen: Option<String>,
+    workers: Option<usize>,
+    queue_capacity: Option<usize>,
+    root: Option<PathBuf>,
+    metrics_path: Option<String>,
+    max_requests: Option<usize>,
+    io_mode: Option<String>,
+    #[serde(default)]
+    timeouts: TimeoutSettings,
+    #[serde(default)]
+    https: HttpsSettings,
+    #[serde(default)]
+    log: LogSettings,
+    #[serde(default)]
+    limits: LimitSettings,
+    #[serde(default)]
+    proxy: BTreeMap<String, ProxySettings>,
+    #[serde(default)]
+    cgi: BTreeMap<String, CgiSettings>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutSettings {
+    idle: Option<f64>,
+    request: Option<f64>,
+    shutdown: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HttpsSettings {
+    listen: Option<String>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitSettings {
+    connections_per_ip: Option<usize>,
+    rate: Option<f64>,
+    burst: Option<u32>,
+    #[serde(default)]
+    routes: BTreeMap<String, RouteLimitSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteLimitSettings {
+    rate: f64,
+    burst: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxySettings {
+    upstreams: Vec<String>,
+    connect_timeout: Option<f64>,
+    timeout: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CgiSettings {
+    program: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    timeout: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogSettings {
+    path: Option<PathBuf>,
+    format: Option<String>,
+    rotate_at: Option<u64>,
+    keep: Option<usize>,
+}
+
+impl Config {
+    /// Build the configuration from the command line arguments, without the program name.
+    ///
+    /// The file named by `--config` has to exist, while `mt_server.toml` is only read if it's there.
+    pub fn from_args<I, S>(args: I) -> Result<Config, ConfigError>
+        where
+            I: IntoIterator<Item = S>,
+            S: Into<String>,
+    {
+        let (file, flags) = parse_flags(args)?;
+        let mut config = Config::default();
+        let mut problems = Vec::new();
+        match file {
+            Some(path) => config.merge(read_file(&path)?, &mut problems),
+            None if Path::new(DEFAULT_PATH).exists() => config.merge(read_file(Path::new(DEFAULT_PATH))?, &mut problems),
+            None => {}
+        }
+        config.merge(flags, &mut problems);
+        config.check(&mut problems);
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Read just a config file on top of the defaults, without any flags.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut problems = Vec::new();
+        config.merge(read_file(path.as_ref())?, &mut problems);
+        config.check(&mut problems);
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// The settings for each connection.
+    pub fn connection(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Some(self.idle_timeout),
+            max_requests: self.max_requests,
+            ..ConnectionConfig::default()
+        }
+    }
+
+    fn merge(&mut self, layer: Settings, problems: &mut Vec<String>) {
+        if let Some(listen) = layer.listen {
+            self.listen = listen;
+        }
+        if let Some(workers) = layer.workers {
+            self.workers = workers;
+        }
+        if let Some(capacity) = layer.queue_capacity {
+            self.queue_capacity = if capacity == 0 { None } else { Some(capacity) };
+        }
+        if let Some(root) = layer.root {
+            self.root = root;
+        }
+        if let Some(path) = layer.metrics_path {
+            self.metrics_path = if path.is_empty() { None } else { Some(path) };
+        }
+        if let Some(max) = layer.max_requests {
+            self.max_requests = max;
+        }
+        if let Some(mode) = layer.io_mode {
+            match mode.parse() {
+                Ok(mode) => self.io_mode = mode,
+                Err(e) => problems.push(e),
+            }
+        }
+
+        let timeouts = [
+            ("idle timeout", layer.timeouts.idle, &mut self.idle_timeout),
+            ("request timeout", layer.timeouts.request, &mut self.request_timeout),
+            ("shutdown timeout", layer.timeouts.shutdown, &mut self.shutdown_timeout),
+        ];
+        for (name, secs, timeout) in timeouts {
+            match secs.map(seconds) {
+                Some(Some(secs)) => *timeout = secs,
+                Some(None) => problems.push(format!("the {} must be a positive number of seconds", name)),
+                None => {}
+            }
+        }
+
+        if let Some(listen) = layer.https.listen {
+            self.https.listen = listen;
+        }
+        if let Some(cert) = layer.https.cert {
+            self.https.cert = Some(cert);
+        }
+        if let Some(key) = layer.https.key {
+            self.https.key = Some(key);
+        }
+
+        if let Some(path) = layer.log.path {
+            self.log.path = if path.as_os_str().is_empty() { None } else { Some(path) };
+        }
+        if let Some(format) = layer.log.format {
+            match format.parse() {
+                Ok(format) => self.log.format = format,
+                Err(e) => problems.push(e),
+            }
+        }
+        if let Some(bytes) = layer.log.rotate_at {
+            self.log.rotate_at = if bytes == 0 { None } else { Some(bytes) };
+        }
+        if let Some(keep) = layer.log.keep {
+            self.log.keep = keep;
+        }
+
+        if let Some(limit) = layer.limits.connections_per_ip {
+            self.limits.connections_per_ip = if limit == 0 { None } else { Some(limit) };
+        }
+        if let Some(rate) = layer.limits.rate {
+            self.limits.rate = if rate == 0.0 { None } else { Some(rate) };
+        }
+        if let Some(burst) = layer.limits.burst {
+            self.limits.burst = burst;
+        }
+        for (route, limit) in layer.limits.routes {
+            let burst = limit.burst.unwrap_or(1);
+            self.limits.routes.insert(route, RateConfig { rate: limit.rate, burst });
+        }
+
+        for (route, proxy) in layer.proxy {
+            let mut timeout = |name: &str, secs: Option<f64>, default: u64| match secs.map(seconds) {
+                Some(Some(secs)) => secs,
+                Some(None) => {
+                    problems.push(format!("proxy.{:?}.{} must be a positive number of seconds", route, name));
+                    Duration::from_secs(default)
+                }
+                None => Duration::from_secs(default),
+            };
+            let proxy = ProxyConfig {
+                connect_timeout: timeout("connect_timeout", proxy.connect_timeout, 5),
+                timeout: timeout("timeout", proxy.timeout, 30),
+                upstreams: proxy.upstreams,
+            };
+            self.proxies.insert(route, proxy);
+        }
+
+        for (route, cgi) in layer.cgi {
+            let timeout = match cgi.timeout.map(seconds) {
+                Some(Some(secs)) => secs,
+                Some(None) => {
+                    problems.push(format!("cgi.{:?}.timeout must be a positive number of seconds", route));
+                    Duration::from_secs(30)
+                }
+                None => Duration::from_secs(30),
+            };
+            self.cgi.insert(route, CgiConfig { program: cgi.program, args: cgi.args, timeout });
+        }
+    }
+
+    //The things we can tell are wrong before trying to start.
+    fn check(&self, problems: &mut Vec<String>) {
+        check_address("listen", &self.listen, problems);
+        if self.workers == 0 {
+            problems.push("workers must be at least 1".to_string());
+        }
+        if self.max_requests == 0 {
+            problems.push("max_requests must be at least 1".to_string());
+        }
+        if !self.root.is_dir() {
+            problems.push(format!("the document root {} is not a directory", self.root.display()));
+        }
+        if let Some(ref path) = self.metrics_path {
+            if !path.starts_with('/') {
+                problems.push(format!("metrics_path must start with a /, got {:?}", path));
+            }
+        }
+        if self.https.enabled() {
+            check_address("https.listen", &self.https.listen, problems);
+            for (name, file) in [("https.cert", &self.https.cert), ("https.key", &self.https.key)] {
+                match *file {
+                    Some(ref file) if !file.is_file() => problems.push(format!("{} {} doesn't exist", name, file.display())),
+                    Some(_) => {}
+                    None => problems.push(format!("{} is needed for HTTPS", name)),
+                }
+            }
+        }
+        if self.log.keep == 0 {
+            problems.push("log.keep must be at least 1".to_string());
+        }
+        if let Some(rate) = self.limits.rate {
+            check_rate("limits", RateConfig { rate, burst: self.limits.burst }, problems);
+        }
+        for (route, &limit) in &self.limits.routes {
+            if !route.starts_with('/') {
+                problems.push(format!("limits.routes {:?} is not a route, those start with a /", route));
+            }
+            check_rate(&format!("limits.routes.{:?}", route), limit, problems);
+        }
+        for (route, proxy) in &self.proxies {
+            if !route.starts_with('/') {
+                problems.push(format!("proxy {:?} is not a route, those start with a /", route));
+            }
+            if proxy.upstreams.is_empty() {
+                problems.push(format!("proxy.{:?} needs at least one upstream", route));
+            }
+            for upstream in &proxy.upstreams {
+                check_address(&format!("proxy.{:?} upstream", route), upstream, problems);
+            }
+        }
+        for (route, cgi) in &self.cgi {
+            if !route.starts_with('/') {
+                problems.push(format!("cgi {:?} is not a route, those start with a /", route));
+            }
+            //A bare name like python3 is looked for in PATH when it's run.
+            if cgi.program.components().count() > 1 && !cgi.program.is_file() {
+                problems.push(format!("cgi.{:?} program {} doesn't exist", route, cgi.program.display()));
+            }
+        }
+    }
+}
+
+fn check_address(name: &str, addr: &str, problems: &mut Vec<String>) {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => {
+            if addrs.next().is_none() {
+                problems.push(format!("{} {:?} doesn't resolve to any address", name, addr));
+            }
+        }
+        Err(e) => problems.push(format!("{} {:?} is not a usable address: {}", name, addr, e)),
+    }
+}
+
+fn check_rate(name: &str, limit: RateConfig, problems: &mut Vec<String>) {
+    if !(limit.rate > 0.0 && limit.rate.is_finite()) {
+        problems.push(format!("{}.rate must be a positive number of requests a second, got {}", name, limit.rate));
+    }
+    if limit.burst == 0 {
+        problems.push(format!("{}.burst must be at least 1", name));
+    }
+}
+
+fn seconds(secs: f64) -> Option<Duration> {
+    if secs > 0.0 {
+        Duration::try_from_secs_f64(secs).ok()
+    } else {
+        None
+    }
+}
+
+fn read_file(path: &Path) -> Result<Settings, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|error| ConfigError::Read { path: path.to_path_buf(), error })?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse { path: path.to_path_buf(), message: e.to_string() })
+}
+
+//Reads the flags into a Settings layer, and picks out the config file since that has to be
+//read before the flags are applied.
+fn parse_flags<I, S>(args: I) -> Result<(Option<PathBuf>, Settings), ConfigError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+{
+    let mut args = args.into_iter().map(Into::into);
+    let mut file = None;
+    let mut flags = Settings::default();
+    let mut positional = false;
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help);
+        }
+        if !arg.starts_with('-') {
+            //A bare argument is the document root, like it always was.
+            if positional {
+                return Err(ConfigError::Usage(format!("unexpected argument {:?}", arg)));
+            }
+            positional = true;
+            flags.root = Some(PathBuf::from(arg));
+            continue;
+        }
+        //Both `--flag value` and `--flag=value` work.
+        let (flag, value) = match arg.find('=') {
+            Some(i) => (arg[..i].to_string(), arg[i + 1..].to_string()),
+            None => {
+                let value = args.next().ok_or_else(|| ConfigError::Usage(format!("{} needs a value", arg)))?;
+                (arg, value)
+            }
+        };
+        match &flag[..] {
+            "-c" | "--config" => file = Some(PathBuf::from(value)),
+            "--listen" => flags.listen = Some(value),
+            "--https-listen" => flags.https.listen = Some(value),
+            "--cert" => flags.https.cert = Some(PathBuf::from(value)),
+            "--key" => flags.https.key = Some(PathBuf::from(value)),
+            "-w" | "--workers" => flags.workers = Some(number(&flag, &value)?),
+            "--queue-capacity" => flags.queue_capacity = Some(number(&flag, &value)?),
+            "--root" => flags.root = Some(PathBuf::from(value)),
+            "--metrics-path" => flags.metrics_path = Some(value),
+            "--max-requests" => flags.max_requests = Some(number(&flag, &value)?),
+            "--io-mode" => flags.io_mode = Some(value),
+            "--idle-timeout" => flags.timeouts.idle = Some(number(&flag, &value)?),
+            "--request-timeout" => flags.timeouts.request = Some(number(&flag, &value)?),
+            "--shutdown-timeout" => flags.timeouts.shutdown = Some(number(&flag, &value)?),
+            "--access-log" => flags.log.path = Some(PathBuf::from(value)),
+            "--log-format" => flags.log.format = Some(value),
+            "--log-rotate-at" => flags.log.rotate_at = Some(number(&flag, &value)?),
+            "--log-keep" => flags.log.keep = Some(number(&flag, &value)?),
+            "--connections-per-ip" => flags.limits.connections_per_ip = Some(number(&flag, &value)?),
+            "--rate-limit" => flags.limits.rate = Some(number(&flag, &value)?),
+            "--rate-burst" => flags.limits.burst = Some(number(&flag, &value)?),
+            _ => return Err(ConfigError::Usage(format!("unknown flag {}", flag))),
+        }
+    }
+    Ok((file, flags))
+}
+
+fn number<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::Usage(format!("{} expects a number, got {:?}", flag, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::TempDir;
+
+    //A directory with a config file in it, which doubles as the document root.
+    struct ConfigDir(TempDir);
+
+    impl ConfigDir {
+        fn with_config(toml: &str) -> ConfigDir {
+            let dir = TempDir::new("config");
+            fs::write(dir.join("mt_server.toml"), toml).unwrap();
+            ConfigDir(dir)
+        }
+
+        fn file(&self) -> String {
+            self.0.join("mt_server.toml").to_str().unwrap().to_string()
+        }
+
+        fn root(&self) -> String {
+            self.0.path().to_str().unwrap().to_string()
+        }
+    }
+
+    fn problems(result: Result<Config, ConfigError>) -> Vec<String> {
+        match result {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected invalid settings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let dir = ConfigDir::with_config(
+            "workers = 8\nqueue_capacity = 0\nmetrics_path = \"\"\nio_mode = \"blocking\"\n\n[timeouts]\nidle = 2.5\n\n[log]\nformat = \"json\"\nkeep = 2\n",
+        );
+        let config = Config::from_args(vec![
+            "--config", &dir.file(), &dir.root(), "--workers=16", "--listen", "127.0.0.1:0", "--access-log", "", "--io-mode", "event",
+        ])
+            .unwrap();
+        assert_eq!(config.workers, 16);
+        assert_eq!(config.io_mode, IoMode::Event);
+        assert_eq!(config.listen, "127.0.0.1:0");
+        assert_eq!(config.root, dir.0.path());
+        assert_eq!(config.queue_capacity, None);
+        assert_eq!(config.metrics_path, None);
+        assert_eq!(config.idle_timeout, Duration::from_millis(2500));
+        assert_eq!(config.connection().idle_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.log.path, None);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.keep, 2);
+        //Settings nobody touched keep their defaults.
+        assert_eq!(config.request_timeout, Duration::from_secs(30));
+        assert!(!config.https.enabled());
+    }
+
+    #[test]
+    fn limits_come_from_the_file_and_flags() {
+        let dir = ConfigDir::with_config(
+            "[limits]\nconnections_per_ip = 4\nrate = 5\n\n[limits.routes.\"/sleep\"]\nrate = 0.2\n\n[limits.routes.\"/api/*rest\"]\nrate = 10\nburst = 20\n",
+        );
+        let config = Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root(), "--rate-burst", "3"]).unwrap();
+        assert_eq!(config.limits.connections_per_ip, Some(4));
+        assert_eq!(config.limits.rate, Some(5.0));
+        assert_eq!(config.limits.burst, 3);
+        assert_eq!(config.limits.routes["/sleep"], RateConfig { rate: 0.2, burst: 1 });
+        assert_eq!(config.limits.routes["/api/*rest"], RateConfig { rate: 10.0, burst: 20 });
+
+        let config = Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root(), "--rate-limit=0", "--connections-per-ip", "0"]).unwrap();
+        assert_eq!(config.limits.rate, None);
+        assert_eq!(config.limits.connections_per_ip, None);
+
+        let dir = ConfigDir::with_config("[limits]\nrate = -1\nburst = 0\n[limits.routes.sleep]\nrate = 1\n");
+        let problems = problems(Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root()]));
+        assert_eq!(
+            problems,
+            [
+                "limits.rate must be a positive number of requests a second, got -1",
+                "limits.burst must be at least 1",
+                "limits.routes \"sleep\" is not a route, those start with a /",
+            ]
+        );
+    }
+
+    #[test]
+    fn proxies_come_from_the_file() {
+        let dir = ConfigDir::with_config(
+            "[proxy.\"/api/*rest\"]\nupstreams = [\"127.0.0.1:3000\", \"127.0.0.1:3001\"]\ntimeout = 2.5\n\n\
+             [proxy.\"/app\"]\nupstreams = [\"localhost:4000\"]\n",
+        );
+        let config = Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root()]).unwrap();
+        let api = &config.proxies["/api/*rest"];
+        assert_eq!(api.upstreams, ["127.0.0.1:3000", "127.0.0.1:3001"]);
+        assert_eq!(api.timeout, Duration::from_millis(2500));
+        assert_eq!(api.connect_timeout, Duration::from_secs(5));
+        assert_eq!(config.proxies["/app"].timeout, Duration::from_secs(30));
+
+        let dir = ConfigDir::with_config("[proxy.api]\nupstreams = []\nconnect_timeout = 0\n[proxy.\"/x\"]\nupstreams = [\"nowhere\"]\n");
+        let problems = problems(Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root()]));
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems.iter().any(|p| p == "proxy.\"api\".connect_timeout must be a positive number of seconds"));
+        assert!(problems.iter().any(|p| p == "proxy \"api\" is not a route, those start with a /"));
+        assert!(problems.iter().any(|p| p == "proxy.\"api\" needs at least one upstream"));
+        assert!(problems.iter().any(|p| p.starts_with("proxy.\"/x\" upstream \"nowhere\"")));
+    }
+
+    #[test]
+    fn cgi_programs_come_from_the_file() {
+        let dir = ConfigDir::with_config("[cgi.\"/report/*path_info\"]\nprogram = \"python3\"\nargs = [\"report.py\", \"-q\"]\ntimeout = 1.5\n");
+        let config = Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root()]).unwrap();
+        let report = &config.cgi["/report/*path_info"];
+        assert_eq!(report.program, PathBuf::from("python3"));
+        assert_eq!(report.args, ["report.py", "-q"]);
+        assert_eq!(report.timeout, Duration::from_millis(1500));
+
+        let dir = ConfigDir::with_config("[cgi.hello]\nprogram = \"/no/such/program\"\ntimeout = -2\n");
+        let problems = problems(Config::from_args(vec!["-c", &dir.file(), "--root", &dir.root()]));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems.iter().any(|p| p == "cgi.\"hello\".timeout must be a positive number of seconds"));
+        assert!(problems.iter().any(|p| p == "cgi \"hello\" is not a route, those start with a /"));
+        assert!(problems.iter().any(|p| p == "cgi.\"hello\" program /no/such/program doesn't exist"));
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let dir = ConfigDir::with_config("workers = 0\nlisten = \"nowhere\"\nroot = \"/no/such/root\"\n[log]\nformat = \"xml\"\n[https]\ncert = \"missing.pem\"\n");
+        let problems = problems(Config::from_file(dir.file()));
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems.iter().any(|p| p == "workers must be at least 1"));
+        assert!(problems.iter().any(|p| p.starts_with("listen \"nowhere\"")));
+        assert!(problems.iter().any(|p| p.contains("unknown log format \"xml\"")));
+        assert!(problems.iter().any(|p| p == "https.cert missing.pem doesn't exist"));
+        assert!(problems.iter().any(|p| p == "https.key is needed for HTTPS"));
+        assert!(problems.iter().any(|p| p.starts_with("the document root")));
+
+        let problems = problems_for(&["--idle-timeout", "-1", "--io-mode", "threads", "--root", &dir.root()]);
+        assert_eq!(
+            problems,
+            ["unknown I/O mode \"threads\", expected blocking or event", "the idle timeout must be a positive number of seconds"]
+        );
+    }
+
+    fn problems_for(args: &[&str]) -> Vec<String> {
+        problems(Config::from_args(args.iter().cloned()))
+    }
+
+    #[test]
+    fn bad_files_and_flags_are_errors() {
+        let dir = ConfigDir::with_config("wrokers = 4\n");
+        match Config::from_file(dir.file()) {
+            Err(ConfigError::Parse { message, .. }) => assert!(message.contains("wrokers"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+        let dir = ConfigDir::with_config("workers = \"four\"\n");
+        assert!(matches!(Config::from_file(dir.file()), Err(ConfigError::Parse { .. })));
+        assert!(matches!(Config::from_file("/no/such/mt_server.toml"), Err(ConfigError::Read { .. })));
+
+        let usage = |args: &[&str]| match Config::from_args(args.iter().cloned()) {
+            Err(ConfigError::Usage(message)) => message,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(usage(&["--workers", "four"]), "--workers expects a number, got \"four\"");
+        assert_eq!(usage(&["--workers"]), "--workers needs a value");
+        assert_eq!(usage(&["--frobnicate=1"]), "unknown flag --frobnicate");
+        assert_eq!(usage(&["a", "b"]), "unexpected argument \"b\"");
+        assert!(matches!(Config::from_args(vec!["--workers", "2", "--help"]), Err(ConfigError::Help)));
+    }
+}
@@ -0,0 +1,419 @@
+//Originally every connection got exactly one request and was then dropped, which means
+//browsers had to open a new TCP connection for every single thing on a page.
+//HTTP/1.1 connections are persistent by default, so here we keep reading requests
+//off of the same stream until the client asks us to close, goes quiet for too long,
+//or has used up its allowance of requests. Pipelined requests (several requests sent
+//before reading any responses) just sit in the RequestReader's buffer and are answered
+//in order.
+//
+//A handler can also take the connection over for another protocol, like a WebSocket, by
+//answering 101 Switching Protocols with an upgrade attached. The upgrade runs right here on
+//the worker that served the request, and when it returns the connection is closed.
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use access_log::{AccessLog, LogEntry};
+use request::{Limits, Method, ReadError, Request, RequestReader, Version};
+use response::{Response, Upgrade};
+use router::Handler;
+
+/// Settings for how a single connection is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// Whether connections may be reused for more than one request at all.
+    pub keep_alive: bool,
+    /// How long to wait for the next request (or the rest of one) before giving up.
+    /// `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    /// The most requests served on one connection before it is closed.
+    pub max_requests: usize,
+    pub limits: Limits,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            keep_alive: true,
+            idle_timeout: Some(Duration::from_secs(5)),
+            max_requests: 100,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// A connection that can be read from and written to, and whose reads can be given up
+/// on after a while.
+pub trait Socket: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<S: Socket + ?Sized> Socket for &mut S {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+/// A connection that's been handed over to another protocol by a 101 Switching Protocols
+/// response. Reads start with anything the client sent right after its request.
+pub struct Upgraded<'a> {
+    socket: &'a mut dyn Socket,
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> Upgraded<'a> {
+    pub(crate) fn new(socket: &'a mut dyn Socket, buffered: Vec<u8>) -> Upgraded<'a> {
+        Upgraded { socket, buffered, pos: 0 }
+    }
+
+    /// How long a read waits for data before it fails with `WouldBlock` or `TimedOut`.
+    /// `None` waits forever. The connection comes with the server's idle timeout set.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+}
+
+impl<'a> Read for Upgraded<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = (&self.buffered[self.pos..]).read(buf)?;
+            self.pos += n;
+            return Ok(n);
+        }
+        self.socket.read(buf)
+    }
+}
+
+impl<'a> Write for Upgraded<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+//What happens to a connection after a response.
+pub(crate) enum After {
+    KeepAlive,
+    Close,
+    Upgrade(Upgrade),
+}
+
+/// Whether the client wants the connection kept open after this request.
+pub fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        //HTTP/1.1 is persistent unless told otherwise
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        //while HTTP/1.0 has to ask for it.
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+/// Serve requests from `stream` with `handler` until the connection should be closed.
+///
+/// This blocks for as long as the connection lives, so it is meant to be run as a job
+/// on the ThreadPool.
+pub fn serve<H: Handler + ?Sized>(stream: TcpStream, handler: &H, config: &ConnectionConfig) -> io::Result<()> {
+    serve_logged(stream, handler, config, None)
+}
+
+/// Like `serve`, but every request that gets a response is also recorded in `log`.
+pub fn serve_logged<H: Handler + ?Sized>(
+    stream: TcpStream,
+    handler: &H,
+    config: &ConnectionConfig,
+    log: Option<&dyn AccessLog>,
+) -> io::Result<()> {
+    stream.set_read_timeout(config.idle_timeout)?;
+    let peer = stream.peer_addr().ok();
+    serve_stream(stream, peer, handler, config, log)
+}
+
+//The request loop itself, for anything we can read requests from and write responses to.
+//A TLS session is one object that does both, so the same stream is used for both directions.
+pub(crate) fn serve_stream<S: Socket, H: Handler + ?Sized>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    handler: &H,
+    config: &ConnectionConfig,
+    log: Option<&dyn AccessLog>,
+) -> io::Result<()> {
+    let mut reader = RequestReader::with_limits(stream, config.limits);
+    let mut served = 0;
+
+    loop {
+        let mut request = match reader.read_request() {
+            Ok(Some(request)) => request,
+            //The client closed the connection between requests.
+            Ok(None) => return Ok(()),
+            Err(ReadError::Parse(e)) => {
+                //After a bad request we don't know where the next one starts so we have to close.
+                let mut response = Response::new(e.status().0).with_header("Connection", "close");
+                return response.write_to(reader.get_mut()).map(|_| ());
+            }
+            Err(ReadError::Io(ref e)) if is_timeout(e) => {
+                //Going quiet between requests is normal, going quiet halfway through one isn't.
+                if reader.buffered().is_empty() {
+                    return Ok(());
+                }
+                let mut response = Response::new(408).with_header("Connection", "close");
+                return response.write_to(reader.get_mut()).map(|_| ());
+            }
+            Err(ReadError::Io(e)) => return Err(e),
+        };
+        served += 1;
+        request.peer = peer;
+
+        match respond(reader.get_mut(), request, served, handler, config, log)? {
+            After::KeepAlive => {}
+            After::Close => return Ok(()),
+            After::Upgrade(upgrade) => {
+                let (mut stream, buffered) = reader.into_parts();
+                upgrade.run(Upgraded::new(&mut stream, buffered));
+                return Ok(());
+            }
+        }
+    }
+}
+
+//Answers one request and tells what to do with the connection after it. `served` counts
+//this request too.
+pub(crate) fn respond<W: Write, H: Handler + ?Sized>(
+    writer: &mut W,
+    request: Request,
+    served: usize,
+    handler: &H,
+    config: &ConnectionConfig,
+    log: Option<&dyn AccessLog>,
+) -> io::Result<After> {
+    let keep_alive = config.keep_alive && served < config.max_requests && wants_keep_alive(&request);
+    let version = request.version;
+    let head_only = request.method == Method::Head;
+    //The handler takes the request so we have to note down what we want to log first.
+    let started = Instant::now();
+    let entry = log.map(|_| LogEntry::begin(&request, request.peer));
+
+    let mut response = handler.handle(request);
+    let upgrade = if response.status == 101 { response.upgrade.take() } else { None };
+    //An HTTP/1.0 client can't be sent chunks, so a body of unknown length ends with the connection.
+    let keep_alive = keep_alive
+        && !response.headers.has_token("Connection", "close")
+        && !(version == Version::Http10 && response.body.len().is_none());
+    //An upgrade's own Connection: Upgrade has to go out as it is.
+    if upgrade.is_none() {
+        if !keep_alive {
+            response.headers.insert("Connection", "close");
+        } else if version == Version::Http10 {
+            response.headers.insert("Connection", "keep-alive");
+        }
+    }
+
+    let written = response.send(writer, version, head_only);
+    if let (Some(log), Some(mut entry)) = (log, entry) {
+        entry.status = response.status;
+        entry.bytes = *written.as_ref().unwrap_or(&0);
+        entry.duration = started.elapsed();
+        log.log(&entry);
+    }
+    written?;
+    Ok(match upgrade {
+        Some(upgrade) => After::Upgrade(upgrade),
+        None if keep_alive => After::KeepAlive,
+        None => After::Close,
+    })
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    //Depending on the platform a read timeout shows up as either of these.
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Instant;
+
+    use router::Router;
+
+    //Starts a server for a single connection and hands back a client connected to it.
+    fn connect(config: ConnectionConfig) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut router = Router::new();
+            router.get("/:name", |req: Request| Response::ok().with_body(req.param("name").unwrap().to_string()));
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &router, &config).unwrap();
+        });
+        let client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    fn read_all(client: &mut TcpStream) -> String {
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client
+            .write_all(b"GET /one HTTP/1.1\r\nHost: a\r\n\r\n\
+                         HEAD /two HTTP/1.1\r\nHost: a\r\n\r\n\
+                         GET /three HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let out = read_all(&mut client);
+        server.join().unwrap();
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 3);
+        let one = out.find("\r\n\r\none").unwrap();
+        let three = out.find("\r\n\r\nthree").unwrap();
+        assert!(one < three);
+        //HEAD gets the Content-Length of the body but not the body itself.
+        assert!(!out.contains("two"));
+        assert!(out.ends_with("Connection: close\r\nContent-Length: 5\r\n\r\nthree"));
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let config = ConnectionConfig { max_requests: 2, ..ConnectionConfig::default() };
+        let (mut client, server) = connect(config);
+        client
+            .write_all(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n\
+                         GET /b HTTP/1.1\r\nHost: a\r\n\r\n\
+                         GET /c HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+
+        let out = read_all(&mut client);
+        server.join().unwrap();
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(out.matches("Connection: close").count(), 1);
+    }
+
+    #[test]
+    fn http10_closes_unless_asked_to_keep_alive() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client
+            .write_all(b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\nGET /c HTTP/1.0\r\n\r\n")
+            .unwrap();
+
+        let out = read_all(&mut client);
+        server.join().unwrap();
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(out.contains("Connection: keep-alive"));
+        assert!(out.ends_with("\r\n\r\nb"));
+    }
+
+    #[test]
+    fn idle_connections_time_out() {
+        let config = ConnectionConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..ConnectionConfig::default()
+        };
+        let (mut client, server) = connect(config);
+        let start = Instant::now();
+        client.write_all(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+
+        let out = read_all(&mut client);
+        server.join().unwrap();
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn stalled_requests_get_408() {
+        let config = ConnectionConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..ConnectionConfig::default()
+        };
+        let (mut client, server) = connect(config);
+        client.write_all(b"GET /a HTTP/1.1\r\nHo").unwrap();
+
+        let out = read_all(&mut client);
+        server.join().unwrap();
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn bad_requests_close_the_connection() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client.write_all(b"GET / HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+
+        let out = read_all(&mut client);
+        server.join().unwrap();
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(!out.contains("200 OK"));
+    }
+
+    #[test]
+    fn logs_every_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&entries);
+        let server = thread::spawn(move || {
+            let mut router = Router::new();
+            router.get("/:name", |req: Request| Response::ok().with_body(req.param("name").unwrap().to_string()));
+            let (stream, _) = listener.accept().unwrap();
+            let log = move |entry: &LogEntry| log.lock().unwrap().push(entry.clone());
+            serve_logged(stream, &router, &ConnectionConfig::default(), Some(&log)).unwrap();
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /hello?x=1 HTTP/1.1\r\nHost: a\r\nUser-Agent: test\r\n\r\n\
+                         HEAD /two HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        read_all(&mut client);
+        server.join().unwrap();
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].method, Method::Get);
+        assert_eq!(entries[0].target, "/hello?x=1");
+        assert_eq!((entries[0].status, entries[0].bytes), (200, 5));
+        assert_eq!(entries[0].user_agent.as_deref(), Some("test"));
+        assert_eq!(entries[0].peer.map(|p| p.ip()), Some(addr.ip()));
+        //HEAD responses don't send their body.
+        assert_eq!((entries[1].method.clone(), entries[1].bytes), (Method::Head, 0));
+    }
+
+    #[test]
+    fn unknown_lengths_are_chunked_or_end_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = |_: Request| Response::ok().with_reader(&b"streamed"[..], None);
+            for _ in 0..2 {
+                let (client, _) = listener.accept().unwrap();
+                serve(client, &stream, &ConnectionConfig::default()).unwrap();
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut client).ends_with("Transfer-Encoding: chunked\r\n\r\n8\r\nstreamed\r\n0\r\n\r\n"));
+
+        //HTTP/1.0 asked to keep the connection open, but the end of the body is the end of the connection.
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        let out = read_all(&mut client);
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(out.ends_with("\r\n\r\nstreamed"));
+        server.join().unwrap();
+    }
+}
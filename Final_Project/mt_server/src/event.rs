@@ -0,0 +1,411 @@
+//In the blocking mode a connection has a worker to itself until it closes, so four idle
+//keep-alive clients are enough to stall a four worker pool. The event mode keeps every
+//connection on an epoll instance instead. The accept thread waits on all of them at once,
+//and a connection only gets a worker once a whole request has arrived. The worker answers
+//it, checks for another request that's already been sent, and otherwise hands the
+//connection back to the poller through a channel, waking it up with an eventfd so it
+//starts waiting on the connection again.
+//
+//Every connection is registered as EPOLLONESHOT, so once it fires it stays quiet until it's
+//rearmed. That way a connection that's with a worker never shows up on the poller as well.
+//
+//HTTPS connections are served the blocking way even in this mode, since the TLS session
+//would need to be driven without blocking too.
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use server::{Context, OpenConnections, Server};
+use ThreadPool;
+
+#[cfg(target_os = "linux")]
+pub(crate) fn run(server: &Server, pool: &ThreadPool, context: &Context, open: &Arc<Mutex<OpenConnections>>) -> io::Result<()> {
+    linux::run(server, pool, context, open)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn run(_: &Server, _: &ThreadPool, _: &Context, _: &Arc<Mutex<OpenConnections>>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the event I/O mode needs epoll, which only Linux has"))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use libc;
+
+    use connection::{self, After, Upgraded};
+    use request::{ReadError, Request, RequestReader};
+    use response::Response;
+    use server::{self, Context, OpenConnections, Registration, Server, POLL_INTERVAL};
+    use tls::TlsConfig;
+    use ThreadPool;
+
+    //Tokens for the things that aren't connections. Connections count up from FIRST_CONNECTION.
+    const LISTENER: u64 = 0;
+    const HTTPS_LISTENER: u64 = 1;
+    const WAKER: u64 = 2;
+    const FIRST_CONNECTION: u64 = 3;
+
+    const MAX_EVENTS: usize = 256;
+    //How often the idle connections are checked against the idle timeout.
+    const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    //A thin wrapper around an epoll instance.
+    struct Poller {
+        fd: RawFd,
+    }
+
+    impl Poller {
+        fn new() -> io::Result<Poller> {
+            let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+            Ok(Poller { fd })
+        }
+
+        fn ctl(&self, op: libc::c_int, fd: RawFd, token: u64, events: libc::c_int) -> io::Result<()> {
+            let mut event = libc::epoll_event { events: events as u32, u64: token };
+            check(unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) }).map(|_| ())
+        }
+
+        //Listeners and the waker stay armed.
+        fn watch(&self, fd: RawFd, token: u64) -> io::Result<()> {
+            self.ctl(libc::EPOLL_CTL_ADD, fd, token, libc::EPOLLIN)
+        }
+
+        //Connections fire once and then wait to be rearmed.
+        fn watch_once(&self, fd: RawFd, token: u64) -> io::Result<()> {
+            self.ctl(libc::EPOLL_CTL_ADD, fd, token, libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLONESHOT)
+        }
+
+        fn rearm(&self, fd: RawFd, token: u64) -> io::Result<()> {
+            self.ctl(libc::EPOLL_CTL_MOD, fd, token, libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLONESHOT)
+        }
+
+        //Fills `events` with the tokens that are ready, waiting up to `timeout` for one.
+        fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+            events.clear();
+            let n = unsafe { libc::epoll_wait(self.fd, events.as_mut_ptr(), events.capacity() as libc::c_int, timeout.as_millis() as libc::c_int) };
+            match check(n) {
+                Ok(n) => {
+                    unsafe { events.set_len(n as usize) };
+                    Ok(())
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                Err(e) => Err(e),
+            }
+        }
+    }
+
+    impl Drop for Poller {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    //An eventfd the workers poke when they hand a connection back, so the poller doesn't sit
+    //out the rest of its wait before noticing.
+    struct Waker {
+        fd: RawFd,
+    }
+
+    impl Waker {
+        fn new() -> io::Result<Waker> {
+            let fd = check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+            Ok(Waker { fd })
+        }
+
+        fn wake(&self) {
+            let one: u64 = 1;
+            //This only fails if the counter is about to overflow, and then the poller is awake anyway.
+            unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8) };
+        }
+
+        fn reset(&self) {
+            let mut count: u64 = 0;
+            unsafe { libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+        }
+    }
+
+    impl Drop for Waker {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    //A connection waiting for its next request, or being answered by a worker.
+    struct Connection {
+        reader: RequestReader<TcpStream>,
+        peer: Option<SocketAddr>,
+        served: usize,
+        //When it went idle, or when the request it's partway through started coming in.
+        since: Instant,
+        //The id on the open list, where a clone of the stream lives.
+        id: usize,
+        //Last so the stream is closed by the time the connection leaves the open list.
+        _registration: Registration,
+    }
+
+    impl Connection {
+        fn fd(&self) -> RawFd {
+            self.reader.get_ref().as_raw_fd()
+        }
+    }
+
+    pub(crate) fn run(server: &Server, pool: &ThreadPool, context: &Context, open: &Arc<Mutex<OpenConnections>>) -> io::Result<()> {
+        let poller = Poller::new()?;
+        let waker = Arc::new(Waker::new()?);
+        poller.watch(server.listener.as_raw_fd(), LISTENER)?;
+        if let Some((ref listener, _)) = server.https {
+            poller.watch(listener.as_raw_fd(), HTTPS_LISTENER)?;
+        }
+        poller.watch(waker.fd, WAKER)?;
+
+        let (sender, returned) = mpsc::channel();
+        let mut event_loop = EventLoop {
+            server,
+            pool,
+            context,
+            open,
+            poller: &poller,
+            waker: &waker,
+            sender,
+            waiting: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+        };
+        let mut events = Vec::with_capacity(MAX_EVENTS);
+        let mut swept = Instant::now();
+
+        while !server.should_stop() {
+            poller.wait(&mut events, POLL_INTERVAL)?;
+            for event in &events {
+                //epoll_event is packed, so the field has to be copied out before it's used.
+                let token = { event.u64 };
+                match token {
+                    LISTENER => event_loop.accept(&server.listener, None)?,
+                    HTTPS_LISTENER => {
+                        if let Some((ref listener, ref tls)) = server.https {
+                            event_loop.accept(listener, Some(tls))?;
+                        }
+                    }
+                    WAKER => waker.reset(),
+                    token => {
+                        if let Some(connection) = event_loop.waiting.remove(&token) {
+                            event_loop.readable(token, connection);
+                        }
+                    }
+                }
+            }
+            //Connections the workers are done with wait for their next request here again.
+            for (token, connection) in returned.try_iter() {
+                event_loop.wait_for_request(token, connection);
+            }
+            if swept.elapsed() >= SWEEP_INTERVAL {
+                event_loop.sweep();
+                swept = Instant::now();
+            }
+        }
+        //The connections that are waiting have nothing in flight, so they're just closed.
+        event_loop.waiting.clear();
+        Ok(())
+    }
+
+    struct EventLoop<'a> {
+        server: &'a Server,
+        pool: &'a ThreadPool,
+        context: &'a Context,
+        open: &'a Arc<Mutex<OpenConnections>>,
+        poller: &'a Poller,
+        waker: &'a Arc<Waker>,
+        sender: Sender<(u64, Connection)>,
+        waiting: HashMap<u64, Connection>,
+        next_token: u64,
+    }
+
+    impl<'a> EventLoop<'a> {
+        fn accept(&mut self, listener: &TcpListener, tls: Option<&TlsConfig>) -> io::Result<()> {
+            loop {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    //Errors like running out of file descriptors only affect this one connection.
+                    Err(e) => {
+                        println!("Failed to accept a connection: {}", e);
+                        return Ok(());
+                    }
+                };
+                //Same as in the blocking mode, failing here only costs us this one connection.
+                let registered = stream.set_nonblocking(false).and_then(|_| self.server.register(&stream, tls.is_some(), self.open));
+                let (id, registration) = match registered {
+                    Ok(Some(registered)) => registered,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("Failed to set up a connection: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(tls) = tls {
+                    server::serve_connection(self.pool, self.context, stream, Some(tls.clone()), id, registration, self.open);
+                    continue;
+                }
+
+                if let Err(e) = stream.set_nonblocking(true) {
+                    println!("Failed to set up a connection: {}", e);
+                    continue;
+                }
+                let token = self.next_token;
+                self.next_token += 1;
+                let connection = Connection {
+                    peer: stream.peer_addr().ok(),
+                    reader: RequestReader::with_limits(stream, self.context.config.limits),
+                    served: 0,
+                    since: Instant::now(),
+                    id,
+                    _registration: registration,
+                };
+                if let Err(e) = self.poller.watch_once(connection.fd(), token) {
+                    println!("Can't wait on a connection: {}", e);
+                    continue;
+                }
+                self.waiting.insert(token, connection);
+            }
+        }
+
+        //Reads what the client sent and hands the connection to a worker once there's a request.
+        fn readable(&mut self, token: u64, mut connection: Connection) {
+            let between_requests = connection.reader.buffered().is_empty();
+            match connection.reader.read_request() {
+                Ok(Some(request)) => self.dispatch(token, connection, request),
+                //Only part of a request so far. The clock starts over when a request begins, not
+                //on every piece of one, or a client sending a byte at a time could stay forever.
+                Err(ReadError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if between_requests && !connection.reader.buffered().is_empty() {
+                        connection.since = Instant::now();
+                    }
+                    self.wait_for_request(token, connection);
+                }
+                Err(ReadError::Parse(e)) => reject(&connection, Response::new(e.status().0)),
+                //The client closed the connection or it broke. Either way it's done.
+                Ok(None) | Err(ReadError::Io(_)) => {}
+            }
+        }
+
+        fn wait_for_request(&mut self, token: u64, connection: Connection) {
+            match self.poller.rearm(connection.fd(), token) {
+                Ok(()) => {
+                    self.waiting.insert(token, connection);
+                }
+                Err(e) => println!("Can't wait on a connection: {}", e),
+            }
+        }
+
+        fn dispatch(&mut self, token: u64, connection: Connection, request: Request) {
+            let id = connection.id;
+            let context = self.context.clone();
+            let sender = self.sender.clone();
+            let waker = Arc::clone(self.waker);
+            let priority = self.server.priority_of(request.path());
+            let job = self.pool.execute_priority(priority, move || answer(connection, request, token, context, sender, waker));
+            if let Err(job) = job {
+                //Every worker is busy and the queue is full, same as in the blocking mode. The
+                //lock is let go of before the job is dropped, since that takes it off the open list.
+                {
+                    let open = &mut *self.open.lock().unwrap();
+                    if let Some(stream) = open.streams.get(&id) {
+                        let _ = server::turn_away(stream, Response::new(503).with_header("Retry-After", "1"), &mut open.drainer);
+                    }
+                }
+                drop(job.into_job());
+            }
+        }
+
+        //Closes the connections that have been waiting for longer than the idle timeout.
+        fn sweep(&mut self) {
+            let timeout = match self.context.config.idle_timeout {
+                Some(timeout) => timeout,
+                None => return,
+            };
+            let expired: Vec<u64> = self.waiting.iter().filter(|&(_, c)| c.since.elapsed() >= timeout).map(|(&t, _)| t).collect();
+            for token in expired {
+                if let Some(connection) = self.waiting.remove(&token) {
+                    //Going quiet between requests is normal, going quiet halfway through one isn't.
+                    if !connection.reader.buffered().is_empty() {
+                        reject(&connection, Response::new(408));
+                    }
+                }
+            }
+        }
+    }
+
+    //Answers with `response` and closes. The socket doesn't block so this is best effort,
+    //but these responses are tiny.
+    fn reject(connection: &Connection, response: Response) {
+        let mut response = response.with_header("Connection", "close");
+        let _ = response.write_to(&mut connection.reader.get_ref());
+    }
+
+    //Runs on a worker: answers the request and any others the client already sent, then
+    //gives the connection back to the poller if it's being kept alive.
+    fn answer(mut connection: Connection, request: Request, token: u64, context: Context, sender: Sender<(u64, Connection)>, waker: Arc<Waker>) {
+        let Context { handler, log, config } = context;
+        let mut next = Some(request);
+        while let Some(mut request) = next.take() {
+            connection.served += 1;
+            request.peer = connection.peer;
+            //The worker writes the whole response, so the socket blocks while it's here.
+            if connection.reader.get_mut().set_nonblocking(false).is_err() {
+                return;
+            }
+            match connection::respond(connection.reader.get_mut(), request, connection.served, &*handler, &config, log.as_deref()) {
+                Ok(After::KeepAlive) => {}
+                Ok(After::Close) => return,
+                //Whatever the connection was upgraded to has it for good, still on this worker.
+                Ok(After::Upgrade(upgrade)) => {
+                    let Connection { reader, _registration, .. } = connection;
+                    let (mut stream, buffered) = reader.into_parts();
+                    //Same as in the blocking mode, where the socket has had it all along.
+                    if stream.set_read_timeout(config.idle_timeout).is_err() {
+                        return;
+                    }
+                    upgrade.run(Upgraded::new(&mut stream, buffered));
+                    return;
+                }
+                Err(e) => {
+                    println!("Connection error: {}", e);
+                    return;
+                }
+            }
+
+            if connection.reader.get_mut().set_nonblocking(true).is_err() {
+                return;
+            }
+            match connection.reader.read_request() {
+                //A pipelined request is answered right away.
+                Ok(Some(request)) => next = Some(request),
+                Err(ReadError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    connection.since = Instant::now();
+                    //If the poller is gone the server is shutting down, and dropping the connection closes it.
+                    if sender.send((token, connection)).is_ok() {
+                        waker.wake();
+                    }
+                    return;
+                }
+                Err(ReadError::Parse(e)) => reject(&connection, Response::new(e.status().0)),
+                Ok(None) | Err(ReadError::Io(_)) => {}
+            }
+        }
+    }
+}
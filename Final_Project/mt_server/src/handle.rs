@@ -0,0 +1,133 @@
+//execute only takes closures that return nothing, so getting a value out of a job meant
+//setting up a channel by hand every time. spawn does that for us: the job gets wrapped
+//so that its return value (or its panic) is sent down a channel of its own, and the
+//JobHandle holds on to the receiving end much like thread::spawn's JoinHandle.
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use FnBox;
+
+/// Why a `JobHandle` couldn't hand back the job's return value.
+pub enum JoinError {
+    /// The job panicked. This holds the value it panicked with, like `thread::Result` does.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job will never run, for example because the queue threw it away or the pool
+    /// shut down first, or its result was already taken.
+    Cancelled,
+    /// The job hasn't finished yet. Only returned by `try_join` and `join_timeout`.
+    NotReady,
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JoinError::Panicked(_) => f.write_str("Panicked(..)"),
+            JoinError::Cancelled => f.write_str("Cancelled"),
+            JoinError::NotReady => f.write_str("NotReady"),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JoinError::Panicked(_) => f.write_str("the job panicked"),
+            JoinError::Cancelled => f.write_str("the job was cancelled before it ran"),
+            JoinError::NotReady => f.write_str("the job hasn't finished yet"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// A handle to the result of a job started with `ThreadPool::spawn`.
+///
+/// Dropping the handle doesn't cancel the job, its result is just thrown away.
+pub struct JobHandle<T> {
+    result: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("JobHandle { .. }")
+    }
+}
+
+impl<T> JobHandle<T> {
+    fn finish(result: thread::Result<T>) -> Result<T, JoinError> {
+        result.map_err(JoinError::Panicked)
+    }
+
+    /// Wait for the job to finish and return what it returned.
+    pub fn join(self) -> Result<T, JoinError> {
+        match self.result.recv() {
+            Ok(result) => JobHandle::finish(result),
+            Err(_) => Err(JoinError::Cancelled),
+        }
+    }
+
+    /// Return the result if the job has already finished, without waiting.
+    pub fn try_join(&self) -> Result<T, JoinError> {
+        match self.result.try_recv() {
+            Ok(result) => JobHandle::finish(result),
+            Err(mpsc::TryRecvError::Empty) => Err(JoinError::NotReady),
+            Err(mpsc::TryRecvError::Disconnected) => Err(JoinError::Cancelled),
+        }
+    }
+
+    /// Wait up to `timeout` for the job to finish.
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, JoinError> {
+        match self.result.recv_timeout(timeout) {
+            Ok(result) => JobHandle::finish(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(JoinError::NotReady),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(JoinError::Cancelled),
+        }
+    }
+}
+
+/// The job that `spawn` actually queues. It's a struct instead of a closure so that
+/// a queue that's full can give the caller's closure back.
+pub(crate) struct Spawned<F, T> {
+    f: F,
+    result: mpsc::SyncSender<thread::Result<T>>,
+    panics: Arc<AtomicUsize>,
+}
+
+impl<F, T> Spawned<F, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static
+{
+    pub(crate) fn new(f: F, panics: Arc<AtomicUsize>) -> (Spawned<F, T>, JobHandle<T>) {
+        //There's only ever one result so a single slot means sending never blocks.
+        let (sender, receiver) = mpsc::sync_channel(1);
+        (Spawned { f, result: sender, panics }, JobHandle { result: receiver })
+    }
+
+    pub(crate) fn into_inner(self) -> F {
+        self.f
+    }
+}
+
+impl<F, T> FnBox for Spawned<F, T>
+    where
+        F: FnOnce() -> T
+{
+    fn call_box(self: Box<Self>) {
+        let this = *self;
+        //We catch the panic ourselves so the payload can go to the handle instead of the worker.
+        let result = panic::catch_unwind(AssertUnwindSafe(this.f));
+        if result.is_err() {
+            this.panics.fetch_add(1, Ordering::SeqCst);
+        }
+        //The handle may have been dropped already, in which case nobody wants the result.
+        let _ = this.result.send(result);
+    }
+}
@@ -0,0 +1,138 @@
+//HTTP header names are case-insensitive and a header is allowed to show up more
+//than once, so a plain HashMap<String, String> doesn't quite cut it. We keep the
+//headers in the order they arrived and do case-insensitive lookups instead.
+use std::slice;
+
+/// An ordered, case-insensitive collection of HTTP headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Create an empty set of headers.
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    /// Returns the value of the first header with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the values of every header with the given name in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Checks whether a comma separated header such as `Connection` or
+    /// `Transfer-Encoding` lists the given token. The comparison ignores case.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Replaces every header with the given name by a single new value.
+    pub fn insert<N, V>(&mut self, name: N, value: V)
+        where
+            N: Into<String>,
+            V: Into<String>
+    {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Adds a header without touching any existing values of the same name.
+    pub fn append<N, V>(&mut self, name: N, value: V)
+        where
+            N: Into<String>,
+            V: Into<String>
+    {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { inner: self.entries.iter() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Iterator over `(name, value)` pairs of a `Headers` collection.
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, (String, String)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.inner.next().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_ignore_case() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert!(headers.contains("CONTENT-TYPE"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn insert_replaces_and_append_keeps() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "application/json");
+        assert_eq!(headers.get_all("Accept").collect::<Vec<_>>(), vec!["text/html", "application/json"]);
+
+        headers.insert("ACCEPT", "*/*");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn finds_tokens_in_lists() {
+        let mut headers = Headers::new();
+        headers.append("Connection", "Upgrade, Keep-Alive");
+        assert!(headers.has_token("connection", "keep-alive"));
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+    }
+}
@@ -1,22 +1,206 @@
+extern crate base64;
+extern crate flate2;
+extern crate libc;
+extern crate rustls;
+extern crate serde;
+extern crate sha1_smol;
+extern crate toml;
+
+use std::error::Error;
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
-use std::sync::mpsc;
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+pub mod access_log;
+pub mod builder;
+pub mod compression;
+pub mod cgi;
+pub mod config;
+pub mod connection;
+pub mod date;
+mod event;
+pub mod handle;
+pub mod headers;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod proxy;
+mod queue;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod signal;
+pub mod static_files;
+mod stealing;
+#[cfg(test)]
+mod test_util;
+pub mod timer;
+pub mod tls;
+pub mod websocket;
+
+pub use access_log::{AccessLog, FileLog, LogEntry, LogFormat};
+pub use builder::{PoolCreationError, ThreadPoolBuilder};
+pub use cgi::Cgi;
+pub use compression::Compression;
+pub use config::{Config, ConfigError};
+pub use connection::{ConnectionConfig, Upgraded};
+pub use handle::{JobHandle, JoinError};
+pub use headers::Headers;
+pub use metrics::{HistogramSnapshot, PoolMetrics};
+pub use middleware::{Chain, Middleware, Next, RequestId, SecurityHeaders, Timeout};
+pub use proxy::Proxy;
+pub use queue::{Priority, QueuePolicy, Scheduler};
+pub use rate_limit::RateLimit;
+pub use request::{Method, Request, RequestReader, Version};
+pub use response::{Body, Response, Status};
+pub use router::{Handler, Router};
+pub use server::{IoMode, Server, ShutdownHandle};
+pub use static_files::StaticFiles;
+pub use timer::CancelToken;
+pub use tls::TlsConfig;
+pub use websocket::{WebSocket, WebSocketHandler};
+
+use builder::check_bounds;
+use handle::Spawned;
+use metrics::Recorder;
+use queue::JobQueue;
+use timer::{Task, Timer};
+
+thread_local! {
+    //Set on every worker thread so code running inside of a job can tell which worker it's on.
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// The id of the pool worker the current thread belongs to, or `None` when called from a
+/// thread that isn't one of a `ThreadPool`'s workers.
+pub fn current_worker() -> Option<usize> {
+    WORKER_ID.with(Cell::get)
+}
 
 //We're doing a little bit of refactoring to have a vector of workers which has an
 //id and a JoinHandle. It will also be what handles the closure
 //We want our workers to either fetch a job or terminate depending on what message is being sent to it.
 //This is to deal with the infinit loop inside of the workers closure.
 enum Message {
-    NewJob(Job),
+    //Along with when it was queued, to see how long it waited.
+    NewJob(Job, Instant),
     Terminate,
 }
 
 pub struct ThreadPool{
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    inner: Arc<Inner>,
+    policy: QueuePolicy,
+}
+
+//With a resizable pool the workers come and go, so everything they share with the
+//pool lives in one place that they all hold an Arc to.
+struct Inner {
+    queue: JobQueue,
+    panics: Arc<AtomicUsize>,
+    metrics: Recorder,
+    //How many workers are waiting for a job right now.
+    idle: AtomicUsize,
+    //How long a worker above the minimum waits for a job before it retires.
+    keep_alive: Option<Duration>,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    //Set once the pool stops taking new jobs.
+    closed: AtomicBool,
+    //Only changed while holding the `workers` lock, but read without it so execute
+    //doesn't have to take the lock just to find out the pool can't grow.
+    live: AtomicUsize,
+    max: AtomicUsize,
+    workers: Mutex<Workers>,
+    //Started the first time a job is scheduled for later.
+    timer: Mutex<Option<Timer>>,
+}
+
+struct Workers {
+    list: Vec<Worker>,
+    min: usize,
+    next_id: usize,
+}
+
+impl Inner {
+    fn lock_workers(&self) -> MutexGuard<'_, Workers> {
+        //Nothing panics while this lock is held so there's nothing to worry about if it's poisoned.
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.snapshot(
+            self.live.load(Ordering::SeqCst),
+            self.queue.len(),
+            self.panics.load(Ordering::SeqCst),
+        )
+    }
+
+    //A worker that sat idle for a whole keep-alive period asks to leave. It's only
+    //allowed to while the pool is above its minimum.
+    fn retire(&self) -> bool {
+        let workers = self.lock_workers();
+        let live = self.live.load(Ordering::SeqCst);
+        if live > workers.min {
+            self.live.store(live - 1, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Why `execute` or `spawn` didn't queue a job. The job is handed back either way so
+/// the caller can decide what to do with it.
+pub enum ExecuteError<F> {
+    /// The queue is full. Only happens with `QueuePolicy::Reject`, or with `try_execute`.
+    Full(F),
+    /// The pool has been closed and isn't taking new jobs.
+    ShuttingDown(F),
+}
+
+impl<F> ExecuteError<F> {
+    pub fn into_job(self) -> F {
+        match self {
+            ExecuteError::Full(job) | ExecuteError::ShuttingDown(job) => job,
+        }
+    }
+
+    fn map<G, M: FnOnce(F) -> G>(self, f: M) -> ExecuteError<G> {
+        match self {
+            ExecuteError::Full(job) => ExecuteError::Full(f(job)),
+            ExecuteError::ShuttingDown(job) => ExecuteError::ShuttingDown(f(job)),
+        }
+    }
+}
+
+impl<F> fmt::Debug for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecuteError::Full(_) => f.write_str("Full(..)"),
+            ExecuteError::ShuttingDown(_) => f.write_str("ShuttingDown(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecuteError::Full(_) => f.write_str("the thread pool's job queue is full"),
+            ExecuteError::ShuttingDown(_) => f.write_str("the thread pool is shutting down"),
+        }
+    }
 }
 
+impl<F> Error for ExecuteError<F> {}
+
 impl ThreadPool {
 //The below is how to do a doc comment
     /// Create a new ThreadPool.
@@ -25,61 +209,424 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
-
+    /// The `new` function will panic if the size is zero or a thread can't be started.
+    /// Use `ThreadPool::builder()` to get an error instead.
     pub fn new(size: usize) -> ThreadPool {
-        //We need more than 0 threads
-        assert!(size > 0);
+        ThreadPool::builder().size(size).build().unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        //Arc allows multiple workers to own the receiver and
-        //Mutex ensures that only one worker is getting a job from
-        //the receiver at a time.
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Set up a pool with more options than `new` has, like a bounded queue, thread
+    /// names or a maximum size to grow to.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    /// Create a new ThreadPool that hands jobs to its workers with the given scheduler.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is zero.
+    pub fn with_scheduler(size: usize, scheduler: Scheduler) -> ThreadPool {
+        ThreadPool::builder().size(size).scheduler(scheduler).build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Create a new ThreadPool whose queue holds at most `capacity` jobs that are
+    /// waiting for a worker. `policy` decides what `execute` does once it's full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size or the capacity is zero.
+    pub fn with_queue(size: usize, capacity: usize, policy: QueuePolicy) -> ThreadPool {
+        ThreadPool::builder()
+            .size(size)
+            .queue_capacity(capacity)
+            .queue_policy(policy)
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Create a ThreadPool that starts with `min` workers and adds more, up to `max`,
+    /// whenever a job is queued while every worker is busy. Workers above `min` that go
+    /// `keep_alive` without a job retire again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or `min` is larger than `max`.
+    pub fn dynamic(min: usize, max: usize, keep_alive: Duration) -> ThreadPool {
+        ThreadPool::builder()
+            .size(min)
+            .max_size(max)
+            .keep_alive(keep_alive)
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    fn create(config: ThreadPoolBuilder) -> Result<ThreadPool, PoolCreationError> {
+        //We need more than 0 threads
+        let (min, max) = config.bounds()?;
 
-        for id in 0..size{
+        //Arc allows multiple workers to own the queue and
+        //its Mutex ensures that only one worker is getting a job from
+        //the queue at a time.
+        let inner = Arc::new(Inner {
+            queue: JobQueue::new(config.scheduler, max, config.capacity, config.starvation_limit),
+            panics: Arc::new(AtomicUsize::new(0)),
+            metrics: Recorder::new(),
+            idle: AtomicUsize::new(0),
+            keep_alive: config.keep_alive,
+            name_prefix: config.name_prefix,
+            stack_size: config.stack_size,
+            closed: AtomicBool::new(false),
+            live: AtomicUsize::new(0),
+            max: AtomicUsize::new(max),
+            workers: Mutex::new(Workers {
+                list: Vec::with_capacity(max),
+                min,
+                next_id: 0,
+            }),
+            timer: Mutex::new(None),
+        });
+        let pool = ThreadPool{
+            inner,
+            policy: config.policy,
+        };
+
+        let started = {
+            let mut workers = pool.inner.lock_workers();
             //We're going to create some threads here and then store them in the above vector.
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            (0..min).try_for_each(|_| ThreadPool::add_worker(&pool.inner, &mut workers))
+        };
+        //If one of them couldn't start, dropping the pool stops the ones that did.
+        started.map_err(PoolCreationError::Spawn)?;
+        Ok(pool)
+    }
+
+    fn add_worker(inner: &Arc<Inner>, workers: &mut Workers) -> io::Result<()> {
+        let id = workers.next_id;
+        workers.list.push(Worker::new(id, Arc::clone(inner))?);
+        workers.next_id += 1;
+        inner.live.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    //Called after a job is queued. If there are more jobs waiting than workers free to
+    //pick them up and we're below the maximum, another worker joins in.
+    fn grow_if_busy(inner: &Arc<Inner>) {
+        if inner.live.load(Ordering::SeqCst) >= inner.max.load(Ordering::SeqCst)
+            || inner.queue.len() <= inner.idle.load(Ordering::SeqCst) {
+            return;
         }
+        let mut workers = inner.lock_workers();
+        workers.reap();
+        if inner.live.load(Ordering::SeqCst) < inner.max.load(Ordering::SeqCst) {
+            //The job is queued already, so the workers we have will get to it eventually.
+            if let Err(e) = ThreadPool::add_worker(inner, &mut workers) {
+                println!("Couldn't start another worker: {}", e);
+            }
+        }
+    }
+
+    fn submit<J>(&self, job: J, priority: Priority, wait: bool) -> Result<(), ExecuteError<J>>
+        where
+            J: FnBox + Send + 'static
+    {
+        ThreadPool::submit_to(&self.inner, self.policy, job, priority, wait)
+    }
 
-        ThreadPool{
-            workers,
-            sender,
+    //The timer thread queues its jobs through here, it only has the pool's insides.
+    fn submit_to<J>(inner: &Arc<Inner>, policy: QueuePolicy, job: J, priority: Priority, wait: bool) -> Result<(), ExecuteError<J>>
+        where
+            J: FnBox + Send + 'static
+    {
+        if inner.closed.load(Ordering::SeqCst) {
+            return Err(ExecuteError::ShuttingDown(job));
         }
+        inner.queue.push(job, priority, policy, wait).map_err(ExecuteError::Full)?;
+        ThreadPool::grow_if_busy(inner);
+        Ok(())
     }
 
     //We're going to eventually want to pass the arguments from this into 
     //thread::spawn so we want to make sure we use the same function types as that method.
-    pub fn execute<F>(&self, f: F)
+    /// Queue a job for the next free worker.
+    ///
+    /// When the queue is full this follows the pool's `QueuePolicy`. Only `Reject`
+    /// returns `ExecuteError::Full`; `Block` waits for room and `DropOldest` makes room.
+    /// Once the pool is closed every job is handed back as `ExecuteError::ShuttingDown`.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        self.submit(f, Priority::Normal, true)
+    }
+
+    /// Like `execute` but with a priority other than `Priority::Normal`. Workers pick the
+    /// most urgent job that's waiting, see `Priority`.
+    pub fn execute_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError<F>>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        self.submit(f, priority, true)
+    }
+
+    /// Like `execute` but never waits. With the `Block` policy a full queue
+    /// hands the job back instead.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        self.submit(f, Priority::Normal, false)
+    }
+
+    /// Queue a job whose return value we want back. The returned handle can wait
+    /// for the value, or for the panic payload if the job panics.
+    ///
+    /// Follows the pool's `QueuePolicy` just like `execute`.
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError<F>>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static
+    {
+        self.spawn_priority(Priority::Normal, f)
+    }
+
+    /// Like `spawn` but with a priority other than `Priority::Normal`.
+    pub fn spawn_priority<F, T>(&self, priority: Priority, f: F) -> Result<JobHandle<T>, ExecuteError<F>>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static
+    {
+        let (job, handle) = Spawned::new(f, Arc::clone(&self.inner.panics));
+        self.submit(job, priority, true).map_err(|e| e.map(Spawned::into_inner))?;
+        Ok(handle)
+    }
+
+    /// Queue a job once `delay` has passed. Until then it's kept by the pool's timer thread,
+    /// not in the queue, so it doesn't count against the queue's capacity.
+    ///
+    /// The returned token cancels the job if it hasn't started yet. Jobs that aren't due
+    /// when the pool shuts down never run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timer thread isn't running yet and can't be started.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<CancelToken, ExecuteError<F>>
         where
             F: FnOnce() + Send + 'static
     {
-        let job = Box::new(f);
+        if self.is_closed() {
+            return Err(ExecuteError::ShuttingDown(f));
+        }
+        Ok(self.schedule(Instant::now() + delay, Task::once(f)))
+    }
+
+    /// Queue a job every `interval`, starting one interval from now, until the returned
+    /// token is cancelled or the pool shuts down.
+    ///
+    /// A run that's still queued or running when the next one is due makes that one get
+    /// skipped, so a slow job never runs on two workers at once. The same goes for a run
+    /// that doesn't fit into a full queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero, or if the timer thread isn't running yet and can't be started.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> Result<CancelToken, ExecuteError<F>>
+        where
+            F: FnMut() + Send + 'static
+    {
+        assert!(interval > Duration::from_secs(0), "the interval of a repeating job can't be zero");
+        if self.is_closed() {
+            return Err(ExecuteError::ShuttingDown(f));
+        }
+        Ok(self.schedule(Instant::now() + interval, Task::every(interval, f)))
+    }
+
+    fn schedule(&self, at: Instant, task: Task) -> CancelToken {
+        let mut timer = self.inner.timer.lock().unwrap_or_else(PoisonError::into_inner);
+        if timer.is_none() {
+            let name = format!("{}timer", self.inner.name_prefix);
+            let started = Timer::start(Arc::downgrade(&self.inner), self.policy, name);
+            *timer = Some(started.unwrap_or_else(|e| panic!("Couldn't start the timer thread: {}", e)));
+        }
+        timer.as_ref().unwrap().schedule(at, task)
+    }
+
+    /// Change how many workers the pool keeps. Missing workers up to `min` are started
+    /// right away, and workers above `max` stop once they finish the jobs queued ahead
+    /// of their terminate message.
+    ///
+    /// Fails without changing anything if `max` is zero or `min` is larger than `max`.
+    pub fn resize(&self, min: usize, max: usize) -> Result<(), PoolCreationError> {
+        check_bounds(min, max)?;
+
+        let inner = &self.inner;
+        let mut workers = inner.lock_workers();
+        workers.reap();
+        workers.min = min;
+        inner.max.store(max, Ordering::SeqCst);
+
+        let live = inner.live.load(Ordering::SeqCst);
+        if live > max {
+            //Whoever picks these up leaves. They count as gone from now on so the
+            //terminate messages sent on drop still add up.
+            for _ in max..live {
+                inner.queue.push_unbounded(Message::Terminate);
+            }
+            inner.live.store(max, Ordering::SeqCst);
+        }
+        for _ in live..min {
+            ThreadPool::add_worker(inner, &mut workers).map_err(PoolCreationError::Spawn)?;
+        }
+        Ok(())
+    }
+
+    /// Stop taking new jobs; `execute` and `spawn` hand them back as
+    /// `ExecuteError::ShuttingDown` from now on. Jobs that are already queued still
+    /// run, and the workers stay until the pool is dropped.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    /// The number of workers in the pool, not counting any that have been told to stop.
+    pub fn size(&self) -> usize {
+        self.inner.live.load(Ordering::SeqCst)
+    }
+
+    /// The number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.inner.queue.len()
+    }
+
+    /// How many jobs have panicked since the pool was created. A panicking job
+    /// doesn't take its worker down with it.
+    pub fn panic_count(&self) -> usize {
+        self.inner.panics.load(Ordering::SeqCst)
+    }
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+    /// A snapshot of the pool's counters and job latencies.
+    pub fn metrics(&self) -> PoolMetrics {
+        self.inner.metrics()
+    }
+
+    /// A handle that can take metrics snapshots from elsewhere, for example from
+    /// the handler serving a /metrics page.
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor { inner: Arc::downgrade(&self.inner) }
+    }
+
+    //Sends a terminate message to every worker that's still around and hands back all of
+    //the workers so they can be joined. Every worker that's running either retires on its own
+    //(and already took itself off of `live`) or picks up exactly one terminate message.
+    fn terminate_all(&self) -> Vec<Worker> {
+        let inner = &self.inner;
+        inner.closed.store(true, Ordering::SeqCst);
+        //The timer goes first so it can't queue anything behind the terminate messages.
+        let timer = inner.timer.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(timer) = timer {
+            timer.stop();
+        }
+        let mut workers = inner.lock_workers();
+        for _ in 0..inner.live.load(Ordering::SeqCst) {
+            inner.queue.push_unbounded(Message::Terminate);
+        }
+        //With live at zero no worker can retire anymore, they all wait for their message.
+        inner.live.store(0, Ordering::SeqCst);
+        workers.list.drain(..).collect()
+    }
+
+    /// Shut the pool down like `drop` does, but only wait up to `timeout` for the
+    /// workers to finish the jobs they're on.
+    ///
+    /// Jobs that were queued before this call still run. Returns `true` if every
+    /// worker stopped in time; any that didn't are left running in the background
+    /// instead of being joined.
+    pub fn shutdown_timeout(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let workers = self.terminate_all();
+
+        //A JoinHandle can't be joined with a timeout so we poll until they're all finished.
+        let mut all_stopped = true;
+        for mut worker in workers {
+            let thread = match worker.thread.take() {
+                Some(thread) => thread,
+                None => continue,
+            };
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if thread.is_finished() {
+                //Jobs can't panic their worker to death, so an error here has nothing left to tell us.
+                let _ = thread.join();
+            } else {
+                println!("Worker {} is still busy, leaving it behind.", worker.id);
+                all_stopped = false;
+            }
+        }
+        //The worker list is empty now so the Drop below has nothing left to do.
+        all_stopped
+    }
+}
+
+impl Workers {
+    //Joins the threads of workers that have retired so the list doesn't grow forever.
+    fn reap(&mut self) {
+        let (finished, running): (Vec<Worker>, Vec<Worker>) = self.list
+            .drain(..)
+            .partition(|w| w.thread.as_ref().is_none_or(|t| t.is_finished()));
+        self.list = running;
+        for mut worker in finished {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Takes metrics snapshots of a `ThreadPool` without keeping it alive. Get one from
+/// `ThreadPool::monitor()`.
+#[derive(Clone)]
+pub struct PoolMonitor {
+    //Weak since the jobs in the queue may well own a monitor themselves.
+    inner: Weak<Inner>,
+}
+
+impl PoolMonitor {
+    /// A snapshot of the pool's metrics, or `None` once the pool is gone.
+    pub fn metrics(&self) -> Option<PoolMetrics> {
+        self.inner.upgrade().map(|inner| inner.metrics())
+    }
+}
+
+impl fmt::Debug for PoolMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PoolMonitor { .. }")
     }
 }
+
 //We never properly cleaned up our data once we quit out of everything just using Ctrl-C.
 //So we are going to implement the Drop command to do this for us.
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         println!("Sending terminate message to all workers.");
         //Here we're telling all of the workers to terminate.
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
-        }
+        //The workers lock is let go of before joining since retiring workers need it.
+        let mut workers = self.terminate_all();
 
         println!("Shutting down all workers.");
-        for worker in &mut self.workers {
+        for worker in &mut workers {
             println!("Shutting down worker {}", worker.id);
             //We needed a way to take ownership of thread out of the worker.
             //So we use the take option of Option.
             //This will then replace the thread variable inside of worker with
             // a None type.
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    println!("Worker {} had already died.", worker.id);
+                }
             }
         }
     }
@@ -87,12 +634,13 @@ impl Drop for ThreadPool {
 
 
 //We need some form of actual closures when we create the workers
-//Earlier we learned about channels. Here we'll be using them. We'll have the thread pool
+//Earlier we learned about channels. We started out using one here and have since swapped it
+//for our own JobQueue that can be bounded. We'll have the thread pool
 //hold the jobs and then have the workers fetch the jobs.
 
 //A job struct will hold the closures we want to send down the channel.
-//The execute method on the thread pool will send the jobs down the sending side of the channel
-//Workers will then loop over their receiving side of the channel and execute the closures of
+//The execute method on the thread pool will push the jobs onto the back of the queue
+//Workers will then loop popping jobs off the front of the queue and execute the closures of
 //any jobs they receive.
 
 //In order to get around rust not realizing we can do self: Box<Self>
@@ -111,7 +659,7 @@ impl<F: FnOnce()> FnBox for F {
 //We've changed Job from a struct to a type alias to make
 //our long type just a little shorter
 //The Job now just needs to be updated to use the FnBox trait.
-type Job = Box<FnBox + Send + 'static>;
+type Job = Box<dyn FnBox + Send + 'static>;
 
 struct Worker {
     id: usize,
@@ -122,23 +670,52 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(id: usize, inner: Arc<Inner>) -> io::Result<Worker> {
         //Our worker is now receiving a reference to a closure
         //Remember to include the semicolon inside the closure...
         //We need to loop the closure forever asking the receiver end for a job.
         //Then we run the job once we get it.
-        let thread = thread::spawn(move || {
+        //thread::spawn would panic if the OS won't give us a thread, the Builder lets us fail instead.
+        let mut builder = thread::Builder::new().name(format!("{}{}", inner.name_prefix, id));
+        if let Some(stack_size) = inner.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let thread = builder.spawn(move || {
+            WORKER_ID.with(|worker| worker.set(Some(id)));
             loop{
-                let message = receiver.lock().unwrap().recv().unwrap();
+                inner.idle.fetch_add(1, Ordering::SeqCst);
+                let message = inner.queue.pop(id, inner.keep_alive);
+                inner.idle.fetch_sub(1, Ordering::SeqCst);
+
+                let message = match message {
+                    Some(message) => message,
+                    //Nothing to do for a whole keep-alive period.
+                    None => {
+                        if inner.retire() {
+                            println!("Worker {} was idle for too long; retiring.", id);
+                            break;
+                        }
+                        continue;
+                    }
+                };
 
                 match message {
-                    Message::NewJob(job) => {
+                    Message::NewJob(job, queued_at) => {
                         println!("Worker {} got a job; executing.", id);
+                        let started = Instant::now();
+                        inner.metrics.started(started.duration_since(queued_at));
                         //Rust currently doesn't realize that it can use self: Box<Self>  to unpack the closure
                         //and move it outside of Box<T> so we can actually use it.
                         //See the above FnBox trait for how to get around this.
                         // (*job)();
-                        job.call_box();
+                        //A panic inside of the job would otherwise unwind right out of this loop and
+                        //kill the worker for good, so we catch it here and move on to the next job.
+                        //The job is thrown away either way so it's fine that it can't be unwind safe.
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| job.call_box())) {
+                            inner.panics.fetch_add(1, Ordering::SeqCst);
+                            println!("Worker {} caught a panic in its job: {}", id, panic_message(&*payload));
+                        }
+                        inner.metrics.finished(started.elapsed());
                     },
                     Message::Terminate => {
                         println!("Worker {} was told to terminate.", id);
@@ -147,11 +724,533 @@ impl Worker {
                     }
                 }
             }
-        });
+        })?;
 
-        Worker {
+        Ok(Worker {
             id,
             thread: Some(thread),
+        })
+    }
+}
+
+//panic! hands us its message as either a &str or a String.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    //Fills a single worker pool with a job that waits until the returned sender is used.
+    fn busy_pool(capacity: usize, policy: QueuePolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::with_queue(1, capacity, policy);
+        let (release, wait) = mpsc::channel();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        }).unwrap();
+        running.recv().unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn reject_hands_the_job_back() {
+        let (pool, release) = busy_pool(1, QueuePolicy::Reject);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        let r = Arc::clone(&ran);
+        assert!(pool.execute(move || r.lock().unwrap().push(1)).is_ok());
+        let r = Arc::clone(&ran);
+        let job = pool.execute(move || r.lock().unwrap().push(2)).unwrap_err().into_job();
+        assert_eq!(pool.queued(), 1);
+
+        //The job we got back still works.
+        job();
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(*ran.lock().unwrap(), vec![2, 1]);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let (pool, release) = busy_pool(2, QueuePolicy::DropOldest);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..4 {
+            let r = Arc::clone(&ran);
+            pool.execute(move || r.lock().unwrap().push(i)).unwrap();
+        }
+        assert_eq!(pool.queued(), 2);
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(*ran.lock().unwrap(), vec![2, 3]);
+    }
+
+    //Queues a job per priority while the only worker is busy and returns the order they ran in.
+    fn run_order(pool: ThreadPool, release: mpsc::Sender<()>, jobs: &[(Priority, &'static str)]) -> Vec<&'static str> {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for &(priority, name) in jobs {
+            let r = Arc::clone(&ran);
+            pool.execute_priority(priority, move || r.lock().unwrap().push(name)).unwrap();
+        }
+        release.send(()).unwrap();
+        drop(pool);
+        let ran = ran.lock().unwrap();
+        ran.clone()
+    }
+
+    const MIXED: [(Priority, &str); 6] = [
+        (Priority::Low, "low 1"),
+        (Priority::Normal, "normal 1"),
+        (Priority::High, "high 1"),
+        (Priority::Low, "low 2"),
+        (Priority::High, "high 2"),
+        (Priority::Normal, "normal 2"),
+    ];
+
+    #[test]
+    fn urgent_jobs_go_first() {
+        let expected = vec!["high 1", "high 2", "normal 1", "normal 2", "low 1", "low 2"];
+        let (pool, release) = busy_pool(10, QueuePolicy::Block);
+        assert_eq!(run_order(pool, release, &MIXED), expected);
+
+        //With queues per worker a High job is stolen from the other queue before a Normal one
+        //in our own queue is started.
+        for scheduler in [Scheduler::Shared, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder().size(2).scheduler(scheduler).build().unwrap();
+            let (release, wait) = mpsc::channel::<()>();
+            let wait = Arc::new(Mutex::new(wait));
+            let (started, running) = mpsc::channel();
+            //Both workers wait, then one of them is let go to do all of the queued work.
+            for _ in 0..2 {
+                let (wait, started) = (Arc::clone(&wait), started.clone());
+                pool.execute(move || {
+                    started.send(()).unwrap();
+                    wait.lock().unwrap().recv().unwrap();
+                })
+                .unwrap();
+                running.recv().unwrap();
+            }
+            let ran = Arc::new(Mutex::new(Vec::new()));
+            for &(priority, name) in &MIXED {
+                let r = Arc::clone(&ran);
+                pool.execute_priority(priority, move || r.lock().unwrap().push(name)).unwrap();
+            }
+            release.send(()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(*ran.lock().unwrap(), expected, "{:?}", scheduler);
+            release.send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn starved_jobs_get_their_turn() {
+        let pool = ThreadPool::builder().size(1).starvation_limit(Duration::from_millis(50)).build().unwrap();
+        let (release, wait) = mpsc::channel();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        }).unwrap();
+        running.recv().unwrap();
+
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let r = Arc::clone(&ran);
+        pool.execute_priority(Priority::Low, move || r.lock().unwrap().push("low")).unwrap();
+        //Long enough for the Low job to count as starved.
+        thread::sleep(Duration::from_millis(80));
+        for name in ["high 1", "high 2"] {
+            let r = Arc::clone(&ran);
+            pool.execute_priority(Priority::High, move || r.lock().unwrap().push(name)).unwrap();
+        }
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(*ran.lock().unwrap(), vec!["low", "high 1", "high 2"]);
+    }
+
+    #[test]
+    fn drop_oldest_drops_the_least_urgent() {
+        let (pool, release) = busy_pool(2, QueuePolicy::DropOldest);
+        let jobs = [(Priority::High, "high"), (Priority::Low, "low"), (Priority::Normal, "normal")];
+        assert_eq!(run_order(pool, release, &jobs), vec!["high", "normal"]);
+    }
+
+    #[test]
+    fn block_waits_for_room_but_try_execute_does_not() {
+        let (pool, release) = busy_pool(1, QueuePolicy::Block);
+        pool.execute(|| {}).unwrap();
+        assert!(pool.try_execute(|| {}).is_err());
+
+        let pool = Arc::new(pool);
+        let (done, finished) = mpsc::channel();
+        let p = Arc::clone(&pool);
+        let blocked = thread::spawn(move || {
+            p.execute(|| {}).unwrap();
+            done.send(()).unwrap();
+        });
+        assert!(finished.recv_timeout(Duration::from_millis(100)).is_err());
+
+        release.send(()).unwrap();
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        blocked.join().unwrap();
+    }
+
+    #[test]
+    fn panicking_jobs_dont_kill_workers() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+
+        for i in 0..3 {
+            pool.execute(move || panic!("job {} blew up", i)).unwrap();
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
         }
+
+        let got: Vec<i32> = (0..3).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(got, vec![0, 1, 2]);
+        assert_eq!(pool.panic_count(), 3);
+    }
+
+    #[test]
+    fn panic_messages_are_readable() {
+        let payload = panic::catch_unwind(|| panic!("plain")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "plain");
+        let payload = panic::catch_unwind(|| panic!("formatted {}", 1)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "formatted 1");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn spawn_returns_values_and_panics() {
+        let pool = ThreadPool::new(2);
+
+        let answer = pool.spawn(|| 6 * 7).unwrap();
+        assert_eq!(answer.join().unwrap(), 42);
+
+        let boom = pool.spawn(|| -> u8 { panic!("boom") }).unwrap();
+        match boom.join() {
+            Err(JoinError::Panicked(payload)) => assert_eq!(panic_message(&*payload), "boom"),
+            other => panic!("expected a panic, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn try_join_and_join_timeout_dont_wait_forever() {
+        let (pool, release) = busy_pool(4, QueuePolicy::Block);
+        let handle = pool.spawn(|| "done").unwrap();
+
+        assert!(matches!(handle.try_join(), Err(JoinError::NotReady)));
+        assert!(matches!(handle.join_timeout(Duration::from_millis(50)), Err(JoinError::NotReady)));
+
+        release.send(()).unwrap();
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)).unwrap(), "done");
+    }
+
+    #[test]
+    fn dropped_jobs_are_cancelled() {
+        let (pool, release) = busy_pool(1, QueuePolicy::DropOldest);
+        let first = pool.spawn(|| 1).unwrap();
+        let second = pool.spawn(|| 2).unwrap();
+        release.send(()).unwrap();
+
+        assert!(matches!(first.join(), Err(JoinError::Cancelled)));
+        assert_eq!(second.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn rejected_spawns_hand_the_closure_back() {
+        let (pool, _release) = busy_pool(1, QueuePolicy::Reject);
+        pool.execute(|| {}).unwrap();
+        let f = pool.spawn(|| 5).unwrap_err().into_job();
+        assert_eq!(f(), 5);
+    }
+
+    #[test]
+    fn idle_workers_steal_from_busy_ones() {
+        let pool = ThreadPool::with_scheduler(2, Scheduler::WorkStealing);
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        }).unwrap();
+        running.recv().unwrap();
+
+        //Half of these are dealt to the worker that's stuck, so they only finish if the other one steals them.
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..10 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+        let mut got: Vec<i32> = (0..10).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        got.sort();
+        assert_eq!(got, (0..10).collect::<Vec<_>>());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn work_stealing_respects_the_queue_policy() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(2)
+            .queue_policy(QueuePolicy::Reject)
+            .scheduler(Scheduler::WorkStealing)
+            .build()
+            .unwrap();
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        }).unwrap();
+        running.recv().unwrap();
+
+        let first = pool.spawn(|| 1).unwrap();
+        let second = pool.spawn(|| 2).unwrap();
+        assert!(pool.execute(|| {}).is_err());
+        assert_eq!(pool.queued(), 2);
+
+        release.send(()).unwrap();
+        assert_eq!(first.join().unwrap() + second.join().unwrap(), 3);
+    }
+
+    //Waits for the pool to reach `size` workers, giving up after a few seconds.
+    fn wait_for_size(pool: &ThreadPool, size: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.size() != size && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.size(), size);
+    }
+
+    #[test]
+    fn dynamic_pools_grow_when_busy_and_shrink_when_idle() {
+        let pool = ThreadPool::dynamic(1, 3, Duration::from_millis(100));
+        assert_eq!(pool.size(), 1);
+
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        let (started, running) = mpsc::channel();
+        for _ in 0..5 {
+            let wait = Arc::clone(&wait);
+            let started = started.clone();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                wait.lock().unwrap().recv().unwrap();
+            }).unwrap();
+        }
+        //Only three of them fit, the rest wait in the queue.
+        for _ in 0..3 {
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.size(), 3);
+        assert!(running.recv_timeout(Duration::from_millis(50)).is_err());
+
+        for _ in 0..5 {
+            release.send(()).unwrap();
+        }
+        wait_for_size(&pool, 1);
+
+        //The worker that's left still takes jobs.
+        assert_eq!(pool.spawn(|| 1).unwrap().join().unwrap(), 1);
+    }
+
+    #[test]
+    fn resize_adds_and_removes_workers() {
+        let pool = ThreadPool::new(2);
+        pool.resize(4, 4).unwrap();
+        assert_eq!(pool.size(), 4);
+
+        //All four have to be running at once for any of them to finish.
+        let barrier = Arc::new(::std::sync::Barrier::new(4));
+        let handles: Vec<_> = (0..4).map(|_| {
+            let barrier = Arc::clone(&barrier);
+            pool.spawn(move || { barrier.wait(); }).unwrap()
+        }).collect();
+        for handle in handles {
+            handle.join_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        assert!(matches!(pool.resize(2, 1), Err(PoolCreationError::MinAboveMax { .. })));
+        assert!(matches!(pool.resize(0, 0), Err(PoolCreationError::ZeroSize)));
+        assert_eq!(pool.size(), 4);
+        pool.resize(1, 1).unwrap();
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.spawn(|| 2).unwrap().join().unwrap(), 2);
+        //Dropping still stops exactly the workers that are left.
+        drop(pool);
+    }
+
+    #[test]
+    fn dropping_a_shrunk_pool_runs_every_queued_job() {
+        let pool = ThreadPool::dynamic(0, 2, Duration::from_millis(20));
+        assert_eq!(pool.size(), 0);
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..4 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+        wait_for_size(&pool, 0);
+        pool.execute(move || sender.send(4).unwrap()).unwrap();
+        drop(pool);
+
+        let mut got: Vec<i32> = receiver.iter().collect();
+        got.sort();
+        assert_eq!(got, (0..5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn closed_pools_hand_jobs_back() {
+        let pool = ThreadPool::new(1);
+        let queued = pool.spawn(|| 1).unwrap();
+        pool.close();
+        assert!(pool.is_closed());
+
+        match pool.execute(|| {}) {
+            Err(ExecuteError::ShuttingDown(_)) => {}
+            other => panic!("expected ShuttingDown, got {:?}", other),
+        }
+        let f = pool.spawn(|| 2).unwrap_err().into_job();
+        assert_eq!(f(), 2);
+        //Jobs from before the pool was closed still run.
+        assert_eq!(queued.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn delayed_jobs_wait_and_can_be_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let started = Instant::now();
+
+        //Scheduled out of order on purpose, they run in the order they're due.
+        for &(name, ms) in &[("late", 150), ("early", 50), ("cancelled", 100)] {
+            let sender = sender.clone();
+            let token = pool.execute_after(Duration::from_millis(ms), move || sender.send(name).unwrap()).unwrap();
+            if name == "cancelled" {
+                token.cancel();
+            }
+        }
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "early");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "late");
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        //Jobs that aren't due yet are thrown away with the pool.
+        let sender = sender.clone();
+        pool.execute_after(Duration::from_secs(60), move || sender.send("never").unwrap()).unwrap();
+        let started = Instant::now();
+        drop(pool);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn repeating_jobs_run_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let mut count = 0;
+        let token = pool
+            .execute_every(Duration::from_millis(20), move || {
+                count += 1;
+                sender.send(count).unwrap();
+            })
+            .unwrap();
+        let got: Vec<i32> = (0..3).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(got, vec![1, 2, 3]);
+
+        token.cancel();
+        //A run that was already queued may still come through, but nothing after that.
+        thread::sleep(Duration::from_millis(50));
+        while receiver.try_recv().is_ok() {}
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        pool.close();
+        assert!(pool.execute_every(Duration::from_millis(20), || {}).is_err());
+        match pool.execute_after(Duration::from_millis(20), || {}) {
+            Err(ExecuteError::ShuttingDown(_)) => {}
+            other => panic!("expected ShuttingDown, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repeating_jobs_survive_a_full_queue() {
+        let (pool, release) = busy_pool(1, QueuePolicy::Reject);
+        //Takes the only spot in the queue, so the repeating job's runs are turned away.
+        pool.execute(|| {}).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute_every(Duration::from_millis(10), move || {
+            let _ = sender.send(());
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err());
+
+        release.send(()).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn slow_repeating_jobs_dont_overlap() {
+        let pool = ThreadPool::new(4);
+        let running = Arc::new(AtomicUsize::new(0));
+        let (most, runs) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (r, m, n) = (Arc::clone(&running), Arc::clone(&most), Arc::clone(&runs));
+        //Takes five intervals every time it runs.
+        pool.execute_every(Duration::from_millis(10), move || {
+            let now = r.fetch_add(1, Ordering::SeqCst) + 1;
+            m.fetch_max(now, Ordering::SeqCst);
+            n.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            r.fetch_sub(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(300));
+        drop(pool);
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+        assert!(runs.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[test]
+    fn metrics_count_jobs_and_latencies() {
+        let (pool, release) = busy_pool(4, QueuePolicy::Block);
+        pool.execute(|| {}).unwrap();
+        let monitor = pool.monitor();
+        let busy = monitor.metrics().unwrap();
+        assert_eq!((busy.workers, busy.active, busy.queued), (1, 1, 1));
+
+        release.send(()).unwrap();
+        pool.spawn(|| -> () { panic!("counted") }).unwrap().join().unwrap_err();
+        //The handle gets its result just before the worker records the job as finished.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.metrics().completed < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let done = pool.metrics();
+        assert_eq!((done.active, done.queued, done.completed, done.panicked), (0, 0, 3, 1));
+        assert_eq!(done.run_time.count, 3);
+        assert_eq!(done.queue_wait.count, 3);
+
+        drop(pool);
+        assert!(monitor.metrics().is_none());
+    }
+
+    #[test]
+    fn jobs_know_their_worker() {
+        assert_eq!(current_worker(), None);
+        let pool = ThreadPool::new(1);
+        assert_eq!(pool.spawn(current_worker).unwrap().join().unwrap(), Some(0));
+    }
+}
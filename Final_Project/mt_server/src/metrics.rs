@@ -0,0 +1,228 @@
+//The only way to see what the pool was doing used to be the println! lines the workers
+//print. Now the workers also keep a few counters and two latency histograms up to date as
+//they go: how long each job sat in the queue and how long it ran. `ThreadPool::metrics()`
+//copies all of that into a PoolMetrics snapshot, which can render itself in the Prometheus
+//text format for a /metrics page.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+//Upper bounds of the histogram buckets in microseconds, 100µs up to 10s. Anything slower
+//only ends up in the +Inf bucket.
+const BOUNDS: [u64; 11] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000, 10_000_000,
+];
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+//Counts of durations per bucket. Every field is its own atomic so recording never takes a
+//lock, which means a snapshot taken while jobs finish can be off by a job or two.
+struct Histogram {
+    //One more than BOUNDS for the +Inf bucket. Not cumulative, unlike the snapshot.
+    buckets: [AtomicU64; 12],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, d: Duration) {
+        let us = micros(d);
+        let i = BOUNDS.iter().position(|&bound| us <= bound).unwrap_or(BOUNDS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(us, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let buckets = BOUNDS.iter().zip(self.buckets.iter()).map(|(&bound, count)| {
+            total += count.load(Ordering::Relaxed);
+            (Duration::from_micros(bound), total)
+        }).collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The state of one of the pool's latency histograms at some point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Each bucket's upper bound along with how many jobs took at most that long,
+    /// so the counts only ever go up. Jobs slower than the last bound are only in `count`.
+    pub buckets: Vec<(Duration, u64)>,
+    /// How many jobs have been recorded.
+    pub count: u64,
+    /// All of the recorded durations added together.
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    /// The average duration, or `None` if nothing has been recorded yet.
+    pub fn mean(&self) -> Option<Duration> {
+        micros(self.sum).checked_div(self.count).map(Duration::from_micros)
+    }
+
+    fn write_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for &(bound, count) in &self.buckets {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound.as_secs_f64(), count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+/// What the pool's workers record as they go.
+pub(crate) struct Recorder {
+    active: AtomicUsize,
+    completed: AtomicU64,
+    queue_wait: Histogram,
+    run_time: Histogram,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Recorder {
+        Recorder {
+            active: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            queue_wait: Histogram::new(),
+            run_time: Histogram::new(),
+        }
+    }
+
+    /// A worker took a job off of the queue after it waited there for `waited`.
+    pub(crate) fn started(&self, waited: Duration) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.queue_wait.record(waited);
+    }
+
+    /// The job finished after running for `ran`, whether it panicked or not.
+    pub(crate) fn finished(&self, ran: Duration) {
+        self.run_time.record(ran);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, workers: usize, queued: usize, panicked: usize) -> PoolMetrics {
+        PoolMetrics {
+            workers,
+            active: self.active.load(Ordering::Relaxed),
+            queued,
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: panicked as u64,
+            queue_wait: self.queue_wait.snapshot(),
+            run_time: self.run_time.snapshot(),
+        }
+    }
+}
+
+/// A snapshot of what a `ThreadPool` is up to, from `ThreadPool::metrics()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Worker threads in the pool.
+    pub workers: usize,
+    /// Workers that are running a job right now.
+    pub active: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Jobs that have finished running, including the ones that panicked.
+    pub completed: u64,
+    /// Jobs that panicked.
+    pub panicked: u64,
+    /// How long jobs waited in the queue before a worker picked them up.
+    pub queue_wait: HistogramSnapshot,
+    /// How long jobs took to run.
+    pub run_time: HistogramSnapshot,
+}
+
+impl PoolMetrics {
+    /// Render the snapshot in the Prometheus text exposition format. Every metric name
+    /// starts with `prefix`, for example `mt_server_pool`.
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut out = String::new();
+        let gauges = [
+            ("workers", "Worker threads in the pool.", self.workers as u64),
+            ("active_workers", "Workers running a job.", self.active as u64),
+            ("queued_jobs", "Jobs waiting for a worker.", self.queued as u64),
+        ];
+        for &(name, help, value) in &gauges {
+            let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} gauge", prefix, name);
+            let _ = writeln!(out, "{}_{} {}", prefix, name, value);
+        }
+        let counters = [
+            ("jobs_completed_total", "Jobs that finished running, panicked or not.", self.completed),
+            ("jobs_panicked_total", "Jobs that panicked.", self.panicked),
+        ];
+        for &(name, help, value) in &counters {
+            let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} counter", prefix, name);
+            let _ = writeln!(out, "{}_{} {}", prefix, name, value);
+        }
+        self.queue_wait.write_prometheus(
+            &mut out,
+            &format!("{}_job_queue_seconds", prefix),
+            "How long jobs waited for a worker.",
+        );
+        self.run_time.write_prometheus(
+            &mut out,
+            &format!("{}_job_run_seconds", prefix),
+            "How long jobs took to run.",
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let h = Histogram::new();
+        h.record(Duration::from_micros(50));
+        h.record(Duration::from_millis(3));
+        h.record(Duration::from_secs(60));
+
+        let snap = h.snapshot();
+        assert_eq!(snap.count, 3);
+        assert_eq!(snap.buckets[0], (Duration::from_micros(100), 1));
+        assert_eq!(snap.buckets[3], (Duration::from_millis(5), 2));
+        //The minute long one is only in the count.
+        assert_eq!(snap.buckets.last().unwrap().1, 2);
+        assert_eq!(snap.mean(), Some(Duration::from_micros((50 + 3_000 + 60_000_000) / 3)));
+    }
+
+    #[test]
+    fn renders_prometheus_text() {
+        let recorder = Recorder::new();
+        recorder.started(Duration::from_millis(2));
+        recorder.finished(Duration::from_millis(20));
+        let text = recorder.snapshot(4, 1, 0).to_prometheus("pool");
+
+        assert!(text.contains("# TYPE pool_workers gauge\npool_workers 4\n"));
+        assert!(text.contains("pool_queued_jobs 1\n"));
+        assert!(text.contains("pool_active_workers 0\n"));
+        assert!(text.contains("# TYPE pool_jobs_completed_total counter\npool_jobs_completed_total 1\n"));
+        assert!(text.contains("pool_job_queue_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("pool_job_queue_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("pool_job_run_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("pool_job_run_seconds_sum 0.02\n"));
+        assert!(text.ends_with("pool_job_run_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,442 @@
+//Things like request ids, security headers or timeouts apply to every page, but the only
+//place a request and its response meet is inside of a handler, so they would have had to
+//be copied into every one of them. A Middleware wraps the handler instead. A Chain is a
+//handler that runs its middlewares in the order they were added, each one deciding whether
+//to pass the request on to the next one (with `Next::run`) or to answer it right away, and
+//getting to touch the response on its way back out.
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use request::Request;
+use response::Response;
+use router::Handler;
+use {QueuePolicy, ThreadPool};
+
+/// Something that runs around a handler.
+///
+/// Most middlewares only need `before` and `after`. Ones that need the request and the
+/// response at the same time, or want to control how the rest of the chain runs,
+/// implement `call` instead. Closures taking a `Request` and a `Next` are middlewares too.
+pub trait Middleware: Send + Sync + 'static {
+    /// Runs before the request goes further down the chain. Returning a response
+    /// answers the request right away, and nothing after this middleware sees it.
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    /// Runs on every response that comes back through this middleware, including the
+    /// one `before` returned.
+    fn after(&self, _response: &mut Response) {}
+
+    fn call(&self, mut request: Request, next: Next) -> Response {
+        let mut response = match self.before(&mut request) {
+            Some(response) => response,
+            None => next.run(request),
+        };
+        self.after(&mut response);
+        response
+    }
+}
+
+impl<F> Middleware for F
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static
+{
+    fn call(&self, request: Request, next: Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the chain after the middleware that's running, ending with the handler.
+///
+/// It owns everything it needs so it can be sent to another thread.
+#[derive(Clone)]
+pub struct Next {
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+    index: usize,
+    handler: Arc<dyn Handler>,
+}
+
+impl Next {
+    /// Pass the request on and get the response back.
+    pub fn run(self, request: Request) -> Response {
+        match self.middlewares.get(self.index).cloned() {
+            Some(middleware) => {
+                let next = Next { index: self.index + 1, ..self };
+                middleware.call(request, next)
+            }
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+/// A handler wrapped in middlewares. The first one added is the outermost, so it sees
+/// the request first and the response last.
+#[derive(Clone)]
+pub struct Chain {
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Chain {
+    pub fn new<H: Handler>(handler: H) -> Chain {
+        Chain {
+            middlewares: Arc::new(Vec::new()),
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// Add a middleware inside of the ones that are already there.
+    pub fn with<M: Middleware>(mut self, middleware: M) -> Chain {
+        Arc::make_mut(&mut self.middlewares).push(Arc::new(middleware));
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: Request) -> Response {
+        let next = Next {
+            middlewares: Arc::clone(&self.middlewares),
+            index: 0,
+            handler: Arc::clone(&self.handler),
+        };
+        next.run(request)
+    }
+}
+
+/// Gives every request an id in the `X-Request-Id` header, and sends the same header
+/// back with the response so the two can be matched up in logs.
+///
+/// An id the client sent is kept as long as it looks sane, otherwise a new one is made.
+#[derive(Debug)]
+pub struct RequestId {
+    header: String,
+    //The time the middleware was created, so ids from different runs don't collide.
+    prefix: String,
+    next: AtomicUsize,
+}
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        RequestId::with_header("X-Request-Id")
+    }
+
+    /// Use a different header than `X-Request-Id`.
+    pub fn with_header<S: Into<String>>(header: S) -> RequestId {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        RequestId {
+            header: header.into(),
+            prefix: format!("{:x}", started.as_micros()),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+//Ids end up in logs and response headers, so we only take short ones without odd characters.
+fn acceptable_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+impl Middleware for RequestId {
+    fn call(&self, mut request: Request, next: Next) -> Response {
+        let id = match request.headers.get(&self.header) {
+            Some(id) if acceptable_id(id) => id.to_string(),
+            _ => format!("{}-{}", self.prefix, self.next.fetch_add(1, Ordering::Relaxed)),
+        };
+        request.headers.insert(self.header.clone(), id.clone());
+        let mut response = next.run(request);
+        response.headers.insert(self.header.clone(), id);
+        response
+    }
+}
+
+/// Adds headers that make browsers a little more careful with our pages. A header the
+/// handler set itself is left alone.
+///
+/// The defaults are `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY`,
+/// `Referrer-Policy: no-referrer` and `Content-Security-Policy: default-src 'self'`.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders::default()
+    }
+
+    /// Add a header or change the value of one of the defaults.
+    pub fn set<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> SecurityHeaders {
+        let name = name.into();
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(&name));
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Don't add the given header after all.
+    pub fn without(mut self, name: &str) -> SecurityHeaders {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        let defaults = [
+            ("X-Content-Type-Options", "nosniff"),
+            ("X-Frame-Options", "DENY"),
+            ("Referrer-Policy", "no-referrer"),
+            ("Content-Security-Policy", "default-src 'self'"),
+        ];
+        SecurityHeaders {
+            headers: defaults.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect(),
+        }
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn after(&self, response: &mut Response) {
+        for (name, value) in &self.headers {
+            if !response.headers.contains(name) {
+                response.headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Answers with 504 Gateway Timeout when the rest of the chain takes longer than the
+/// limit.
+///
+/// A thread can't be stopped from the outside, so the rest of the chain runs on a small
+/// pool of helper threads and is left to finish in the background when it's too slow. Its
+/// response is thrown away. A handler that hangs keeps its helper busy, so once every
+/// helper is taken requests are answered with 503 Service Unavailable right away instead
+/// of piling up more threads. This is meant for routes that might hang rather than for
+/// every cheap page.
+#[derive(Clone)]
+pub struct Timeout {
+    limit: Duration,
+    helpers: Arc<Helpers>,
+}
+
+//How many requests a Timeout runs at once unless it's told otherwise.
+const DEFAULT_HELPERS: usize = 16;
+
+impl Timeout {
+    pub fn new(limit: Duration) -> Timeout {
+        Timeout::with_helpers(limit, DEFAULT_HELPERS)
+    }
+
+    /// How many requests can run at once, counting ones that already timed out but haven't
+    /// finished yet. 16 by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `helpers` is zero.
+    pub fn helpers(self, helpers: usize) -> Timeout {
+        Timeout::with_helpers(self.limit, helpers)
+    }
+
+    fn with_helpers(limit: Duration, helpers: usize) -> Timeout {
+        //Helpers are only started when there's something for them to do, and they go away
+        //again when it's quiet.
+        let pool = ThreadPool::builder()
+            .size(0)
+            .max_size(helpers)
+            .keep_alive(Duration::from_secs(30))
+            .queue_capacity(1)
+            .queue_policy(QueuePolicy::Reject)
+            .name_prefix("timeout-helper-")
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e));
+        Timeout { limit, helpers: Arc::new(Helpers(Some(pool))) }
+    }
+}
+
+impl fmt::Debug for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Timeout").field("limit", &self.limit).finish()
+    }
+}
+
+impl Middleware for Timeout {
+    fn call(&self, request: Request, next: Next) -> Response {
+        let (sender, receiver) = mpsc::channel();
+        //The pool is only taken out when the Timeout is dropped.
+        let pool = self.helpers.0.as_ref().unwrap();
+        let job = pool.execute(move || {
+            //Nobody is listening anymore if we already gave up.
+            let _ = sender.send(next.run(request));
+        });
+        if job.is_err() {
+            println!("Every timeout helper is busy; turning a request away.");
+            return Response::new(503).with_header("Retry-After", "1").with_body("Service Unavailable");
+        }
+        match receiver.recv_timeout(self.limit) {
+            Ok(response) => response,
+            Err(_) => Response::new(504)
+                .with_header("Connection", "close")
+                .with_body("Request timed out"),
+        }
+    }
+}
+
+//Dropping a ThreadPool waits for its jobs, which could be a handler that never returns.
+struct Helpers(Option<ThreadPool>);
+
+impl Drop for Helpers {
+    fn drop(&mut self) {
+        if let Some(pool) = self.0.take() {
+            pool.shutdown_timeout(Duration::from_secs(0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use request::Limits;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\nHost: test\r\n{}\r\n", headers);
+        Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0
+    }
+
+    //Notes down when it sees the request and the response.
+    struct Trace {
+        name: &'static str,
+        seen: Arc<Mutex<Vec<String>>>,
+        answer: bool,
+    }
+
+    impl Middleware for Trace {
+        fn before(&self, _request: &mut Request) -> Option<Response> {
+            self.seen.lock().unwrap().push(format!("{} before", self.name));
+            if self.answer {
+                Some(Response::new(403))
+            } else {
+                None
+            }
+        }
+
+        fn after(&self, _response: &mut Response) {
+            self.seen.lock().unwrap().push(format!("{} after", self.name));
+        }
+    }
+
+    #[test]
+    fn runs_in_order_and_can_short_circuit() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let trace = |name, answer| Trace { name, seen: Arc::clone(&seen), answer };
+        let handled = Arc::clone(&seen);
+        let chain = Chain::new(move |_: Request| {
+            handled.lock().unwrap().push("handler".to_string());
+            Response::ok()
+        })
+            .with(trace("outer", false))
+            .with(trace("inner", false));
+        assert_eq!(chain.handle(request("")).status, 200);
+        assert_eq!(*seen.lock().unwrap(), ["outer before", "inner before", "handler", "inner after", "outer after"]);
+
+        seen.lock().unwrap().clear();
+        let chain = Chain::new(|_: Request| Response::ok())
+            .with(trace("outer", false))
+            .with(trace("guard", true))
+            .with(trace("inner", false));
+        assert_eq!(chain.handle(request("")).status, 403);
+        assert_eq!(*seen.lock().unwrap(), ["outer before", "guard before", "guard after", "outer after"]);
+    }
+
+    #[test]
+    fn closures_are_middlewares() {
+        let chain = Chain::new(|req: Request| Response::ok().with_body(req.headers.get("X-User").unwrap_or("nobody")))
+            .with(|mut req: Request, next: Next| {
+                req.headers.insert("X-User", "admin");
+                next.run(req).with_header("X-Seen", "yes")
+            });
+        let response = chain.handle(request(""));
+        assert_eq!(response.body, b"admin");
+        assert_eq!(response.headers.get("X-Seen"), Some("yes"));
+    }
+
+    #[test]
+    fn request_ids_are_kept_or_made_up() {
+        let chain = Chain::new(|req: Request| Response::ok().with_body(req.headers.get("X-Request-Id").unwrap()))
+            .with(RequestId::new());
+
+        let response = chain.handle(request("X-Request-Id: abc-123\r\n"));
+        assert_eq!(response.body, b"abc-123");
+        assert_eq!(response.headers.get("X-Request-Id"), Some("abc-123"));
+
+        let first = chain.handle(request("X-Request-Id: has spaces\r\n"));
+        let second = chain.handle(request(""));
+        let first = first.headers.get("X-Request-Id").unwrap().to_string();
+        assert_ne!(first, "has spaces");
+        assert_ne!(Some(&first[..]), second.headers.get("X-Request-Id"));
+    }
+
+    #[test]
+    fn security_headers_dont_override_the_handler() {
+        let chain = Chain::new(|_: Request| Response::ok().with_header("X-Frame-Options", "SAMEORIGIN"))
+            .with(SecurityHeaders::new().without("Content-Security-Policy").set("X-Extra", "1"));
+        let response = chain.handle(request(""));
+        assert_eq!(response.headers.get("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(response.headers.get("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(response.headers.get("X-Extra"), Some("1"));
+        assert!(!response.headers.contains("Content-Security-Policy"));
+    }
+
+    #[test]
+    fn slow_requests_time_out() {
+        let chain = Chain::new(|req: Request| {
+            if req.headers.contains("X-Slow") {
+                thread::sleep(Duration::from_millis(500));
+            }
+            Response::ok()
+        })
+            .with(Timeout::new(Duration::from_millis(50)));
+        assert_eq!(chain.handle(request("")).status, 200);
+        let response = chain.handle(request("X-Slow: 1\r\n"));
+        assert_eq!(response.status, 504);
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn hung_requests_dont_pile_up_threads() {
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Mutex::new(wait);
+        let chain = Chain::new(move |req: Request| {
+            if req.headers.contains("X-Hang") {
+                let _ = wait.lock().unwrap().recv();
+            }
+            Response::ok()
+        })
+            .with(Timeout::new(Duration::from_millis(50)).helpers(1));
+        let chain = Arc::new(chain);
+
+        //The first one keeps the only helper and one more fits in its queue.
+        assert_eq!(chain.handle(request("X-Hang: 1\r\n")).status, 504);
+        let waiting = Arc::clone(&chain);
+        let waiting = thread::spawn(move || waiting.handle(request("")).status);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(chain.handle(request("")).status, 503);
+        assert_eq!(waiting.join().unwrap(), 504);
+
+        //Once the helper is done with both of them it's free again.
+        release.send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(chain.handle(request("")).status, 200);
+    }
+}
@@ -0,0 +1,61 @@
+//Browsers decide what to do with a response based on its Content-Type, so when we
+//serve a file we guess the type from its extension like most servers do.
+use std::path::Path;
+
+/// The Content-Type to use for a file extension (without the dot).
+/// Unknown extensions get `application/octet-stream`.
+pub fn from_extension(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The Content-Type to use for a file based on its extension.
+pub fn from_path<P: AsRef<Path>>(path: P) -> &'static str {
+    match path.as_ref().extension().and_then(|e| e.to_str()) {
+        Some(ext) => from_extension(ext),
+        None => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_from_extension() {
+        assert_eq!(from_path("public/index.HTML"), "text/html; charset=utf-8");
+        assert_eq!(from_path("logo.png"), "image/png");
+        assert_eq!(from_path("archive.tar.gz"), "application/gzip");
+        assert_eq!(from_path("Makefile"), "application/octet-stream");
+        assert_eq!(from_path("data.unknown"), "application/octet-stream");
+    }
+}
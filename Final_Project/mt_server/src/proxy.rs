@@ -0,0 +1,375 @@
+//A Proxy is a handler that passes requests on to a backend process (an upstream) and its
+//responses back to the client, so mt_server can sit in front of an application server and
+//serve the static files itself. Every request opens a connection of its own to the next
+//upstream in turn. If that upstream can't be reached the others are tried before giving up,
+//which is safe because nothing has been sent yet.
+//
+//The upstream is told who it's really talking to with X-Forwarded-For and X-Forwarded-Host,
+//and gets its own address as the Host. Hop-by-hop headers like Connection only mean
+//something on one connection, so they're dropped in both directions.
+//
+//The request body has already been read whole by the request parser, so it goes up in one
+//piece. The response body is streamed: it becomes a Body::Reader over the upstream
+//connection and is copied to the client as it arrives, chunked or not. An upstream that
+//can't be reached or sends garbage gets the client a 502 Bad Gateway, and one that takes
+//too long a 504 Gateway Timeout.
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use headers::Headers;
+use request::{Method, Request};
+use response::{Body, Response, Status};
+use router::Handler;
+
+//Headers that are about a single connection rather than the message.
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+//Same limits as we hold clients to, a response head is no different from a request head.
+const MAX_HEAD: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// A handler that forwards requests to one or more upstream servers, round-robin.
+///
+/// Register it with `Router::any` so it gets every method. The request target is passed
+/// on as it is.
+#[derive(Debug)]
+pub struct Proxy {
+    upstreams: Vec<String>,
+    next: AtomicUsize,
+    connect_timeout: Duration,
+    timeout: Duration,
+}
+
+impl Proxy {
+    /// Forward to `upstream`, a `host:port`.
+    pub fn new<A: Into<String>>(upstream: A) -> Proxy {
+        Proxy {
+            upstreams: vec![upstream.into()],
+            next: AtomicUsize::new(0),
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Another upstream to take turns with.
+    pub fn upstream<A: Into<String>>(mut self, upstream: A) -> Proxy {
+        self.upstreams.push(upstream.into());
+        self
+    }
+
+    /// How long to wait for a connection to an upstream. 5 seconds by default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Proxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a read from or write to an upstream may take, which covers waiting for
+    /// the response to start. 30 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn upstreams(&self) -> &[String] {
+        &self.upstreams
+    }
+
+    //Connects to the next upstream whose turn it is, or any of the others if it's down.
+    fn connect(&self) -> io::Result<(TcpStream, &str)> {
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..self.upstreams.len() {
+            let upstream = &self.upstreams[(first + i) % self.upstreams.len()];
+            match connect(upstream, self.connect_timeout) {
+                Ok(stream) => return Ok((stream, upstream)),
+                Err(e) => {
+                    println!("Can't reach upstream {}: {}", upstream, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no upstreams")))
+    }
+
+    fn forward(&self, request: &Request) -> io::Result<Response> {
+        let (stream, upstream) = self.connect()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut writer = &stream;
+        writer.write_all(&upstream_request(request, upstream))?;
+        writer.write_all(&request.body)?;
+        writer.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = loop {
+            let (status, headers) = read_head(&mut reader)?;
+            //We never ask for an upgrade, so there's nothing to switch to.
+            if status == 101 {
+                return Err(bad_response("switched protocols without being asked to"));
+            }
+            //Interim responses like 100 Continue are for us, not the client.
+            if status >= 200 {
+                break (status, headers);
+            }
+        };
+
+        let mut response = Response::new(status);
+        for (name, value) in &headers {
+            if !is_hop_by_hop(&headers, name) && !name.eq_ignore_ascii_case("Content-Length") {
+                response.headers.append(name, value);
+            }
+        }
+        let length = headers.get("Content-Length").and_then(|len| len.trim().parse::<u64>().ok());
+        response.body = if request.method == Method::Head || status == 204 || status == 304 {
+            //No body follows, but the length still tells a HEAD request how big it'd be.
+            Body::Reader { reader: Box::new(io::empty()), len: length }
+        } else if headers.has_token("Transfer-Encoding", "chunked") {
+            Body::Reader { reader: Box::new(Chunked::new(reader)), len: None }
+        } else if let Some(len) = length {
+            Body::Reader { reader: Box::new(reader), len: Some(len) }
+        } else {
+            //Without either the body runs until the upstream closes the connection.
+            Body::Reader { reader: Box::new(reader), len: None }
+        };
+        Ok(response)
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: Request) -> Response {
+        match self.forward(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Proxying {} {} failed: {}", request.method, request.target, e);
+                let status = if is_timeout(&e) { Status::GatewayTimeout } else { Status::BadGateway };
+                Response::from_status(status).with_body(status.reason())
+            }
+        }
+    }
+}
+
+fn connect(upstream: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in upstream.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the address didn't resolve")))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    //Depending on the platform a read timeout shows up as either of these.
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+//Whether `name` only applies to the connection the headers came in on, including anything
+//the Connection header lists.
+fn is_hop_by_hop(headers: &Headers, name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+        || name.eq_ignore_ascii_case("Transfer-Encoding")
+        || headers.has_token("Connection", name)
+}
+
+//The request line and headers to send upstream. The connection is only used for this one
+//request so the upstream gets to say where the response ends by closing it if it likes.
+fn upstream_request(request: &Request, upstream: &str) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, request.target, upstream);
+    let mut forwarded_for = None;
+    for (name, value) in &request.headers {
+        let dropped = ["Host", "Content-Length", "Expect", "X-Forwarded-Host"];
+        if is_hop_by_hop(&request.headers, name) || dropped.iter().any(|d| d.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        //Earlier proxies' entries are kept and the client we got it from goes on the end.
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(forwarded_for.map_or(value.to_string(), |f: String| f + ", " + value));
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(peer) = request.peer {
+        let ip = peer.ip().to_string();
+        forwarded_for = Some(forwarded_for.map_or(ip.clone(), |f| f + ", " + &ip));
+    }
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    if let Some(host) = request.headers.get("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    let had_body = request.headers.contains("Content-Length") || request.headers.contains("Transfer-Encoding");
+    if had_body || !request.body.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+fn bad_response(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad response from upstream: {}", message))
+}
+
+//Reads a status line and headers.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Headers)> {
+    let mut read = 0;
+    let mut line = String::new();
+    let mut next_line = |line: &mut String| -> io::Result<()> {
+        line.clear();
+        let n = reader.by_ref().take((MAX_HEAD - read) as u64).read_line(line)?;
+        read += n;
+        if n == 0 || !line.ends_with('\n') {
+            return Err(if read >= MAX_HEAD { bad_response("head too long") } else { bad_response("ended early") });
+        }
+        let end = line.trim_end_matches(['\r', '\n']).len();
+        line.truncate(end);
+        Ok(())
+    };
+
+    next_line(&mut line)?;
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(bad_response("not HTTP/1.x"));
+    }
+    let status = parts
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (100..1000).contains(code))
+        .ok_or_else(|| bad_response("bad status code"))?;
+
+    let mut headers = Headers::new();
+    loop {
+        next_line(&mut line)?;
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(bad_response("too many headers"));
+        }
+        match line.find(':') {
+            Some(colon) if colon > 0 => headers.append(&line[..colon], line[colon + 1..].trim()),
+            _ => return Err(bad_response("bad header line")),
+        }
+    }
+}
+
+//Takes the chunked coding off a response body as it's read.
+struct Chunked<R> {
+    reader: R,
+    //What's left of the current chunk. None before the first one.
+    left: Option<u64>,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(reader: R) -> Chunked<R> {
+        Chunked { reader, left: None, done: false }
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        self.reader.by_ref().take(1024).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(bad_response("chunked body ended early"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && self.left.unwrap_or(0) == 0 {
+            //Every chunk but the first ends with a CRLF of its own.
+            if self.left.is_some() && !self.line()?.is_empty() {
+                return Err(bad_response("chunk longer than its size"));
+            }
+            let line = self.line()?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = u64::from_str_radix(size, 16).map_err(|_| bad_response("bad chunk size"))?;
+            if size == 0 {
+                //Trailers aren't passed on, they end with an empty line like the headers.
+                while !self.line()?.is_empty() {}
+                self.done = true;
+            }
+            self.left = Some(size);
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let left = self.left.unwrap_or(0);
+        let want = buf.len().min(left as usize);
+        let n = self.reader.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(bad_response("chunked body ended early"));
+        }
+        self.left = Some(left - n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::Limits;
+
+    #[test]
+    fn rewrites_the_request_head() {
+        let raw = "POST /api/items?x=1 HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\n\
+                   X-Secret: 1\r\nKeep-Alive: timeout=5\r\nX-Forwarded-For: 10.0.0.1\r\nAccept: */*\r\n\
+                   Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        let mut request = Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0;
+        request.peer = Some("192.168.1.9:5000".parse().unwrap());
+        let head = String::from_utf8(upstream_request(&request, "127.0.0.1:3000")).unwrap();
+        assert_eq!(
+            head,
+            "POST /api/items?x=1 HTTP/1.1\r\nHost: 127.0.0.1:3000\r\nAccept: */*\r\n\
+             X-Forwarded-For: 10.0.0.1, 192.168.1.9\r\nX-Forwarded-Host: example.com\r\n\
+             Content-Length: 3\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn reads_response_heads() {
+        let mut raw = &b"HTTP/1.0 404 Not Found\r\nContent-Type: text/plain\r\nX-A:b\r\n\r\nbody"[..];
+        let (status, headers) = read_head(&mut raw).unwrap();
+        assert_eq!(status, 404);
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("X-A"), Some("b"));
+        assert_eq!(raw, b"body");
+
+        for bad in [&b"SMTP 220 hi\r\n\r\n"[..], b"HTTP/1.1 abc\r\n\r\n", b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n", b"HTTP/1.1 200 OK\r\n"] {
+            assert_eq!(read_head(&mut &bad[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        let long = format!("HTTP/1.1 200 OK\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD));
+        assert_eq!(read_head(&mut long.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let raw = &b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\nnext"[..];
+        let mut decoded = String::new();
+        let mut chunked = Chunked::new(raw);
+        chunked.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "hello, world");
+        assert_eq!(chunked.reader, b"next");
+
+        let mut out = Vec::new();
+        assert!(Chunked::new(&b"5\r\nhel"[..]).read_to_end(&mut out).is_err());
+        assert!(Chunked::new(&b"zz\r\n"[..]).read_to_end(&mut out).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//The pool started out sending jobs down an mpsc::channel, which has no limit at all,
+//so a flood of connections just kept piling up in memory. A sync_channel could block
+//when full, but it can't hand a job back to the caller or throw away the oldest one,
+//so the queue is now a VecDeque behind a Mutex with a pair of Condvars to wait on.
+//That single Mutex is also something every worker fights over, so there's a second
+//design with a queue per worker in the stealing module, picked with `Scheduler`.
+//
+//A single FIFO meant a health check had to wait behind every slow request queued before it,
+//so either queue keeps its jobs in Lanes: one VecDeque per Priority. Workers take from the
+//most urgent lane that has anything in it, except that a job that has waited longer than the
+//pool's starvation limit goes first no matter what's above it. That way a steady stream of
+//urgent jobs can slow the others down but never stop them.
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use stealing::StealingQueue;
+use {FnBox, Message};
+
+/// How jobs get from `execute` to the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheduler {
+    /// One queue shared by every worker. Jobs start in exactly the order they were queued.
+    #[default]
+    Shared,
+    /// Every worker has its own queue and jobs are dealt out to them in turn. A worker
+    /// that runs out of jobs steals from the others. Cuts down on lock contention when
+    /// there are lots of short jobs, at the cost of strict FIFO order across workers.
+    WorkStealing,
+}
+
+/// How urgent a job is. Workers take `High` jobs before `Normal` ones and `Normal` ones before
+/// `Low` ones, and jobs with the same priority in the order they were queued.
+///
+/// A job that has waited longer than the pool's starvation limit is taken before everything
+/// else, so lower priorities still get their turn when the pool is busy.
+///
+/// `Server::priority` gives requests for a path their own priority, but only in
+/// `IoMode::Event`, the one mode that reads a request before it's queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+//How many job lanes there are, one per Priority. Terminate messages get the lane after them.
+pub(crate) const LEVELS: usize = 3;
+pub(crate) const TERMINATE: usize = LEVELS;
+
+/// The messages waiting in a queue, a lane per priority plus one for terminate messages.
+///
+/// The terminate lane is only looked at once there are no jobs left, so shrinking or dropping
+/// the pool never leaves a queued job behind.
+pub(crate) struct Lanes {
+    lanes: [VecDeque<Message>; LEVELS + 1],
+}
+
+impl Lanes {
+    pub(crate) fn new() -> Lanes {
+        Lanes { lanes: Default::default() }
+    }
+
+    /// Which lane a message goes in.
+    pub(crate) fn lane_of(message: &Message, priority: Priority) -> usize {
+        match *message {
+            Message::NewJob(..) => priority.lane(),
+            Message::Terminate => TERMINATE,
+        }
+    }
+
+    pub(crate) fn push(&mut self, lane: usize, message: Message) {
+        self.lanes[lane].push_back(message);
+    }
+
+    pub(crate) fn pop_lane(&mut self, lane: usize) -> Option<Message> {
+        self.lanes[lane].pop_front()
+    }
+
+    /// The lane whose first job has waited the longest, if that's longer than `limit`.
+    /// `High` jobs can't be starved so their lane isn't looked at.
+    pub(crate) fn starved(&self, limit: Duration, now: Instant) -> Option<usize> {
+        (1..LEVELS)
+            .filter_map(|lane| match self.lanes[lane].front() {
+                Some(&Message::NewJob(_, queued_at)) if now.saturating_duration_since(queued_at) > limit => Some((queued_at, lane)),
+                _ => None,
+            })
+            .min()
+            .map(|(_, lane)| lane)
+    }
+
+    /// The next message in line: a starved job, then the most urgent job, then a terminate message.
+    pub(crate) fn pop(&mut self, limit: Duration) -> Option<Message> {
+        let first = match self.starved(limit, Instant::now()) {
+            Some(lane) => lane,
+            None => (0..=TERMINATE).find(|&lane| !self.lanes[lane].is_empty())?,
+        };
+        self.pop_lane(first)
+    }
+
+    /// Throw away the job that has waited the longest in the least urgent lane that has one.
+    /// Returns false if there are no jobs at all.
+    pub(crate) fn drop_oldest(&mut self) -> bool {
+        (0..LEVELS).rev().any(|lane| self.lanes[lane].pop_front().is_some())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// What `ThreadPool::execute` does when the queue is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait until a worker takes a job off of the queue.
+    Block,
+    /// Give the new job straight back to the caller.
+    Reject,
+    /// Throw away the job that has been waiting the longest to make room, starting with the
+    /// least urgent ones.
+    DropOldest,
+}
+
+/// The queue the pool and its workers share, whichever scheduler it uses.
+pub(crate) enum JobQueue {
+    Shared(SharedQueue),
+    Stealing(StealingQueue),
+}
+
+impl JobQueue {
+    /// `starvation_limit` is how long a job may wait before it goes ahead of more urgent ones.
+    pub(crate) fn new(scheduler: Scheduler, workers: usize, capacity: Option<usize>, starvation_limit: Duration) -> JobQueue {
+        match scheduler {
+            Scheduler::Shared => JobQueue::Shared(SharedQueue::new(capacity, starvation_limit)),
+            Scheduler::WorkStealing => JobQueue::Stealing(StealingQueue::new(workers, capacity, starvation_limit)),
+        }
+    }
+
+    /// Add a job following `policy` if the queue is full. `wait` set to false turns
+    /// `Block` into `Reject` for `try_execute`. A rejected job is handed back untouched.
+    pub(crate) fn push<J>(&self, job: J, priority: Priority, policy: QueuePolicy, wait: bool) -> Result<(), J>
+        where
+            J: FnBox + Send + 'static
+    {
+        match *self {
+            JobQueue::Shared(ref queue) => queue.push(job, priority, policy, wait),
+            JobQueue::Stealing(ref queue) => queue.push(job, priority, policy, wait),
+        }
+    }
+
+    /// Add a message no matter how full the queue is. Used for the terminate messages
+    /// so dropping the pool never has to wait for room.
+    pub(crate) fn push_unbounded(&self, message: Message) {
+        match *self {
+            JobQueue::Shared(ref queue) => queue.push_unbounded(message),
+            JobQueue::Stealing(ref queue) => queue.push_unbounded(message),
+        }
+    }
+
+    /// Wait for the next message for the given worker, or at most `timeout` if there is one.
+    /// Returns `None` only when the timeout runs out.
+    pub(crate) fn pop(&self, worker: usize, timeout: Option<Duration>) -> Option<Message> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        match *self {
+            JobQueue::Shared(ref queue) => queue.pop(deadline),
+            JobQueue::Stealing(ref queue) => queue.pop(worker, deadline),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match *self {
+            JobQueue::Shared(ref queue) => queue.len(),
+            JobQueue::Stealing(ref queue) => queue.len(),
+        }
+    }
+}
+
+pub(crate) struct SharedQueue {
+    messages: Mutex<Lanes>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    starvation_limit: Duration,
+}
+
+impl SharedQueue {
+    fn new(capacity: Option<usize>, starvation_limit: Duration) -> SharedQueue {
+        SharedQueue {
+            messages: Mutex::new(Lanes::new()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            starvation_limit,
+        }
+    }
+
+    //Jobs run outside of the lock and nothing in here can panic halfway through changing
+    //the lanes, so a poisoned lock still holds a perfectly good queue. Unwrapping would
+    //let one unlucky panic take down every worker, so we just carry on instead.
+    fn lock(&self) -> MutexGuard<'_, Lanes> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_full(&self, messages: &Lanes) -> bool {
+        match self.capacity {
+            Some(capacity) => messages.len() >= capacity,
+            None => false,
+        }
+    }
+
+    fn push<J>(&self, job: J, priority: Priority, policy: QueuePolicy, wait: bool) -> Result<(), J>
+        where
+            J: FnBox + Send + 'static
+    {
+        let mut messages = self.lock();
+        while self.is_full(&messages) {
+            match policy {
+                QueuePolicy::Block if wait => {
+                    messages = self.not_full.wait(messages).unwrap_or_else(PoisonError::into_inner);
+                }
+                QueuePolicy::Block | QueuePolicy::Reject => return Err(job),
+                QueuePolicy::DropOldest => {
+                    //Only terminate messages left, they don't really take up room.
+                    if !messages.drop_oldest() {
+                        break;
+                    }
+                }
+            }
+        }
+        messages.push(priority.lane(), Message::NewJob(Box::new(job), Instant::now()));
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn push_unbounded(&self, message: Message) {
+        let lane = Lanes::lane_of(&message, Priority::Normal);
+        self.lock().push(lane, message);
+        self.not_empty.notify_one();
+    }
+
+    fn pop(&self, deadline: Option<Instant>) -> Option<Message> {
+        let mut messages = self.lock();
+        loop {
+            if let Some(message) = messages.pop(self.starvation_limit) {
+                self.not_full.notify_one();
+                return Some(message);
+            }
+            messages = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.not_empty.wait_timeout(messages, deadline - now).unwrap_or_else(PoisonError::into_inner).0
+                }
+                None => self.not_empty.wait(messages).unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.lock().len()
+    }
+}
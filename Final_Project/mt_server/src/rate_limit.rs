@@ -0,0 +1,174 @@
+//A single client could keep every worker busy, /sleep being the easy way to do it. RateLimit
+//is a middleware that gives every client IP a token bucket: the bucket holds up to `burst`
+//tokens, refills at `rate` tokens a second, and every request takes one. A client with an
+//empty bucket gets 429 Too Many Requests with a Retry-After saying when the next token is
+//due. Added to the outer Chain it limits every request, and wrapped around a single route's
+//handler it only limits that route, with buckets of its own.
+//
+//Limiting how many connections a client can have open at once happens before there is a
+//request at all, so that one lives in the Server's accept loop.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use middleware::Middleware;
+use request::Request;
+use response::Response;
+
+//How many requests go by between sweeps of the buckets nobody has used in a while.
+const SWEEP_EVERY: usize = 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    since_sweep: usize,
+}
+
+/// Per client IP rate limiting with a token bucket.
+///
+/// Requests without a known client address, like ones handed to a handler directly
+/// instead of coming in over a connection, aren't limited.
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimit {
+    /// Allow `rate` requests a second from each client on average, and up to `burst`
+    /// at once after the client has been quiet for a while.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` isn't a positive number or `burst` is zero.
+    pub fn new(rate: f64, burst: u32) -> RateLimit {
+        assert!(rate > 0.0 && rate.is_finite(), "the rate has to be a positive number");
+        assert!(burst > 0, "the burst has to be at least 1");
+        RateLimit {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Allow `requests` per minute from each client, all of which can come at once.
+    pub fn per_minute(requests: u32) -> RateLimit {
+        RateLimit::new(f64::from(requests) / 60.0, requests)
+    }
+
+    /// Take a token from `ip`'s bucket. If it's empty, returns how long until there's one.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        buckets.since_sweep += 1;
+        if buckets.since_sweep >= SWEEP_EVERY {
+            buckets.since_sweep = 0;
+            //A bucket that would be full again is the same as no bucket, so those can go.
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.clients.retain(|_, b| b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * rate < burst);
+        }
+
+        let bucket = buckets.clients.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+        let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let ip = request.peer?.ip();
+        self.check(ip).err().map(too_many_requests)
+    }
+}
+
+/// A 429 Too Many Requests telling the client to come back after `wait`.
+pub fn too_many_requests(wait: Duration) -> Response {
+    //Retry-After only takes whole seconds, and rounding down would invite another 429.
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Response::new(429)
+        .with_header("Retry-After", secs.max(1).to_string())
+        .with_body("Too Many Requests")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use middleware::Chain;
+    use request::Limits;
+    use router::Handler;
+
+    fn request(peer: &str) -> Request {
+        let mut request = Request::parse(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n", &Limits::default()).unwrap().unwrap().0;
+        request.peer = Some(peer.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limit = RateLimit::new(2.0, 3);
+        let ip = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limit.check_at(ip, start), Ok(()));
+        }
+        assert_eq!(limit.check_at(ip, start), Err(Duration::from_millis(500)));
+        //Another client has a bucket of its own.
+        assert_eq!(limit.check_at("10.0.0.2".parse().unwrap(), start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limit.check_at(ip, later), Ok(()));
+        assert!(limit.check_at(ip, later).is_err());
+        //A long break only refills up to the burst.
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limit.check_at(ip, much_later), Ok(()));
+        }
+        assert!(limit.check_at(ip, much_later).is_err());
+    }
+
+    #[test]
+    fn idle_buckets_are_swept() {
+        let limit = RateLimit::new(1.0, 1);
+        let start = Instant::now();
+        for i in 0..SWEEP_EVERY - 1 {
+            let ip = IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]);
+            assert!(limit.check_at(ip, start).is_ok());
+        }
+        assert_eq!(limit.buckets.lock().unwrap().clients.len(), SWEEP_EVERY - 1);
+        let _ = limit.check_at("10.9.9.9".parse().unwrap(), start + Duration::from_secs(2));
+        assert_eq!(limit.buckets.lock().unwrap().clients.len(), 1);
+    }
+
+    #[test]
+    fn answers_429_with_retry_after() {
+        let chain = Chain::new(|_: Request| Response::ok()).with(RateLimit::per_minute(1));
+        assert_eq!(chain.handle(request("10.0.0.1:1000")).status, 200);
+        let response = chain.handle(request("10.0.0.1:1001"));
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers.get("Retry-After"), Some("60"));
+        assert_eq!(chain.handle(request("10.0.0.2:1000")).status, 200);
+
+        //Without an address there's nobody to hold to the limit.
+        let mut anonymous = request("10.0.0.1:1000");
+        anonymous.peer = None;
+        assert_eq!(chain.handle(anonymous).status, 200);
+    }
+}
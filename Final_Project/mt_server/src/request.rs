@@ -0,0 +1,728 @@
+//The browser is taking a request of this form
+// Method Request-URI HTTP-Version CRLF
+// headers CRLF
+// message-body
+//Before this module existed we only compared the first few bytes of a 512 byte
+//buffer against "GET / HTTP/1.1\r\n". Anything with a query string, another method or
+//a request that didn't fit in a single read fell over. Here we parse the whole thing.
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+
+use headers::Headers;
+use router::Params;
+
+/// The request method from the request line.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    /// Any other valid token. Methods are case-sensitive so `get` ends up here.
+    Other(String),
+}
+
+impl Method {
+    pub fn from_token(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(ref s) => s,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The HTTP versions we know how to speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Size limits applied while parsing so a client can't make us buffer forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of the request line plus headers in bytes.
+    pub max_head: usize,
+    /// Maximum number of header fields.
+    pub max_headers: usize,
+    /// Maximum size of the (decoded) body in bytes.
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_head: 8 * 1024,
+            max_headers: 100,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+/// Everything that can be wrong with the bytes a client sent us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The request is malformed. Maps to 400.
+    BadRequest(&'static str),
+    /// The body is larger than `Limits::max_body`. Maps to 413.
+    PayloadTooLarge,
+    /// The request line and headers are larger than `Limits::max_head`
+    /// or there are too many of them. Maps to 431.
+    HeadersTooLarge,
+    /// A transfer coding other than chunked was used. Maps to 501.
+    UnsupportedTransferEncoding,
+    /// The version wasn't HTTP/1.0 or HTTP/1.1. Maps to 505.
+    UnsupportedVersion,
+}
+
+impl ParseError {
+    /// The status code and reason phrase that should be sent back to the client.
+    pub fn status(&self) -> (u16, &'static str) {
+        match *self {
+            ParseError::BadRequest(_) => (400, "Bad Request"),
+            ParseError::PayloadTooLarge => (413, "Payload Too Large"),
+            ParseError::HeadersTooLarge => (431, "Request Header Fields Too Large"),
+            ParseError::UnsupportedTransferEncoding => (501, "Not Implemented"),
+            ParseError::UnsupportedVersion => (505, "HTTP Version Not Supported"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::BadRequest(why) => write!(f, "bad request: {}", why),
+            _ => f.write_str(self.status().1),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// A fully read HTTP request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as it was sent, query string included.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    /// The body with any chunked transfer coding already removed.
+    pub body: Vec<u8>,
+    /// Values captured by the router from the route pattern. Empty until the request is routed.
+    pub params: Params,
+    /// The address of the client that sent the request, filled in by the connection it came in on.
+    pub peer: Option<SocketAddr>,
+}
+
+impl Request {
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(i) => &self.target[..i],
+            None => &self.target,
+        }
+    }
+
+    /// The part of the target after the `?`, if there is one.
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|i| &self.target[i + 1..])
+    }
+
+    /// A value captured by the route pattern, e.g. `id` for `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    /// Try to parse a request from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` when `buf` doesn't hold a whole request yet, in which case the
+    /// caller should read more bytes and try again. On success the request is returned
+    /// together with how many bytes of `buf` it used so anything after it (a pipelined
+    /// request for example) can be kept around.
+    pub fn parse(buf: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, ParseError> {
+        //RFC 7230 says we should ignore at least one empty line before the request line. They
+        //count towards the head, otherwise a client could send empty lines forever.
+        let mut start = 0;
+        while buf[start..].starts_with(b"\r\n") {
+            start += 2;
+        }
+
+        let head_len = match find(&buf[start..], b"\r\n\r\n") {
+            Some(i) => i,
+            None => {
+                if buf.len() > limits.max_head {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+                return Ok(None);
+            }
+        };
+        if start + head_len > limits.max_head {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        let head = match ::std::str::from_utf8(&buf[start..start + head_len]) {
+            Ok(head) => head,
+            Err(_) => return Err(ParseError::BadRequest("request head is not valid UTF-8")),
+        };
+        let mut lines = head.split("\r\n");
+        //split always yields at least one item
+        let (method, target, version) = parse_request_line(lines.next().unwrap())?;
+
+        let mut headers = Headers::new();
+        for line in lines {
+            if headers.len() == limits.max_headers {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            let (name, value) = parse_header_line(line)?;
+            headers.append(name, value);
+        }
+
+        if version == Version::Http11 && !headers.contains("Host") {
+            return Err(ParseError::BadRequest("missing Host header"));
+        }
+
+        let body_start = start + head_len + 4;
+        let (body, body_len) = match body_length(&headers)? {
+            BodyLength::Chunked => match decode_chunked(&buf[body_start..], limits)? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            },
+            BodyLength::Fixed(len) => {
+                if len > limits.max_body {
+                    return Err(ParseError::PayloadTooLarge);
+                }
+                if buf.len() - body_start < len {
+                    return Ok(None);
+                }
+                (buf[body_start..body_start + len].to_vec(), len)
+            }
+        };
+
+        let request = Request {
+            method,
+            target: target.to_string(),
+            version,
+            headers,
+            body,
+            params: Params::default(),
+            peer: None,
+        };
+        Ok(Some((request, body_start + body_len)))
+    }
+}
+
+/// Decode `%XX` escapes in a piece of a request target.
+///
+/// Returns `None` for a broken escape or if the result isn't valid UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = ::std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+//tchar from RFC 7230. Both methods and header names have to be made of these.
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| match b {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_'
+        | b'`' | b'|' | b'~' => true,
+        _ => b.is_ascii_alphanumeric(),
+    })
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::BadRequest("malformed request line")),
+    };
+
+    if !is_token(method) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::BadRequest("invalid HTTP version")),
+    };
+
+    Ok((Method::from_token(method), target, version))
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError> {
+    //Lines starting with whitespace are the obsolete line folding which we refuse.
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return Err(ParseError::BadRequest("obsolete header line folding"));
+    }
+    let colon = match line.find(':') {
+        Some(i) => i,
+        None => return Err(ParseError::BadRequest("header line without a colon")),
+    };
+    let name = &line[..colon];
+    //No whitespace is allowed between the name and the colon, so is_token catches it.
+    if !is_token(name) {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(ParseError::BadRequest("invalid header value"));
+    }
+    Ok((name, value))
+}
+
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+fn body_length(headers: &Headers) -> Result<BodyLength, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        //Having both is how request smuggling attacks start, so we just say no.
+        if headers.contains("Content-Length") {
+            return Err(ParseError::BadRequest("both Transfer-Encoding and Content-Length"));
+        }
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .collect();
+        //chunked is the only coding we understand and it has to be the last one.
+        return match codings.as_slice() {
+            [c] if c.eq_ignore_ascii_case("chunked") => Ok(BodyLength::Chunked),
+            _ => Err(ParseError::UnsupportedTransferEncoding),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all("Content-Length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        //Too many digits to fit in a usize is certainly too large for us as well.
+        let parsed = match value.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => return Err(ParseError::PayloadTooLarge),
+        };
+        match length {
+            Some(prev) if prev != parsed => {
+                return Err(ParseError::BadRequest("conflicting Content-Length values"));
+            }
+            _ => length = Some(parsed),
+        }
+    }
+    Ok(BodyLength::Fixed(length.unwrap_or(0)))
+}
+
+//A chunked body looks like
+// chunk-size [; extensions] CRLF
+// chunk-data CRLF
+// ... repeated until a chunk of size 0, followed by optional trailer fields and a CRLF.
+//Returns the decoded body and how many bytes of buf it took up.
+fn decode_chunked(buf: &[u8], limits: &Limits) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let line_len = match find(&buf[pos..], b"\r\n") {
+            Some(i) => i,
+            None => {
+                if buf.len() - pos > limits.max_head {
+                    return Err(ParseError::BadRequest("chunk size line too long"));
+                }
+                return Ok(None);
+            }
+        };
+        let line = &buf[pos..pos + line_len];
+        let size_end = line.iter().position(|&b| b == b';').unwrap_or(line.len());
+        let size = ::std::str::from_utf8(&line[..size_end])
+            .ok()
+            .map(|s| s.trim_matches(|c| c == ' ' || c == '\t'))
+            .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or(ParseError::BadRequest("invalid chunk size"))?;
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(n) => n,
+            Err(_) => return Err(ParseError::PayloadTooLarge),
+        };
+        pos += line_len + 2;
+
+        if size == 0 {
+            break;
+        }
+        //Written so that a huge size can't overflow its way past the limit.
+        if size > limits.max_body - body.len() {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        match size.checked_add(2) {
+            Some(needed) if buf.len() - pos >= needed => {}
+            Some(_) => return Ok(None),
+            None => return Err(ParseError::PayloadTooLarge),
+        }
+        body.extend_from_slice(&buf[pos..pos + size]);
+        pos += size;
+        if &buf[pos..pos + 2] != b"\r\n" {
+            return Err(ParseError::BadRequest("chunk data not followed by CRLF"));
+        }
+        pos += 2;
+    }
+
+    //Skip over any trailer fields until we hit the empty line.
+    let trailer_start = pos;
+    loop {
+        let line_len = match find(&buf[pos..], b"\r\n") {
+            Some(i) => i,
+            None => {
+                if buf.len() - trailer_start > limits.max_head {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+                return Ok(None);
+            }
+        };
+        pos += line_len + 2;
+        if line_len == 0 {
+            return Ok(Some((body, pos)));
+        }
+        if pos - trailer_start > limits.max_head {
+            return Err(ParseError::HeadersTooLarge);
+        }
+    }
+}
+
+/// Errors from `RequestReader::read_request`.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The connection failed or was closed halfway through a request.
+    Io(io::Error),
+    /// The client sent something we couldn't accept.
+    Parse(ParseError),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::Io(ref e) => write!(f, "i/o error: {}", e),
+            ReadError::Parse(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ReadError::Io(ref e) => Some(e),
+            ReadError::Parse(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> ReadError {
+        ReadError::Parse(e)
+    }
+}
+
+/// Reads requests off a stream, keeping whatever bytes come after a request
+/// so the next call can pick them up.
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: Limits,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R) -> RequestReader<R> {
+        RequestReader::with_limits(inner, Limits::default())
+    }
+
+    pub fn with_limits(inner: R, limits: Limits) -> RequestReader<R> {
+        RequestReader {
+            inner,
+            buf: Vec::new(),
+            limits,
+        }
+    }
+
+    /// Read the next request.
+    ///
+    /// Returns `Ok(None)` if the peer closed the connection cleanly between requests.
+    pub fn read_request(&mut self) -> Result<Option<Request>, ReadError> {
+        let mut chunk = [0; 4096];
+        loop {
+            if !self.buf.is_empty() {
+                if let Some((request, used)) = Request::parse(&self.buf, &self.limits)? {
+                    self.buf.drain(..used);
+                    return Ok(Some(request));
+                }
+            }
+
+            let n = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ReadError::Io(e)),
+            };
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ReadError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a request",
+                )));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Bytes that have been read but not yet used by a request.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The stream together with the bytes that have been read from it but not used by a
+    /// request, for when something other than HTTP takes over the connection.
+    pub fn into_parts(self) -> (R, Vec<u8>) {
+        (self.inner, self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        Request::parse(raw, &Limits::default())
+    }
+
+    //Hands out the bytes a few at a time so we can check partial reads.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn parses_request_line_headers_and_query() {
+        let raw = b"GET /search?q=rust&page=2 HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n";
+        let (request, used) = parse(raw).unwrap().unwrap();
+        assert_eq!(used, raw.len());
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target, "/search?q=rust&page=2");
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), Some("q=rust&page=2"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc").unwrap(), "a b/c");
+        assert_eq!(percent_decode("%e2%9c%93").unwrap(), "\u{2713}");
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn reads_content_length_body() {
+        let raw = b"POST /form HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET";
+        let (request, used) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.body, b"hello");
+        assert_eq!(&raw[used..], b"GET");
+    }
+
+    #[test]
+    fn decodes_chunked_body_with_trailers() {
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\n";
+        let (request, used) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(used, raw.len());
+    }
+
+    #[test]
+    fn incomplete_requests_ask_for_more() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap(), None);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nabc").unwrap(), None);
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let bad: &[&[u8]] = &[
+            b"GET /\r\nHost: a\r\n\r\n",
+            b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: abc\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        ];
+        for raw in bad {
+            match parse(raw) {
+                Err(ParseError::BadRequest(_)) => {}
+                other => panic!("expected 400 for {:?}, got {:?}", String::from_utf8_lossy(raw), other),
+            }
+        }
+    }
+
+    #[test]
+    fn maps_limits_to_status_codes() {
+        let limits = Limits { max_head: 64, max_headers: 2, max_body: 4 };
+
+        let long_head = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(100));
+        let err = Request::parse(long_head.as_bytes(), &limits).unwrap_err();
+        assert_eq!(err.status().0, 431);
+
+        let many = b"GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert_eq!(Request::parse(many, &limits), Err(ParseError::HeadersTooLarge));
+
+        let big = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(Request::parse(big, &limits).unwrap_err().status().0, 413);
+
+        let big_chunks = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\n";
+        assert_eq!(Request::parse(big_chunks, &limits), Err(ParseError::PayloadTooLarge));
+        let huge_chunk = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        assert_eq!(Request::parse(huge_chunk, &limits), Err(ParseError::PayloadTooLarge));
+        assert_eq!(parse(huge_chunk), Err(ParseError::PayloadTooLarge));
+
+        let empty_lines = "\r\n".repeat(40);
+        assert_eq!(Request::parse(empty_lines.as_bytes(), &limits), Err(ParseError::HeadersTooLarge));
+        let after_empty_lines = format!("{}GET / HTTP/1.1\r\nHost: a\r\n\r\n", "\r\n".repeat(30));
+        assert_eq!(Request::parse(after_empty_lines.as_bytes(), &limits), Err(ParseError::HeadersTooLarge));
+
+        assert_eq!(parse(b"GET / HTTP/2.0\r\n\r\n").unwrap_err().status().0, 505);
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n").unwrap_err().status().0,
+            501
+        );
+    }
+
+    #[test]
+    fn reader_handles_partial_reads_and_pipelining() {
+        let raw = b"GET /one HTTP/1.1\r\nHost: a\r\n\r\n\
+                    POST /two HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabc";
+        let mut reader = RequestReader::new(Trickle { data: raw, step: 3 });
+
+        let first = reader.read_request().unwrap().unwrap();
+        assert_eq!(first.target, "/one");
+        let second = reader.read_request().unwrap().unwrap();
+        assert_eq!(second.target, "/two");
+        assert_eq!(second.body, b"abc");
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_reports_truncated_requests() {
+        let mut reader = RequestReader::new(Trickle { data: b"GET / HTTP/1.1\r\nHo", step: 64 });
+        match reader.read_request() {
+            Err(ReadError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("expected an unexpected EOF, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//Responses take the following format:
+// HTTP-Version Status-Code Reason-Phrase CRLF
+// headers CRLF
+// message-body
+//So we have the HTTP-Version used in the response, a numeric status code summarizing the results,
+//and a reason phrase that provides a text description of the status code.
+//
+//A body used to have to be in memory in one piece, so serving a big file meant reading all
+//of it first. A Body can also be a reader now, which is copied to the client a piece at a
+//time. When its length is known up front it's sent with a Content-Length like any other
+//body, otherwise HTTP/1.1 clients get it in chunks and HTTP/1.0 clients until we hang up.
+//
+//A 101 Switching Protocols response can carry an upgrade as well, which is what the
+//connection gets handed over to once the response has gone out.
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::time::SystemTime;
+
+use connection::Upgraded;
+use date::format_http_date;
+use headers::Headers;
+use request::Version;
+
+/// The status codes we know the reason phrase for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum Status {
+    Continue = 100,
+    SwitchingProtocols = 101,
+    Ok = 200,
+    Created = 201,
+    Accepted = 202,
+    NoContent = 204,
+    PartialContent = 206,
+    MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    UpgradeRequired = 426,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+    HttpVersionNotSupported = 505,
+}
+
+const ALL: [Status; 35] = [
+    Status::Continue,
+    Status::SwitchingProtocols,
+    Status::Ok,
+    Status::Created,
+    Status::Accepted,
+    Status::NoContent,
+    Status::PartialContent,
+    Status::MovedPermanently,
+    Status::Found,
+    Status::SeeOther,
+    Status::NotModified,
+    Status::TemporaryRedirect,
+    Status::PermanentRedirect,
+    Status::BadRequest,
+    Status::Unauthorized,
+    Status::Forbidden,
+    Status::NotFound,
+    Status::MethodNotAllowed,
+    Status::RequestTimeout,
+    Status::Conflict,
+    Status::Gone,
+    Status::LengthRequired,
+    Status::PayloadTooLarge,
+    Status::UriTooLong,
+    Status::UnsupportedMediaType,
+    Status::RangeNotSatisfiable,
+    Status::UpgradeRequired,
+    Status::TooManyRequests,
+    Status::RequestHeaderFieldsTooLarge,
+    Status::InternalServerError,
+    Status::NotImplemented,
+    Status::BadGateway,
+    Status::ServiceUnavailable,
+    Status::GatewayTimeout,
+    Status::HttpVersionNotSupported,
+];
+
+impl Status {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_code(code: u16) -> Option<Status> {
+        ALL.iter().cloned().find(|status| status.code() == code)
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Status::Continue => "Continue",
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::Accepted => "Accepted",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::SeeOther => "See Other",
+            Status::NotModified => "Not Modified",
+            Status::TemporaryRedirect => "Temporary Redirect",
+            Status::PermanentRedirect => "Permanent Redirect",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::RequestTimeout => "Request Timeout",
+            Status::Conflict => "Conflict",
+            Status::Gone => "Gone",
+            Status::LengthRequired => "Length Required",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::UriTooLong => "URI Too Long",
+            Status::UnsupportedMediaType => "Unsupported Media Type",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::UpgradeRequired => "Upgrade Required",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::BadGateway => "Bad Gateway",
+            Status::ServiceUnavailable => "Service Unavailable",
+            Status::GatewayTimeout => "Gateway Timeout",
+            Status::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+/// The body of a response.
+pub enum Body {
+    /// The whole body, already in memory.
+    Bytes(Vec<u8>),
+    /// A body that's read while it's being sent. If `len` is given exactly that many
+    /// bytes are sent, otherwise everything up to the end of the reader.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: Option<u64>,
+    },
+}
+
+impl Body {
+    /// The length of the body if it's known before sending it.
+    pub fn len(&self) -> Option<u64> {
+        match *self {
+            Body::Bytes(ref bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// The body if it's in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Body::Bytes(ref bytes) => Some(bytes),
+            Body::Reader { .. } => None,
+        }
+    }
+
+    /// The whole body, reading it in if it's a reader.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::Reader { reader, len: Some(len) } => {
+                let mut bytes = Vec::with_capacity(len as usize);
+                reader.take(len).read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+            Body::Reader { mut reader, len: None } => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::Bytes(Vec::new())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Body::Bytes(ref bytes) => f.debug_tuple("Bytes").field(&String::from_utf8_lossy(bytes)).finish(),
+            Body::Reader { len, .. } => f.debug_struct("Reader").field("len", &len).finish(),
+        }
+    }
+}
+
+//So tests and handlers can write `response.body == b"..."`. A reader never equals anything
+//since comparing it would mean using it up.
+impl<'a, const N: usize> PartialEq<&'a [u8; N]> for Body {
+    fn eq(&self, other: &&'a [u8; N]) -> bool {
+        self.as_bytes() == Some(&other[..])
+    }
+}
+
+impl<B: Into<Vec<u8>>> From<B> for Body {
+    fn from(bytes: B) -> Body {
+        Body::Bytes(bytes.into())
+    }
+}
+
+/// An HTTP response that handlers hand back to the server.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Body,
+    pub(crate) upgrade: Option<Upgrade>,
+}
+
+//What takes over the connection after a 101.
+pub(crate) struct Upgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl Upgrade {
+    pub(crate) fn run(self, upgraded: Upgraded) {
+        (self.0)(upgraded)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Upgrade").finish()
+    }
+}
+
+impl Response {
+    /// Create an empty response with the given status code.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::default(),
+            upgrade: None,
+        }
+    }
+
+    pub fn ok() -> Response {
+        Response::new(200)
+    }
+
+    pub fn not_found() -> Response {
+        Response::new(404)
+    }
+
+    pub fn from_status(status: Status) -> Response {
+        Response::new(status.code())
+    }
+
+    pub fn with_header<N, V>(mut self, name: N, value: V) -> Response
+        where
+            N: Into<String>,
+            V: Into<String>
+    {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Send whatever `reader` gives us as the body. `len` should be given when it's
+    /// known so the client gets a Content-Length, otherwise the body is sent chunked.
+    pub fn with_reader<R: Read + Send + 'static>(mut self, reader: R, len: Option<u64>) -> Response {
+        self.body = Body::Reader { reader: Box::new(reader), len };
+        self
+    }
+
+    /// Hand the connection to `upgrade` once this response has been sent, instead of
+    /// reading another request from it. Only a 101 Switching Protocols response upgrades
+    /// the connection, any other status ignores this. The connection closes when
+    /// `upgrade` returns.
+    pub fn with_upgrade<F: FnOnce(Upgraded) + Send + 'static>(mut self, upgrade: F) -> Response {
+        self.upgrade = Some(Upgrade(Box::new(upgrade)));
+        self
+    }
+
+    /// Send the rest of `file` as the body, starting from wherever it's positioned.
+    pub fn with_file(self, mut file: File) -> io::Result<Response> {
+        let len = file.metadata()?.len();
+        let start = file.stream_position()?;
+        Ok(self.with_reader(file, Some(len.saturating_sub(start))))
+    }
+
+    //1xx, 204 and 304 responses never have a body so they don't get a length either.
+    fn allows_body(&self) -> bool {
+        !(self.status < 200 || self.status == 204 || self.status == 304)
+    }
+
+    fn head(&self, version: Version) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        if !self.headers.contains("Date") {
+            head.push_str(&format!("Date: {}\r\n", format_http_date(SystemTime::now())));
+        }
+        for (name, value) in &self.headers {
+            //How the body is framed is up to us, not the handler.
+            if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.allows_body() {
+            match self.body.len() {
+                Some(len) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+                None if version == Version::Http11 => head.push_str("Transfer-Encoding: chunked\r\n"),
+                //An HTTP/1.0 client reads until the connection closes.
+                None => {}
+            }
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    /// Write the status line, headers and body, and return how many bytes of body were
+    /// sent. A Content-Length header is added from the body, or chunked encoding is used
+    /// if its length isn't known.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+        self.send(writer, Version::Http11, false)
+    }
+
+    /// Write only the status line and headers, which is what a HEAD request gets.
+    pub fn write_head<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.head(Version::Http11).as_bytes())?;
+        writer.flush()
+    }
+
+    /// Write the response for a client speaking `version`. With `head_only` the body
+    /// is left out, the way a HEAD request wants it.
+    pub(crate) fn send<W: Write>(&mut self, writer: &mut W, version: Version, head_only: bool) -> io::Result<u64> {
+        writer.write_all(self.head(version).as_bytes())?;
+        if head_only || !self.allows_body() {
+            writer.flush()?;
+            return Ok(0);
+        }
+
+        let sent = match self.body {
+            Body::Bytes(ref bytes) => {
+                writer.write_all(bytes)?;
+                bytes.len() as u64
+            }
+            Body::Reader { ref mut reader, len: Some(len) } => {
+                let sent = io::copy(&mut reader.take(len), writer)?;
+                //We promised the client `len` bytes, so a short body leaves the connection broken.
+                if sent < len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the response body ended early"));
+                }
+                sent
+            }
+            Body::Reader { ref mut reader, len: None } if version == Version::Http11 => {
+                let mut chunked = Chunked { writer: &mut *writer };
+                let sent = io::copy(reader, &mut chunked)?;
+                chunked.writer.write_all(b"0\r\n\r\n")?;
+                sent
+            }
+            Body::Reader { ref mut reader, len: None } => io::copy(reader, writer)?,
+        };
+        writer.flush()?;
+        Ok(sent)
+    }
+}
+
+impl From<Status> for Response {
+    fn from(status: Status) -> Response {
+        Response::from_status(status)
+    }
+}
+
+//Wraps every write in its own chunk. io::copy hands us its buffer at a time so the
+//chunks don't get too small.
+struct Chunked<'a, W: 'a> {
+    writer: &'a mut W,
+}
+
+impl<'a, W: Write> Write for Chunked<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        //A zero sized chunk would end the body.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.writer, "{:x}\r\n", buf.len())?;
+        self.writer.write_all(buf)?;
+        self.writer.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The standard reason phrase for a status code.
+pub fn reason_phrase(status: u16) -> &'static str {
+    Status::from_code(status).map_or("Unknown", Status::reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(mut response: Response, version: Version) -> String {
+        let mut out = Vec::new();
+        response.send(&mut out, version, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn statuses_know_their_codes() {
+        assert_eq!(Status::NotFound.code(), 404);
+        assert_eq!(Status::from_code(429), Some(Status::TooManyRequests));
+        assert_eq!(Status::from_code(299), None);
+        assert_eq!(reason_phrase(503), "Service Unavailable");
+        assert_eq!(reason_phrase(299), "Unknown");
+        assert_eq!(Response::from(Status::Gone).status, 410);
+    }
+
+    #[test]
+    fn adds_length_and_date() {
+        let out = sent(Response::ok().with_header("Content-Length", "99").with_body("hello"), Version::Http11);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\nDate: "));
+        assert!(out.ends_with(" GMT\r\nContent-Length: 5\r\n\r\nhello"));
+
+        //A Date set by the handler wins.
+        let out = sent(Response::new(204).with_header("Date", "yesterday"), Version::Http11);
+        assert_eq!(out, "HTTP/1.1 204 No Content\r\nDate: yesterday\r\n\r\n");
+    }
+
+    #[test]
+    fn binary_bodies_go_out_untouched() {
+        let body = vec![0, 159, 146, 150, 255];
+        let mut out = Vec::new();
+        let sent = Response::ok().with_body(body.clone()).write_to(&mut out).unwrap();
+        assert_eq!(sent, 5);
+        assert!(out.ends_with(b"Content-Length: 5\r\n\r\n\x00\x9f\x92\x96\xff"));
+    }
+
+    #[test]
+    fn readers_with_a_length_stream_as_is() {
+        let response = Response::ok().with_header("Date", "now").with_reader(&b"hello world"[..], Some(5));
+        assert_eq!(sent(response, Version::Http11), "HTTP/1.1 200 OK\r\nDate: now\r\nContent-Length: 5\r\n\r\nhello");
+
+        //Running out early is an error rather than a silently short body.
+        let mut response = Response::ok().with_reader(&b"hi"[..], Some(5));
+        assert_eq!(response.write_to(&mut Vec::new()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn readers_without_a_length_are_chunked() {
+        let body = vec![b'a'; 10_000];
+        let response = Response::ok().with_header("Date", "now").with_reader(io::Cursor::new(body.clone()), None);
+        let out = sent(response, Version::Http11);
+        let head = "HTTP/1.1 200 OK\r\nDate: now\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(out.starts_with(head));
+        assert!(out.ends_with("\r\n0\r\n\r\n"));
+
+        //Putting the chunks back together gives the original body.
+        let mut rest = &out[head.len()..];
+        let mut decoded = Vec::new();
+        loop {
+            let line_end = rest.find("\r\n").unwrap();
+            let size = usize::from_str_radix(&rest[..line_end], 16).unwrap();
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                break;
+            }
+            decoded.extend_from_slice(&rest.as_bytes()[..size]);
+            rest = &rest[size + 2..];
+        }
+        assert_eq!(decoded, body);
+
+        //HTTP/1.0 has no chunks, the body just runs until the connection closes.
+        let response = Response::ok().with_header("Date", "now").with_reader(&b"abc"[..], None);
+        assert_eq!(sent(response, Version::Http10), "HTTP/1.1 200 OK\r\nDate: now\r\n\r\nabc");
+    }
+}
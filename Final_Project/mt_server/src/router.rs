@@ -0,0 +1,371 @@
+//Instead of an if/else chain inside of handle_connection the binary now registers
+//handlers on a Router. A handler is anything that implements the Handler trait, which
+//closures of the right shape get for free, so adding a page doesn't mean touching the
+//connection code anymore.
+use std::cmp::Ordering;
+
+use request::{percent_decode, Method, Request};
+use response::Response;
+
+/// Something that can turn a request into a response.
+///
+/// Handlers are shared between all of the pool's workers so they need to be
+/// `Send + Sync`.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> Response;
+}
+
+impl<F> Handler for F
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+/// The values captured from a route pattern such as `/users/:id`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    //Matches exactly one non-empty segment, for example :id
+    Param(String),
+    //Matches whatever is left of the path, * or *name. Only allowed at the end.
+    Wildcard(Option<String>),
+}
+
+impl Segment {
+    //Lower is more specific. Used to pick between several patterns that match.
+    fn rank(&self) -> u8 {
+        match *self {
+            Segment::Literal(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        assert!(pattern.starts_with('/'), "route patterns must start with '/': {}", pattern);
+
+        let parts: Vec<&str> = pattern[1..].split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "route parameter without a name in {}", pattern);
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(i == parts.len() - 1, "wildcards must be the last segment in {}", pattern);
+                Segment::Wildcard(if name.is_empty() { None } else { Some(name.to_string()) })
+            } else {
+                Segment::Literal(part.to_string())
+            };
+            segments.push(segment);
+        }
+        Pattern { segments }
+    }
+
+    //Returns the captured parameters if the path matches.
+    fn matches(&self, path: &str) -> Option<Params> {
+        if !path.starts_with('/') {
+            return None;
+        }
+        let parts: Vec<&str> = path[1..].split('/').collect();
+        let mut params = Params::default();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match *segment {
+                Segment::Wildcard(ref name) => {
+                    if let Some(ref name) = *name {
+                        let rest = if i < parts.len() { parts[i..].join("/") } else { String::new() };
+                        params.entries.push((name.clone(), percent_decode(&rest)?));
+                    }
+                    return Some(params);
+                }
+                Segment::Literal(ref lit) => {
+                    if parts.get(i) != Some(&lit.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(ref name) => match parts.get(i) {
+                    Some(part) if !part.is_empty() => {
+                        params.entries.push((name.clone(), percent_decode(part)?));
+                    }
+                    _ => return None,
+                },
+            }
+        }
+
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    fn ranks(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+struct Route {
+    //`None` takes every method.
+    method: Option<Method>,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+/// Dispatches requests to handlers registered by method and path pattern.
+///
+/// Patterns are made of `/` separated segments which are either literal text,
+/// `:name` to capture a single segment, or `*`/`*name` as the last segment to
+/// match the rest of the path. When more than one route matches the most specific
+/// one wins (literals beat captures which beat wildcards), and after that the one
+/// registered first. HEAD requests use the GET route for a path if there's no HEAD route.
+/// A route for one method wins over one registered with `any` for the same pattern.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+    method_not_allowed: Box<dyn Handler>,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_| Response::not_found().with_body("Not Found")),
+            method_not_allowed: Box::new(|_| Response::new(405).with_body("Method Not Allowed")),
+        }
+    }
+
+    /// Register a handler for a method and path pattern.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern doesn't start with `/`, has an unnamed `:` capture, or
+    /// has a wildcard anywhere but the last segment.
+    pub fn route<H: Handler>(&mut self, method: Method, pattern: &str, handler: H) -> &mut Router {
+        self.routes.push(Route {
+            method: Some(method),
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// Register a handler for a path pattern whatever the method, like a `Proxy` that
+    /// passes every request on.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the same bad patterns `route` does.
+    pub fn any<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.routes.push(Route {
+            method: None,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Handler used when no pattern matches the path. Defaults to a plain 404.
+    pub fn not_found<H: Handler>(&mut self, handler: H) -> &mut Router {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Handler used when a pattern matches the path but not with the request's method.
+    /// Defaults to a plain 405. An `Allow` header is added to its response if it doesn't set one.
+    pub fn method_not_allowed<H: Handler>(&mut self, handler: H) -> &mut Router {
+        self.method_not_allowed = Box::new(handler);
+        self
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, mut request: Request) -> Response {
+        //The best route so far, its params and how well its method fits: 2 for the request's
+        //own method, 1 for a route that takes any method and 0 for GET standing in for HEAD.
+        let mut best: Option<(&Route, Params, u8)> = None;
+        let mut allowed: Vec<&str> = Vec::new();
+
+        for route in &self.routes {
+            let params = match route.pattern.matches(request.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+            let fit = match route.method {
+                Some(ref method) if *method == request.method => 2,
+                None => 1,
+                //HEAD is answered by the GET handler unless it has its own route,
+                //the connection leaves the body off for us.
+                Some(Method::Get) if request.method == Method::Head => 0,
+                Some(ref method) => {
+                    if !allowed.contains(&method.as_str()) {
+                        allowed.push(method.as_str());
+                    }
+                    continue;
+                }
+            };
+            //GET standing in for HEAD is the last resort. Otherwise the most specific pattern
+            //wins, and for the same pattern the route for the request's own method.
+            let better = match best {
+                Some((_, _, current_fit)) if (current_fit == 0) != (fit == 0) => fit > 0,
+                Some((current, _, current_fit)) => match route.pattern.ranks().cmp(&current.pattern.ranks()) {
+                    Ordering::Less => true,
+                    Ordering::Equal => fit > current_fit,
+                    Ordering::Greater => false,
+                },
+                None => true,
+            };
+            if better {
+                best = Some((route, params, fit));
+            }
+        }
+
+        match best {
+            Some((route, params, _)) => {
+                request.params = params;
+                route.handler.handle(request)
+            }
+            None if !allowed.is_empty() => {
+                let allow = allowed.join(", ");
+                let mut response = self.method_not_allowed.handle(request);
+                if !response.headers.contains("Allow") {
+                    response.headers.insert("Allow", allow);
+                }
+                response
+            }
+            None => self.not_found.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::Limits;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, target);
+        Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |_: Request| Response::ok().with_body(name)
+    }
+
+    #[test]
+    fn captures_parameters_and_wildcards() {
+        let mut router = Router::new();
+        router.get("/users/:id", |req: Request| {
+            Response::ok().with_body(req.param("id").unwrap().to_string())
+        });
+        router.get("/static/*path", |req: Request| {
+            Response::ok().with_body(req.param("path").unwrap().to_string())
+        });
+
+        assert_eq!(body(router.handle(request("GET", "/users/42?x=1"))), "42");
+        assert_eq!(body(router.handle(request("GET", "/users/a%20b"))), "a b");
+        assert_eq!(body(router.handle(request("GET", "/static/css/site.css"))), "css/site.css");
+        assert_eq!(router.handle(request("GET", "/users/")).status, 404);
+        assert_eq!(router.handle(request("GET", "/users/1/posts")).status, 404);
+    }
+
+    #[test]
+    fn more_specific_routes_win() {
+        let mut router = Router::new();
+        router.get("/*", echo("wildcard"));
+        router.get("/users/:id", echo("param"));
+        router.get("/users/me", echo("literal"));
+
+        assert_eq!(body(router.handle(request("GET", "/users/me"))), "literal");
+        assert_eq!(body(router.handle(request("GET", "/users/7"))), "param");
+        assert_eq!(body(router.handle(request("GET", "/anything/else"))), "wildcard");
+        assert_eq!(body(router.handle(request("GET", "/"))), "wildcard");
+    }
+
+    #[test]
+    fn falls_back_to_404_and_405() {
+        let mut router = Router::new();
+        router.get("/", echo("index"));
+        router.post("/", echo("posted"));
+        router.route(Method::Delete, "/items/:id", echo("deleted"));
+        router.not_found(|_: Request| Response::not_found().with_body("custom"));
+
+        let response = router.handle(request("PUT", "/"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, POST"));
+
+        assert_eq!(body(router.handle(request("HEAD", "/"))), "index");
+
+        let response = router.handle(request("GET", "/missing"));
+        assert_eq!(response.status, 404);
+        assert_eq!(body(response), "custom");
+    }
+
+    #[test]
+    fn any_method_routes() {
+        let mut router = Router::new();
+        router.get("/*path", echo("files"));
+        router.any("/api/*rest", echo("any"));
+        router.post("/api/login", echo("login"));
+        router.any("/api/login", echo("any login"));
+
+        assert_eq!(body(router.handle(request("GET", "/index.html"))), "files");
+        assert_eq!(body(router.handle(request("GET", "/api/users"))), "any");
+        assert_eq!(body(router.handle(request("DELETE", "/api/users/1"))), "any");
+        assert_eq!(body(router.handle(request("POST", "/api/login"))), "login");
+        assert_eq!(body(router.handle(request("PUT", "/api/login"))), "any login");
+        assert_eq!(router.handle(request("PUT", "/index.html")).status, 405);
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcards must be the last segment")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*/oops", echo("never"));
+    }
+}
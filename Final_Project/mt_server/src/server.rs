@@ -0,0 +1,550 @@
+//main.rs used to stop after listener.incoming().take(2) just to show off the pool's Drop.
+//A Server owns the listener and the pool and keeps accepting until it is told to stop,
+//either through a ShutdownHandle or by SIGINT/SIGTERM. Stopping goes like this:
+// 1. stop accepting and close the listener so new clients are refused,
+// 2. close the reading half of every open connection so idle keep-alive connections
+//    end right away while requests that are already being handled can still be answered,
+// 3. give the workers a grace period to finish and then drop the pool.
+//A Server can also have an HTTPS listener next to the plain one, and connections from both
+//end up on the same pool.
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use access_log::AccessLog;
+use connection::{self, ConnectionConfig};
+use event;
+use rate_limit;
+use request::{Method, Request};
+use response::Response;
+use router::Handler;
+use signal;
+use tls::{self, TlsConfig};
+use {PoolMonitor, Priority, QueuePolicy, ThreadPool};
+
+//How often the accept loop looks for a shutdown request when nobody is connecting.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a `Server` waits on its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    /// Every connection gets a worker of its own for as long as it's open, which is simple
+    /// but means idle keep-alive connections take up workers too.
+    Blocking,
+    /// Connections wait on a poller, and a worker is only used while a request is being
+    /// answered, so there can be far more open connections than workers. HTTPS connections
+    /// are still served the blocking way. Only available on Linux.
+    Event,
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    /// Parses `blocking` or `event`, in any case.
+    fn from_str(s: &str) -> Result<IoMode, String> {
+        match &s.to_ascii_lowercase()[..] {
+            "blocking" => Ok(IoMode::Blocking),
+            "event" => Ok(IoMode::Event),
+            _ => Err(format!("unknown I/O mode {:?}, expected blocking or event", s)),
+        }
+    }
+}
+
+/// Tells a running `Server` to shut down. Cheap to clone and can be sent to other threads.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop. `Server::run` returns once it has finished shutting down.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+//The connections that are currently open so their reading half can be closed on shutdown,
+//and how many of them each client has.
+#[derive(Default)]
+pub(crate) struct OpenConnections {
+    next_id: usize,
+    pub(crate) streams: HashMap<usize, TcpStream>,
+    per_ip: HashMap<IpAddr, usize>,
+    pub(crate) drainer: Drainer,
+}
+
+//Takes a connection off of the open list when the job serving it ends, even if it panics.
+pub(crate) struct Registration {
+    id: usize,
+    ip: Option<IpAddr>,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.open.lock() {
+            open.streams.remove(&self.id);
+            if let Some(ip) = self.ip {
+                if let Entry::Occupied(mut count) = open.per_ip.entry(ip) {
+                    *count.get_mut() -= 1;
+                    if *count.get() == 0 {
+                        count.remove();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An HTTP server that hands every connection to a `ThreadPool`.
+pub struct Server {
+    pub(crate) listener: TcpListener,
+    pub(crate) https: Option<(TcpListener, TlsConfig)>,
+    handler: Arc<dyn Handler>,
+    workers: usize,
+    queue_capacity: Option<usize>,
+    connections_per_ip: Option<usize>,
+    io_mode: IoMode,
+    config: ConnectionConfig,
+    grace_period: Duration,
+    signals: bool,
+    metrics_path: Option<String>,
+    priorities: HashMap<String, Priority>,
+    access_log: Option<Arc<dyn AccessLog>>,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    /// Bind to `addr` and serve requests with `handler`.
+    ///
+    /// Defaults to 4 workers, the default `ConnectionConfig` and a 30 second grace period.
+    pub fn bind<A: ToSocketAddrs, H: Handler>(addr: A, handler: H) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            https: None,
+            handler: Arc::new(handler),
+            workers: 4,
+            queue_capacity: None,
+            connections_per_ip: None,
+            io_mode: IoMode::Blocking,
+            config: ConnectionConfig::default(),
+            grace_period: Duration::from_secs(30),
+            signals: false,
+            metrics_path: None,
+            priorities: HashMap::new(),
+            access_log: None,
+            shutdown: ShutdownHandle::default(),
+        })
+    }
+
+    /// The number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero, same as `ThreadPool::new`.
+    pub fn workers(mut self, workers: usize) -> Server {
+        assert!(workers > 0);
+        self.workers = workers;
+        self
+    }
+
+    /// Limit how many accepted connections can wait for a free worker. Once that many
+    /// are waiting, new connections get a 503 Service Unavailable instead of being queued.
+    /// Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn queue_capacity(mut self, capacity: usize) -> Server {
+        assert!(capacity > 0);
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Limit how many connections one client IP can have open at once. Connections over
+    /// the limit get a 429 Too Many Requests and are closed right away, so a single client
+    /// can't take up every worker. Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Server {
+        assert!(limit > 0);
+        self.connections_per_ip = Some(limit);
+        self
+    }
+
+    /// How connections are waited on. `IoMode::Blocking` by default.
+    pub fn io_mode(mut self, mode: IoMode) -> Server {
+        self.io_mode = mode;
+        self
+    }
+
+    pub fn connection_config(mut self, config: ConnectionConfig) -> Server {
+        self.config = config;
+        self
+    }
+
+    /// How long requests that are in flight get to finish once shutdown starts.
+    pub fn grace_period(mut self, grace_period: Duration) -> Server {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Also shut down on SIGINT or SIGTERM. Off by default since signal handlers are
+    /// process wide.
+    pub fn handle_signals(mut self, enabled: bool) -> Server {
+        self.signals = enabled;
+        self
+    }
+
+    /// Answer GET requests for `path` with the pool's metrics in the Prometheus text
+    /// format instead of passing them to the handler. Off by default.
+    pub fn metrics_path<S: Into<String>>(mut self, path: S) -> Server {
+        self.metrics_path = Some(path.into());
+        self
+    }
+
+    /// Queue requests for `path` with `priority`, so that for example a health check doesn't
+    /// wait behind slow requests. Everything else is `Priority::Normal`.
+    ///
+    /// Only `IoMode::Event` reads a request before it's queued. In `IoMode::Blocking` a worker
+    /// gets the whole connection before anything is read, so `run` fails with `InvalidInput`
+    /// there instead of ignoring the priorities. HTTPS connections are served the blocking way
+    /// in either mode and are always `Normal`.
+    pub fn priority<S: Into<String>>(mut self, path: S, priority: Priority) -> Server {
+        self.priorities.insert(path.into(), priority);
+        self
+    }
+
+    pub(crate) fn priority_of(&self, path: &str) -> Priority {
+        self.priorities.get(path).cloned().unwrap_or_default()
+    }
+
+    /// Record every request in `log`. There is no access log by default, which is also
+    /// the way to go for benchmarks.
+    pub fn access_log<L: AccessLog>(mut self, log: L) -> Server {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Also listen for HTTPS connections on `addr`, using the certificate in `tls`.
+    /// Requests coming in either way go to the same handler and pool.
+    pub fn https<A: ToSocketAddrs>(mut self, addr: A, tls: TlsConfig) -> io::Result<Server> {
+        self.https = Some((TcpListener::bind(addr)?, tls));
+        Ok(self)
+    }
+
+    /// The address the server is listening on, handy after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The address of the HTTPS listener, if there is one.
+    pub fn https_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.https.as_ref().map(|(listener, _)| listener.local_addr())
+    }
+
+    /// A handle that can stop the server from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub(crate) fn should_stop(&self) -> bool {
+        self.shutdown.is_shutdown() || (self.signals && signal::received())
+    }
+
+    /// Accept connections until shutdown is requested and then shut down gracefully.
+    ///
+    /// Returns `Ok(true)` if every in-flight request finished within the grace period.
+    /// Fails right away if priorities were set but the I/O mode can't use them.
+    pub fn run(self) -> io::Result<bool> {
+        if self.io_mode == IoMode::Blocking && !self.priorities.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request priorities need the event I/O mode"));
+        }
+        if self.signals {
+            signal::install()?;
+        }
+        //A blocking accept can't be interrupted, so we poll instead and check for a shutdown in between.
+        self.listener.set_nonblocking(true)?;
+        if let Some((ref listener, _)) = self.https {
+            listener.set_nonblocking(true)?;
+        }
+
+        //We're now going to introduce a thread pool to handle the request so that we don't run out of the
+        //number of threads. An async method with a set thread pool would probably still be the best method for this.
+        let mut pool = ThreadPool::builder()
+            .size(self.workers)
+            .name_prefix("http-worker-")
+            .queue_policy(QueuePolicy::Reject);
+        if let Some(capacity) = self.queue_capacity {
+            pool = pool.queue_capacity(capacity);
+        }
+        let pool = pool.build()?;
+        //The pool only exists once we're running, so this is the first chance to hook up its metrics.
+        let handler = match self.metrics_path {
+            Some(ref path) => Arc::new(Metrics {
+                path: path.clone(),
+                monitor: pool.monitor(),
+                handler: Arc::clone(&self.handler),
+            }),
+            None => Arc::clone(&self.handler),
+        };
+        let open = Arc::new(Mutex::new(OpenConnections::default()));
+        let context = Context {
+            handler,
+            log: self.access_log.clone(),
+            config: self.config,
+        };
+
+        match self.io_mode {
+            IoMode::Blocking => self.accept_loop(&pool, &context, &open)?,
+            IoMode::Event => event::run(&self, &pool, &context, &open)?,
+        }
+
+        println!("Shutting down the server.");
+        drop(self.listener);
+        drop(self.https);
+        for stream in open.lock().unwrap().streams.values() {
+            //The connection might already be on its way out, which is fine.
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        Ok(pool.shutdown_timeout(self.grace_period))
+    }
+
+    //Accepts connections and gives each one to a worker for as long as it stays open.
+    fn accept_loop(&self, pool: &ThreadPool, context: &Context, open: &Arc<Mutex<OpenConnections>>) -> io::Result<()> {
+        while !self.should_stop() {
+            //The plain listener goes first. Either one can have a connection waiting, and we
+            //only sleep when neither of them does.
+            let accepted = match accept(&self.listener) {
+                Ok(None) => match self.https {
+                    Some((ref listener, ref tls)) => accept(listener).map(|s| s.map(|s| (s, Some(tls.clone())))),
+                    None => Ok(None),
+                },
+                other => other.map(|s| s.map(|s| (s, None))),
+            };
+            let (stream, tls) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                //Errors like running out of file descriptors only affect this one connection.
+                Err(e) => {
+                    println!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            //Accepted sockets may inherit non-blocking mode from the listener on some platforms.
+            //Like the accept errors above, failing here only costs us this one connection.
+            let registered = stream.set_nonblocking(false).and_then(|_| self.register(&stream, tls.is_some(), open));
+            match registered {
+                Ok(Some((id, registration))) => serve_connection(pool, context, stream, tls, id, registration, open),
+                Ok(None) => {}
+                Err(e) => println!("Failed to set up a connection: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    //Puts a new connection on the open list, unless its client already has as many as it gets.
+    pub(crate) fn register(
+        &self,
+        stream: &TcpStream,
+        https: bool,
+        open: &Arc<Mutex<OpenConnections>>,
+    ) -> io::Result<Option<(usize, Registration)>> {
+        let ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        let mut connections = open.lock().unwrap();
+        let count = ip.map_or(0, |ip| connections.per_ip.get(&ip).cloned().unwrap_or(0));
+        if self.connections_per_ip.is_some_and(|limit| count >= limit) {
+            //Same as when the queue is full, an HTTPS client couldn't read the answer without a handshake.
+            if !https {
+                let _ = turn_away(stream, rate_limit::too_many_requests(Duration::from_secs(1)), &mut connections.drainer);
+            }
+            return Ok(None);
+        }
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.streams.insert(id, stream.try_clone()?);
+        if let Some(ip) = ip {
+            *connections.per_ip.entry(ip).or_insert(0) += 1;
+        }
+        Ok(Some((id, Registration { id, ip, open: Arc::clone(open) })))
+    }
+}
+
+//What the jobs serving connections need, cloned into each one of them.
+#[derive(Clone)]
+pub(crate) struct Context {
+    pub(crate) handler: Arc<dyn Handler>,
+    pub(crate) log: Option<Arc<dyn AccessLog>>,
+    pub(crate) config: ConnectionConfig,
+}
+
+//Hands a whole connection to a worker, which answers every request sent on it until it closes.
+pub(crate) fn serve_connection(
+    pool: &ThreadPool,
+    context: &Context,
+    stream: TcpStream,
+    tls: Option<TlsConfig>,
+    id: usize,
+    registration: Registration,
+    open: &Arc<Mutex<OpenConnections>>,
+) {
+    let https = tls.is_some();
+    let context = context.clone();
+    let job = pool.execute(move || {
+        let _registration = registration;
+        let Context { handler, log, config } = context;
+        let served = match tls {
+            Some(tls) => tls::serve_logged(stream, &tls, &*handler, &config, log.as_deref()),
+            None => connection::serve_logged(stream, &*handler, &config, log.as_deref()),
+        };
+        if let Err(e) = served {
+            println!("Connection error: {}", e);
+        }
+    });
+    if let Err(job) = job {
+        //Every worker is busy and the queue is full so we turn the client away. The
+        //stream itself is inside of the job, but the open list has a clone of it.
+        //An HTTPS client would need a handshake before it could read the 503, which
+        //is work we have no room for, so those are just closed.
+        if !https {
+            let open = &mut *open.lock().unwrap();
+            if let Some(stream) = open.streams.get(&id) {
+                let _ = turn_away(stream, Response::new(503).with_header("Retry-After", "1"), &mut open.drainer);
+            }
+        }
+        //Dropping the job closes the connection and takes it off of the open list.
+        drop(job.into_job());
+    }
+}
+
+//Serves the pool's metrics on one path and hands every other request to the real handler.
+struct Metrics {
+    path: String,
+    monitor: PoolMonitor,
+    handler: Arc<dyn Handler>,
+}
+
+impl Handler for Metrics {
+    fn handle(&self, request: Request) -> Response {
+        let wanted = match request.method {
+            Method::Get | Method::Head => request.path() == self.path,
+            _ => false,
+        };
+        if !wanted {
+            return self.handler.handle(request);
+        }
+        match self.monitor.metrics() {
+            Some(metrics) => Response::ok()
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(metrics.to_prometheus("mt_server_pool")),
+            None => Response::new(503),
+        }
+    }
+}
+
+//Takes a waiting connection off of a non-blocking listener, if there is one.
+fn accept(listener: &TcpListener) -> io::Result<Option<TcpStream>> {
+    match listener.accept() {
+        Ok((stream, _)) => Ok(Some(stream)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+//Answers a connection we have no room for with `response` and closes it.
+pub(crate) fn turn_away(stream: &TcpStream, response: Response, drainer: &mut Drainer) -> io::Result<()> {
+    let mut response = response.with_header("Connection", "close");
+    //This runs on the accept thread or the event loop, neither of which can wait on a client.
+    //A new connection has plenty of room for a short answer, and if it doesn't, too bad.
+    stream.set_nonblocking(true)?;
+    response.write_to(&mut &*stream)?;
+    stream.shutdown(Shutdown::Write)?;
+    drainer.drain(stream.try_clone()?);
+    Ok(())
+}
+
+//How many turned away connections get drained at once. Any more are simply closed.
+const MAX_DRAINING: usize = 64;
+//How long a turned away client gets to finish sending before its connection is closed.
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+//Closing a socket that still has unread request bytes makes the OS send a reset, which can
+//throw away the response before the client reads it. So whatever a turned away client still
+//sends is read and thrown away until it closes its end, but on a thread of its own, since
+//the accept thread and the event loop can't wait on a client we have no room for.
+#[derive(Default)]
+pub(crate) struct Drainer {
+    //The thread is only started once somebody is turned away. It stops when this is dropped.
+    sender: Option<SyncSender<TcpStream>>,
+}
+
+impl Drainer {
+    fn drain(&mut self, stream: TcpStream) {
+        if self.sender.is_none() {
+            let (sender, receiver) = mpsc::sync_channel(MAX_DRAINING);
+            match thread::Builder::new().name("http-drainer".to_string()).spawn(move || drain(&receiver)) {
+                Ok(_) => self.sender = Some(sender),
+                Err(e) => println!("Can't start the drainer thread: {}", e),
+            }
+        }
+        //When it's already busy with plenty of others this one is closed right away.
+        if let Some(ref sender) = self.sender {
+            let _ = sender.try_send(stream);
+        }
+    }
+}
+
+fn drain(incoming: &Receiver<TcpStream>) {
+    let mut draining: Vec<(TcpStream, Instant)> = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        let next = if draining.is_empty() {
+            incoming.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            incoming.recv_timeout(POLL_INTERVAL)
+        };
+        match next {
+            Ok(stream) => {
+                if draining.len() < MAX_DRAINING && stream.set_nonblocking(true).is_ok() {
+                    draining.push((stream, Instant::now() + DRAIN_TIME));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) if draining.is_empty() => return,
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+        }
+        let now = Instant::now();
+        draining.retain(|&(ref stream, until)| now < until && still_sending(stream, &mut buf));
+    }
+}
+
+//Reads what's there without waiting for more. False once the client is done or gone.
+fn still_sending(mut stream: &TcpStream, buf: &mut [u8]) -> bool {
+    loop {
+        match stream.read(buf) {
+            Ok(0) => return false,
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+}
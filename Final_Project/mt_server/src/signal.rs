@@ -0,0 +1,44 @@
+//Pressing Ctrl-C used to just kill the process in the middle of whatever the workers
+//were doing. Here we catch SIGINT and SIGTERM instead and only remember that they
+//happened. Very little is allowed inside of a signal handler, so the server checks
+//`received()` from its accept loop and does the actual shutting down itself.
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// Catch SIGINT and SIGTERM from now on instead of letting them end the process.
+///
+/// This is process wide, so every caller shares the same flag.
+#[cfg(unix)]
+pub fn install() -> io::Result<()> {
+    extern "C" fn on_signal(_: libc::c_int) {
+        //Storing to an atomic is one of the few things that's safe in here.
+        RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    for &signum in &[libc::SIGINT, libc::SIGTERM] {
+        //sigaction rather than signal() so the handler stays installed after the first signal
+        //and blocking calls get restarted instead of failing with EINTR.
+        unsafe {
+            let mut action: libc::sigaction = ::std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signum, &action, ::std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "signal handling is only supported on unix"))
+}
+
+/// Whether SIGINT or SIGTERM has arrived since `install` was called.
+pub fn received() -> bool {
+    RECEIVED.load(Ordering::SeqCst)
+}
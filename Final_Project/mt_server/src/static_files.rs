@@ -0,0 +1,543 @@
+//Serving files used to mean File::open("hello.html").unwrap() relative to wherever the
+//binary happened to be started. StaticFiles serves everything under a document root
+//instead. A request path can never leave that root, neither with ../ segments nor by
+//following a symlink that points somewhere else, and browsers get the usual caching
+//(ETag/Last-Modified and 304 Not Modified) and Range support for resuming large downloads.
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use date::{format_http_date, parse_http_date};
+use mime;
+use request::{percent_decode, Method, Request};
+use response::Response;
+use router::Handler;
+
+/// A handler that serves files from a document root.
+///
+/// The file is looked up from the `path` route parameter when there is one, so
+/// `router.get("/static/*path", files)` serves the root under `/static/`. Otherwise the
+/// whole request path is used.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+    listing: bool,
+    not_found: Box<dyn Handler>,
+}
+
+impl StaticFiles {
+    /// Serve files from `root`, which has to be an existing directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<StaticFiles> {
+        //Everything we serve gets compared against the canonical root so symlinks can't escape it.
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "document root is not a directory"));
+        }
+        Ok(StaticFiles {
+            root,
+            index: Some("index.html".to_string()),
+            listing: false,
+            not_found: Box::new(|_| Response::not_found().with_body("Not Found")),
+        })
+    }
+
+    /// The file served for a request to a directory. Defaults to `index.html`.
+    pub fn index<S: Into<String>>(mut self, name: S) -> StaticFiles {
+        self.index = Some(name.into());
+        self
+    }
+
+    /// Don't look for an index file in directories.
+    pub fn no_index(mut self) -> StaticFiles {
+        self.index = None;
+        self
+    }
+
+    /// Whether directories without an index file get an HTML listing of their
+    /// contents. Off by default.
+    pub fn listing(mut self, enabled: bool) -> StaticFiles {
+        self.listing = enabled;
+        self
+    }
+
+    /// Handler used when the file doesn't exist. Defaults to a plain 404.
+    pub fn not_found<H: Handler>(mut self, handler: H) -> StaticFiles {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Map a decoded url path onto the file system.
+    ///
+    /// Returns `Err` with the response to send when the path tries to leave the root.
+    fn resolve(&self, path: &str) -> Result<PathBuf, Response> {
+        let mut resolved = self.root.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(Response::new(403).with_body("Forbidden")),
+                //A backslash is a separator on Windows and a NUL byte would cut the path short.
+                s if s.contains('\\') || s.contains('\0') => {
+                    return Err(Response::new(400).with_body("Bad Request"));
+                }
+                s => resolved.push(s),
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn serve(&self, request: Request, path: &str) -> Response {
+        if request.method != Method::Get && request.method != Method::Head {
+            return Response::new(405).with_header("Allow", "GET, HEAD").with_body("Method Not Allowed");
+        }
+
+        let path = match self.resolve(path) {
+            Ok(path) => path,
+            Err(response) => return response,
+        };
+        //canonicalize follows every symlink so this is where escapes get caught.
+        let path = match fs::canonicalize(&path) {
+            Ok(path) => path,
+            Err(_) => return self.not_found.handle(request),
+        };
+        if !path.starts_with(&self.root) {
+            return Response::new(403).with_body("Forbidden");
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return self.not_found.handle(request),
+        };
+
+        if !metadata.is_dir() {
+            return file_response(&request, &path, &metadata);
+        }
+
+        //Relative links inside of the page only work if the directory url ends with a slash.
+        if !request.path().ends_with('/') {
+            let mut location = format!("{}/", request.path());
+            if let Some(query) = request.query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Response::new(301).with_header("Location", location);
+        }
+        if let Some(ref index) = self.index {
+            let index_path = path.join(index);
+            if let Ok(metadata) = fs::metadata(&index_path) {
+                if metadata.is_file() {
+                    return file_response(&request, &index_path, &metadata);
+                }
+            }
+        }
+        if self.listing {
+            return match directory_listing(request.path(), &path) {
+                Ok(page) => Response::ok()
+                    .with_header("Content-Type", "text/html; charset=utf-8")
+                    .with_body(page),
+                Err(_) => Response::new(500).with_body("Internal Server Error"),
+            };
+        }
+        self.not_found.handle(request)
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: Request) -> Response {
+        //The router has already percent-decoded captures, the raw path we decode ourselves.
+        let path = match request.param("path") {
+            Some(path) => Some(path.to_string()),
+            None => percent_decode(request.path()),
+        };
+        match path {
+            Some(path) => self.serve(request, &path),
+            None => Response::new(400).with_body("Bad Request"),
+        }
+    }
+}
+
+/// A strong validator made of the file's size and modification time, the same
+/// ingredients most servers use.
+fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+//If-None-Match uses the weak comparison, so a W/ prefix on either side is ignored.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(|t| t.trim()).any(|t| {
+        t == "*" || t.trim_start_matches("W/") == etag.trim_start_matches("W/")
+    })
+}
+
+//HTTP dates only have whole seconds so the file's time is rounded down before comparing.
+fn whole_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    //When both are present If-None-Match wins.
+    if let Some(header) = request.headers.get("If-None-Match") {
+        return etag_matches(header, etag);
+    }
+    match (request.headers.get("If-Modified-Since").and_then(parse_http_date), modified) {
+        (Some(since), Some(modified)) => whole_seconds(modified) <= whole_seconds(since),
+        _ => false,
+    }
+}
+
+/// What the Range header asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Range {
+    /// No usable range, send the whole file.
+    Full,
+    /// A byte range with an inclusive end.
+    Partial(u64, u64),
+    /// A range that doesn't overlap the file. Maps to 416.
+    Unsatisfiable,
+}
+
+//Only a single range is supported. RFC 7233 lets us ignore anything we don't
+//understand (including multiple ranges) and just send the whole thing.
+fn parse_range(header: &str, len: u64) -> Range {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Range::Full,
+    };
+    let dash = match spec.find('-') {
+        Some(i) => i,
+        None => return Range::Full,
+    };
+    let (start, end) = (&spec[..dash], &spec[dash + 1..]);
+
+    if start.is_empty() {
+        //bytes=-N is the last N bytes
+        return match end.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if len == 0 => Range::Unsatisfiable,
+            Ok(n) => Range::Partial(len.saturating_sub(n), len - 1),
+            Err(_) => Range::Full,
+        };
+    }
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Range::Full,
+    };
+    let end = if end.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+            _ => return Range::Full,
+        }
+    };
+    if start >= len {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(start, end)
+    }
+}
+
+//If-Range makes the Range conditional: if the file changed since the client got its
+//first part, it gets the whole new file instead of a piece that doesn't fit.
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let header = match request.headers.get("If-Range") {
+        Some(header) => header.trim(),
+        None => return true,
+    };
+    if header.starts_with('"') {
+        return header == etag;
+    }
+    if header.starts_with("W/") {
+        //Weak validators can't be used for ranges.
+        return false;
+    }
+    match (parse_http_date(header), modified) {
+        (Some(date), Some(modified)) => whole_seconds(date) == whole_seconds(modified),
+        _ => false,
+    }
+}
+
+fn open_range(path: &Path, start: u64) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok(file)
+}
+
+fn file_response(request: &Request, path: &Path, metadata: &Metadata) -> Response {
+    let etag = etag(metadata);
+    let modified = metadata.modified().ok();
+    let len = metadata.len();
+
+    let mut response = Response::ok()
+        .with_header("ETag", etag.clone())
+        .with_header("Accept-Ranges", "bytes");
+    if let Some(modified) = modified {
+        response.headers.insert("Last-Modified", format_http_date(modified));
+    }
+
+    if not_modified(request, &etag, modified) {
+        response.status = 304;
+        return response;
+    }
+    response.headers.insert("Content-Type", mime::from_path(path));
+
+    let range = match request.headers.get("Range") {
+        Some(header) if if_range_matches(request, &etag, modified) => parse_range(header, len),
+        _ => Range::Full,
+    };
+    let (start, end) = match range {
+        Range::Full => (0, len.saturating_sub(1)),
+        Range::Partial(start, end) => {
+            response.status = 206;
+            response.headers.insert("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+            (start, end)
+        }
+        Range::Unsatisfiable => {
+            return Response::new(416)
+                .with_header("Content-Range", format!("bytes */{}", len))
+                .with_header("Accept-Ranges", "bytes");
+        }
+    };
+    let count = if len == 0 { 0 } else { end - start + 1 };
+
+    //The file is sent a piece at a time instead of being read into memory first.
+    match open_range(path, start) {
+        Ok(file) => response.with_reader(file, Some(count)),
+        Err(e) => {
+            println!("Failed to read {}: {}", path.display(), e);
+            Response::new(500).with_body("Internal Server Error")
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+//Just enough escaping to turn a file name into a single path segment of a link.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            b => write!(out, "%{:02X}", b).unwrap(),
+        }
+    }
+    out
+}
+
+fn directory_listing(url_path: &str, dir: &Path) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            //Names that aren't UTF-8 can't be put in a link anyway.
+            Err(_) => continue,
+        };
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        entries.push((name, is_dir));
+    }
+    entries.sort();
+
+    let title = escape_html(url_path);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n  <head>\n    <meta charset=\"utf-8\">\n    \
+         <title>Index of {0}</title>\n  </head>\n  <body>\n    <h1>Index of {0}</h1>\n    <ul>\n",
+        title
+    );
+    if url_path != "/" {
+        page.push_str("      <li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        writeln!(
+            page,
+            "      <li><a href=\"{}{}\">{}{}</a></li>",
+            percent_encode(&name),
+            slash,
+            escape_html(&name),
+            slash
+        ).unwrap();
+    }
+    page.push_str("    </ul>\n  </body>\n</html>\n");
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    use request::Limits;
+    use response::Body;
+    use test_util::TempDir;
+
+    //A scratch document root, with a file outside of it that must never be served.
+    struct TempRoot(TempDir);
+
+    impl TempRoot {
+        fn new() -> TempRoot {
+            let dir = TempDir::new("static");
+            fs::create_dir_all(dir.join("public/docs")).unwrap();
+            fs::write(dir.join("public/index.html"), "<h1>home</h1>").unwrap();
+            fs::write(dir.join("public/docs/a b.txt"), "0123456789").unwrap();
+            fs::write(dir.join("secret.txt"), "top secret").unwrap();
+            TempRoot(dir)
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(self.0.join("public")).unwrap()
+        }
+    }
+
+    fn get(files: &StaticFiles, target: &str, headers: &str) -> Response {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: test\r\n{}\r\n", target, headers);
+        let mut response = files.handle(Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0);
+        //Files are streamed, so we read them in here to compare them.
+        let body = mem::take(&mut response.body).into_bytes().unwrap();
+        response.body = Body::Bytes(body);
+        response
+    }
+
+    #[test]
+    fn serves_files_and_index_pages() {
+        let root = TempRoot::new();
+        let files = root.files();
+
+        let response = get(&files, "/", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"<h1>home</h1>");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+
+        let response = get(&files, "/docs/a%20b.txt?v=1", "");
+        assert_eq!(response.body, b"0123456789");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain; charset=utf-8"));
+
+        let response = get(&files, "/docs?x=1", "");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.headers.get("Location"), Some("/docs/?x=1"));
+
+        assert_eq!(get(&files, "/missing.html", "").status, 404);
+        //No index and no listing
+        assert_eq!(get(&files, "/docs/", "").status, 404);
+    }
+
+    #[test]
+    fn lists_directories_when_enabled() {
+        let root = TempRoot::new();
+        let files = root.files().listing(true);
+
+        let response = get(&files, "/docs/", "");
+        assert_eq!(response.status, 200);
+        let page = String::from_utf8(response.body.into_bytes().unwrap()).unwrap();
+        assert!(page.contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+        assert!(page.contains("<a href=\"../\">"));
+    }
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        let root = TempRoot::new();
+        let files = root.files();
+
+        assert_eq!(get(&files, "/../secret.txt", "").status, 403);
+        assert_eq!(get(&files, "/docs/%2e%2e/%2e%2e/secret.txt", "").status, 403);
+        assert_eq!(get(&files, "/docs/..%2f..%2fsecret.txt", "").status, 403);
+        assert_eq!(get(&files, "/docs%5c..%5csecret.txt", "").status, 400);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let root = TempRoot::new();
+        symlink(root.0.join("secret.txt"), root.0.join("public/escape.txt")).unwrap();
+        symlink(root.0.join("public/index.html"), root.0.join("public/inside.html")).unwrap();
+        let files = root.files();
+
+        assert_eq!(get(&files, "/escape.txt", "").status, 403);
+        assert_eq!(get(&files, "/inside.html", "").status, 200);
+    }
+
+    #[test]
+    fn answers_conditional_requests_with_304() {
+        let root = TempRoot::new();
+        let files = root.files();
+
+        let first = get(&files, "/index.html", "");
+        let etag = first.headers.get("ETag").unwrap().to_string();
+        let modified = first.headers.get("Last-Modified").unwrap().to_string();
+
+        let response = get(&files, "/index.html", &format!("If-None-Match: \"nope\", {}\r\n", etag));
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+
+        let response = get(&files, "/index.html", &format!("If-Modified-Since: {}\r\n", modified));
+        assert_eq!(response.status, 304);
+
+        let response = get(&files, "/index.html", "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n");
+        assert_eq!(response.status, 200);
+        //If-None-Match takes precedence over the date
+        let response = get(
+            &files,
+            "/index.html",
+            &format!("If-None-Match: \"nope\"\r\nIf-Modified-Since: {}\r\n", modified),
+        );
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn serves_byte_ranges() {
+        let root = TempRoot::new();
+        let files = root.files();
+        let path = "/docs/a%20b.txt";
+
+        let response = get(&files, path, "Range: bytes=2-5\r\n");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, b"2345");
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-5/10"));
+
+        assert_eq!(get(&files, path, "Range: bytes=7-\r\n").body, b"789");
+        assert_eq!(get(&files, path, "Range: bytes=-3\r\n").body, b"789");
+        assert_eq!(get(&files, path, "Range: bytes=8-100\r\n").body, b"89");
+
+        let response = get(&files, path, "Range: bytes=10-\r\n");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */10"));
+
+        //Multiple ranges and If-Range mismatches fall back to the whole file.
+        assert_eq!(get(&files, path, "Range: bytes=0-1,4-5\r\n").status, 200);
+        let response = get(&files, path, "Range: bytes=0-1\r\nIf-Range: \"stale\"\r\n");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"0123456789");
+
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        let response = get(&files, path, &format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", etag));
+        assert_eq!(response.body, b"01");
+    }
+}
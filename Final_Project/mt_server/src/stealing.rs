@@ -0,0 +1,236 @@
+//With the shared queue every worker has to take the same lock to get a job, so when
+//there are lots of tiny jobs the workers spend more time waiting on each other than
+//working. Here every worker gets a queue of its own instead. execute deals jobs out to
+//the queues in turn, a worker takes jobs from the front of its own queue, and a worker
+//whose queue is empty steals from the front of the others before going to sleep.
+//
+//Workers take from the front of their own queue as well (instead of the back like a
+//classic work-stealing deque) because our jobs are requests, and the one that's been
+//waiting the longest should go first.
+//
+//Every queue has its lanes, one per priority. A worker looks through the most urgent lane
+//of every queue before it moves on to the next lane, so a High job waiting in a busy
+//worker's queue is stolen before anyone starts a Normal one. Counting the messages in each
+//lane lets it skip the lanes that are empty everywhere without taking all of the locks.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use queue::{Lanes, Priority, QueuePolicy, LEVELS, TERMINATE};
+use {FnBox, Message};
+
+pub(crate) struct StealingQueue {
+    locals: Vec<Mutex<Lanes>>,
+    //Where the next job goes.
+    next: AtomicUsize,
+    //Messages in each lane across all of the queues. Bumped before the message goes in, with
+    //the queue's lock held, so it can't drop below zero when it's taken right away.
+    queued: [AtomicUsize; LEVELS + 1],
+    starvation_limit: Duration,
+    //Messages that are in a queue or about to be put in one. Reserving a spot here before
+    //pushing is how the capacity is enforced without one big lock.
+    pending: AtomicUsize,
+    capacity: Option<usize>,
+    //Workers with nothing to do sleep on `wake`, and callers blocked on a full queue on `not_full`.
+    //The Mutexes don't guard any data, they're only there for the Condvars.
+    sleepers: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    blocked: AtomicUsize,
+    space: Mutex<()>,
+    not_full: Condvar,
+}
+
+//Same as the shared queue, a poisoned lock still holds a good queue.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl StealingQueue {
+    pub(crate) fn new(workers: usize, capacity: Option<usize>, starvation_limit: Duration) -> StealingQueue {
+        StealingQueue {
+            locals: (0..workers).map(|_| Mutex::new(Lanes::new())).collect(),
+            next: AtomicUsize::new(0),
+            queued: Default::default(),
+            starvation_limit,
+            pending: AtomicUsize::new(0),
+            capacity,
+            sleepers: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            blocked: AtomicUsize::new(0),
+            space: Mutex::new(()),
+            not_full: Condvar::new(),
+        }
+    }
+
+    //Try to claim a spot for one more message.
+    fn reserve(&self) -> bool {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => {
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+        let mut pending = self.pending.load(Ordering::SeqCst);
+        while pending < capacity {
+            match self.pending.compare_exchange(pending, pending + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(actual) => pending = actual,
+            }
+        }
+        false
+    }
+
+    //Throw away the oldest job in the least urgent lane that has one, from the first queue
+    //that has one. Returns false if there are no jobs at all.
+    fn drop_oldest(&self) -> bool {
+        let start = self.next.load(Ordering::Relaxed);
+        for lane in (0..LEVELS).rev() {
+            if self.queued[lane].load(Ordering::SeqCst) == 0 {
+                continue;
+            }
+            for i in 0..self.locals.len() {
+                let local = &self.locals[(start + i) % self.locals.len()];
+                if lock(local).pop_lane(lane).is_some() {
+                    self.taken(lane);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    pub(crate) fn push<J>(&self, job: J, priority: Priority, policy: QueuePolicy, wait: bool) -> Result<(), J>
+        where
+            J: FnBox + Send + 'static
+    {
+        while !self.reserve() {
+            match policy {
+                QueuePolicy::Block if wait => {
+                    //The same handshake as a sleeping worker in `pop`, just with `taken`.
+                    let space = lock(&self.space);
+                    self.blocked.fetch_add(1, Ordering::SeqCst);
+                    if self.capacity.is_some_and(|c| self.pending.load(Ordering::SeqCst) >= c) {
+                        drop(self.not_full.wait(space).unwrap_or_else(PoisonError::into_inner));
+                    }
+                    self.blocked.fetch_sub(1, Ordering::SeqCst);
+                }
+                QueuePolicy::Block | QueuePolicy::Reject => return Err(job),
+                QueuePolicy::DropOldest => {
+                    if !self.drop_oldest() {
+                        //Only terminate messages left, they don't really take up room.
+                        self.pending.fetch_add(1, Ordering::SeqCst);
+                        break;
+                    }
+                }
+            }
+        }
+        let message = Message::NewJob(Box::new(job), Instant::now());
+        self.put(Lanes::lane_of(&message, priority), message);
+        Ok(())
+    }
+
+    pub(crate) fn push_unbounded(&self, message: Message) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.put(Lanes::lane_of(&message, Priority::Normal), message);
+    }
+
+    //Hand a message that already has its spot reserved to the next worker in line.
+    fn put(&self, lane: usize, message: Message) {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.locals.len();
+        {
+            let mut local = lock(&self.locals[i]);
+            self.queued[lane].fetch_add(1, Ordering::SeqCst);
+            local.push(lane, message);
+        }
+        //Only take the sleep lock when someone might be asleep, otherwise this would be
+        //the same single point of contention we're trying to get away from.
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _sleep = lock(&self.sleep);
+            self.wake.notify_one();
+        }
+    }
+
+    //Called whenever a message leaves one of the queues.
+    fn taken(&self, lane: usize) {
+        self.queued[lane].fetch_sub(1, Ordering::SeqCst);
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _space = lock(&self.space);
+            self.not_full.notify_one();
+        }
+    }
+
+    fn find(&self, worker: usize) -> Option<Message> {
+        //Workers come and go when the pool resizes so ids can be larger than the number of queues.
+        let count = self.locals.len();
+        //Our own queue first, then everyone else's starting with our neighbour.
+        let queues = || (0..count).map(move |i| &self.locals[(worker + i) % count]);
+
+        //A job can only be starved by more urgent ones if more than one lane has jobs.
+        let busy = (0..LEVELS).filter(|&lane| self.queued[lane].load(Ordering::SeqCst) > 0).count();
+        if busy > 1 {
+            let now = Instant::now();
+            for local in queues() {
+                let mut local = lock(local);
+                if let Some(lane) = local.starved(self.starvation_limit, now) {
+                    let message = local.pop_lane(lane);
+                    self.taken(lane);
+                    return message;
+                }
+            }
+        }
+        for lane in 0..=TERMINATE {
+            if self.queued[lane].load(Ordering::SeqCst) == 0 {
+                continue;
+            }
+            for local in queues() {
+                if let Some(message) = lock(local).pop_lane(lane) {
+                    self.taken(lane);
+                    return Some(message);
+                }
+            }
+        }
+        None
+    }
+
+    pub(crate) fn pop(&self, worker: usize, deadline: Option<Instant>) -> Option<Message> {
+        loop {
+            if let Some(message) = self.find(worker) {
+                return Some(message);
+            }
+
+            let sleep = lock(&self.sleep);
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            //`put` bumps pending before it checks for sleepers and we did it the other way
+            //around, so at least one of us sees the other and the job can't be missed.
+            let pending = self.pending.load(Ordering::SeqCst);
+            if pending == 0 {
+                match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                            return None;
+                        }
+                        drop(self.wake.wait_timeout(sleep, deadline - now).unwrap_or_else(PoisonError::into_inner));
+                    }
+                    None => drop(self.wake.wait(sleep).unwrap_or_else(PoisonError::into_inner)),
+                }
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            } else {
+                //A job has its spot reserved but isn't in a queue yet, it will be in a moment.
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                drop(sleep);
+                thread::yield_now();
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
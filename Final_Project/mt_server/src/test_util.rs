@@ -0,0 +1,37 @@
+//Several unit tests need real files on disk: a document root, a config file, a log to rotate.
+//They each get a scratch directory of their own that's gone again once the test is over.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory under the system's temp dir, removed when this is dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` ends up in the directory's name, so leftovers tell which tests made them.
+    pub(crate) fn new(name: &str) -> TempDir {
+        let n = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("mt_server_{}_{}_{}", name, process::id(), n));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
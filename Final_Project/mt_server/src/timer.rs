@@ -0,0 +1,277 @@
+//execute runs a job as soon as a worker is free. For things like expiring a cache or flushing
+//a log every so often we'd otherwise need a thread of our own that sleeps in a loop, so the
+//pool gets a timer thread instead. It keeps the jobs that aren't due yet in a heap ordered by
+//when they're due, sleeps until the first one is, and then queues it like execute would. The
+//jobs themselves still run on the workers.
+//
+//The timer thread is only started once something is scheduled, and it only holds on to the
+//pool weakly, so a pool nobody schedules anything on looks just like it did before.
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {ExecuteError, Inner, Priority, QueuePolicy, ThreadPool};
+
+//How long a delayed job that didn't fit into a full queue waits before it tries again.
+const RETRY: Duration = Duration::from_millis(10);
+
+/// Stops a job queued with `ThreadPool::execute_after` or `ThreadPool::execute_every`.
+///
+/// A job that's cancelled before it starts never runs, and a repeating job doesn't run
+/// again after it's cancelled. A run that has already started finishes.
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    fn new() -> CancelToken {
+        CancelToken { cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) enum Task {
+    Once(Box<dyn FnOnce() + Send>),
+    Every {
+        interval: Duration,
+        job: Arc<Mutex<dyn FnMut() + Send>>,
+        //Set while a run is queued or running, so a slow job doesn't pile up runs.
+        running: Arc<AtomicBool>,
+    },
+}
+
+impl Task {
+    pub(crate) fn once<F: FnOnce() + Send + 'static>(f: F) -> Task {
+        Task::Once(Box::new(f))
+    }
+
+    pub(crate) fn every<F: FnMut() + Send + 'static>(interval: Duration, f: F) -> Task {
+        Task::Every {
+            interval,
+            job: Arc::new(Mutex::new(f)),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+struct Entry {
+    at: Instant,
+    //Entries due at the same time go in the order they were scheduled.
+    seq: u64,
+    token: CancelToken,
+    task: Task,
+}
+
+//BinaryHeap hands out the largest entry first, so the earliest one has to compare as the largest.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> CmpOrdering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    //Rung when something is scheduled that may be due before what the thread is waiting for,
+    //and when it's time to stop.
+    wake: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        //Jobs never run while this lock is held, so nothing can poison it that we'd care about.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, state: &mut State, at: Instant, token: CancelToken, task: Task) {
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry { at, seq, token, task });
+        self.wake.notify_one();
+    }
+}
+
+/// The pool's timer thread.
+pub(crate) struct Timer {
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    pub(crate) fn start(pool: Weak<Inner>, policy: QueuePolicy, name: String) -> io::Result<Timer> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { entries: BinaryHeap::new(), next_seq: 0, stopped: false }),
+            wake: Condvar::new(),
+        });
+        let timer = Arc::clone(&shared);
+        let thread = thread::Builder::new().name(name).spawn(move || run(&timer, &pool, policy))?;
+        Ok(Timer { shared, thread: Some(thread) })
+    }
+
+    /// Queue `task` on the pool once `at` comes around.
+    pub(crate) fn schedule(&self, at: Instant, task: Task) -> CancelToken {
+        let token = CancelToken::new();
+        let mut state = self.shared.lock();
+        self.shared.push(&mut state, at, token.clone(), task);
+        token
+    }
+
+    /// Stop the timer thread and wait for it. Whatever wasn't due yet is thrown away.
+    pub(crate) fn stop(mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(shared: &Shared, pool: &Weak<Inner>, policy: QueuePolicy) {
+    let mut state = shared.lock();
+    loop {
+        if state.stopped {
+            return;
+        }
+        let now = Instant::now();
+        let wait = match state.entries.peek() {
+            None => None,
+            //Cancelled jobs are only dropped once they come up, cancel doesn't know where they are.
+            Some(entry) if entry.token.is_cancelled() => Some(Duration::from_secs(0)),
+            Some(entry) => Some(entry.at.saturating_duration_since(now)),
+        };
+        match wait {
+            None => state = shared.wake.wait(state).unwrap_or_else(PoisonError::into_inner),
+            Some(wait) if wait > Duration::from_secs(0) => {
+                state = shared.wake.wait_timeout(state, wait).unwrap_or_else(PoisonError::into_inner).0;
+            }
+            Some(_) => {
+                let entry = state.entries.pop().unwrap();
+                if entry.token.is_cancelled() {
+                    continue;
+                }
+                //Queueing can take the pool's locks, so ours is let go of first.
+                drop(state);
+                let pool = match pool.upgrade() {
+                    Some(pool) => pool,
+                    None => return,
+                };
+                let again = fire(&pool, policy, entry, now);
+                drop(pool);
+                state = shared.lock();
+                if let Some(entry) = again {
+                    shared.push(&mut state, entry.at, entry.token, entry.task);
+                }
+            }
+        }
+    }
+}
+
+//Queues a job that's due and hands back the entry for its next run, if there is one.
+fn fire(pool: &Arc<Inner>, policy: QueuePolicy, entry: Entry, now: Instant) -> Option<Entry> {
+    let Entry { at, token, task, .. } = entry;
+    match task {
+        Task::Once(job) => {
+            //It can still be cancelled while it waits in the queue.
+            let check = token.clone();
+            let job = move || {
+                if !check.is_cancelled() {
+                    job()
+                }
+            };
+            //Waiting for room would hold up every other job on the timer, so a full queue
+            //just means trying again in a little while.
+            match ThreadPool::submit_to(pool, policy, job, Priority::Normal, false) {
+                Ok(()) => None,
+                Err(ExecuteError::Full(job)) => Some(Entry { at: now + RETRY, seq: 0, token, task: Task::once(job) }),
+                Err(ExecuteError::ShuttingDown(_)) => None,
+            }
+        }
+        Task::Every { interval, job, running } => {
+            if running.swap(true, Ordering::SeqCst) {
+                println!("A repeating job is still running; skipping this run.");
+            } else {
+                //Made out here so a run that's dropped without running, because the queue was
+                //full, it was pushed out of it or the pool shut down, lets go of `running` too.
+                let (check, done) = (token.clone(), Done(Arc::clone(&running)));
+                let job = Arc::clone(&job);
+                let run = move || {
+                    let _done = done;
+                    if !check.is_cancelled() {
+                        (*job.lock().unwrap_or_else(PoisonError::into_inner))()
+                    }
+                };
+                match ThreadPool::submit_to(pool, policy, run, Priority::Normal, false) {
+                    Ok(()) => {}
+                    //A run that doesn't fit is skipped like one that's still going. Dropping it
+                    //lets go of `running` again.
+                    Err(ExecuteError::Full(_)) => println!("The queue is full; skipping a run of a repeating job."),
+                    Err(ExecuteError::ShuttingDown(_)) => return None,
+                }
+            }
+            //Runs stay on the schedule they started with. Ones that were missed entirely,
+            //because the timer was held up, are skipped instead of all run at once.
+            let mut next = at + interval;
+            if next <= now {
+                next = now + interval;
+            }
+            Some(Entry { at: next, seq: 0, token, task: Task::Every { interval, job, running } })
+        }
+    }
+}
+
+//Lets the timer know the run is over, even if it panicked.
+struct Done(Arc<AtomicBool>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn earliest_entries_come_out_first() {
+        let now = Instant::now();
+        let mut heap = BinaryHeap::new();
+        for (seq, ms) in [(0, 30), (1, 10), (2, 20), (3, 10)].iter().cloned() {
+            let at = now + Duration::from_millis(ms);
+            heap.push(Entry { at, seq, token: CancelToken::new(), task: Task::once(|| {}) });
+        }
+        let order: Vec<u64> = (0..4).map(|_| heap.pop().unwrap().seq).collect();
+        assert_eq!(order, vec![1, 3, 2, 0]);
+    }
+}
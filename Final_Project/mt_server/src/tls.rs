@@ -0,0 +1,108 @@
+//Everything used to go over plain TCP. A TlsConfig holds a certificate chain and its private
+//key (read from the usual PEM files) and lets the Server run an HTTPS listener next to the
+//plain one. The handshake is done by rustls on the worker that got the connection, the first
+//time it reads from it, so a slow client only ties up its own worker. After that the session
+//is just another stream the connection loop reads requests from and writes responses to.
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use access_log::AccessLog;
+use connection::{self, ConnectionConfig, Socket};
+use router::Handler;
+
+/// The certificate and settings an HTTPS listener uses.
+///
+/// Only HTTP/1.1 is offered through ALPN since that's all we speak. Cheap to clone.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Read the certificate chain and private key from PEM files. The certificate file
+    /// starts with our own certificate followed by any intermediates.
+    pub fn from_pem_files<C: AsRef<Path>, K: AsRef<Path>>(cert: C, key: K) -> io::Result<TlsConfig> {
+        let certs = CertificateDer::pem_file_iter(cert.as_ref())
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(format!("can't read certificates from {}: {}", cert.as_ref().display(), e)))?;
+        let key = PrivateKeyDer::from_pem_file(key.as_ref())
+            .map_err(|e| invalid(format!("can't read a private key from {}: {}", key.as_ref().display(), e)))?;
+        TlsConfig::new(certs, key)
+    }
+
+    /// Like `from_pem_files` for PEM data that's already in memory.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> io::Result<TlsConfig> {
+        let certs = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("can't read certificates: {}", e)))?;
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| invalid(format!("can't read a private key: {}", e)))?;
+        TlsConfig::new(certs, key)
+    }
+
+    fn new(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<TlsConfig> {
+        if certs.is_empty() {
+            return Err(invalid("no certificates found".to_string()));
+        }
+        //We pick the provider ourselves rather than relying on a process wide default being installed.
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| invalid(e.to_string()))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsConfig { config: Arc::new(config) })
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish()
+    }
+}
+
+impl Socket for StreamOwned<ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Like `connection::serve_logged`, but `stream` is an HTTPS connection that still needs
+/// its handshake.
+pub fn serve_logged<H: Handler + ?Sized>(
+    stream: TcpStream,
+    tls: &TlsConfig,
+    handler: &H,
+    config: &ConnectionConfig,
+    log: Option<&dyn AccessLog>,
+) -> io::Result<()> {
+    //The idle timeout covers the handshake too, so a client can't just connect and sit there.
+    stream.set_read_timeout(config.idle_timeout)?;
+    let peer = stream.peer_addr().ok();
+    let session = ServerConnection::new(Arc::clone(&tls.config)).map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(session, stream);
+    let served = match connection::serve_stream(&mut stream, peer, handler, config, log) {
+        //Lots of clients just close the socket when they're done instead of saying goodbye
+        //first. That only matters to a client in the middle of reading a response.
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+        served => served,
+    };
+
+    //Let the client know the response really ended here rather than being cut off. The
+    //connection may be broken already, in which case there's nobody to tell.
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+    served
+}
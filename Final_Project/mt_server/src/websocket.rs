@@ -0,0 +1,576 @@
+//Dashboards want to be told when something changes rather than asking over and over, which a
+//plain request and response can't do. A WebSocket (RFC 6455) starts out as a GET with an
+//Upgrade: websocket header. WebSocketHandler answers it with 101 Switching Protocols, and
+//from then on the connection carries messages both ways in frames instead of HTTP.
+//
+//The upgraded connection stays on the worker that answered the handshake, the same way a
+//keep-alive connection does in the blocking mode, and the handler's function gets a
+//WebSocket to receive and send messages on. Pings are answered, fragmented messages are put
+//back together and the closing handshake is done without the handler having to care. A
+//client that breaks the protocol gets a Close frame with the matching status code.
+//
+//Frames are parsed out of a buffer like requests are, so a read that times out in the
+//middle of one loses nothing and recv_timeout can be used to push updates in between
+//messages from the client.
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1_smol::Sha1;
+
+use connection::Upgraded;
+use request::{Method, Request, Version};
+use response::{Response, Status};
+use router::Handler;
+
+//Mixed into the client's key to show the handshake was understood (RFC 6455 section 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Status codes sent in a Close frame.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+/// A whole message, however many frames it came in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    pub fn text<S: Into<String>>(text: S) -> Message {
+        Message::Text(text.into())
+    }
+
+    pub fn binary<B: Into<Vec<u8>>>(bytes: B) -> Message {
+        Message::Binary(bytes.into())
+    }
+}
+
+/// A handler that turns WebSocket handshakes into connections for `F`.
+///
+/// `F` is called with the handshake request and the WebSocket, and the connection is
+/// closed when it returns. Requests that aren't a valid handshake get a 4xx response.
+pub struct WebSocketHandler<F> {
+    handler: Arc<F>,
+    max_message: usize,
+}
+
+impl<F> WebSocketHandler<F>
+    where
+        F: Fn(Request, WebSocket) + Send + Sync + 'static
+{
+    pub fn new(handler: F) -> WebSocketHandler<F> {
+        WebSocketHandler {
+            handler: Arc::new(handler),
+            max_message: 1024 * 1024,
+        }
+    }
+
+    /// The biggest message a client may send, in bytes. Bigger ones close the connection
+    /// with 1009 Message Too Big. 1 MiB by default.
+    pub fn max_message_size(mut self, bytes: usize) -> WebSocketHandler<F> {
+        self.max_message = bytes;
+        self
+    }
+}
+
+impl<F> Handler for WebSocketHandler<F>
+    where
+        F: Fn(Request, WebSocket) + Send + Sync + 'static
+{
+    fn handle(&self, request: Request) -> Response {
+        let accept = match handshake(&request) {
+            Ok(accept) => accept,
+            Err(response) => return response,
+        };
+        let handler = Arc::clone(&self.handler);
+        let max_message = self.max_message;
+        Response::from_status(Status::SwitchingProtocols)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", accept)
+            .with_upgrade(move |upgraded| handler(request, WebSocket::new(upgraded, max_message)))
+    }
+}
+
+/// Checks that `request` is a WebSocket handshake and works out the Sec-WebSocket-Accept
+/// to answer it with, or the response to turn it down with.
+pub fn handshake(request: &Request) -> Result<String, Response> {
+    if request.method != Method::Get {
+        return Err(Response::from_status(Status::MethodNotAllowed).with_header("Allow", "GET"));
+    }
+    let upgrade = request.version == Version::Http11
+        && request.headers.has_token("Upgrade", "websocket")
+        && request.headers.has_token("Connection", "Upgrade");
+    if !upgrade {
+        return Err(Response::from_status(Status::UpgradeRequired)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_body("This is a WebSocket endpoint"));
+    }
+    if request.headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::from_status(Status::UpgradeRequired).with_header("Sec-WebSocket-Version", "13"));
+    }
+    //The key is 16 random bytes in base64. We don't care what they are, only that they're there.
+    let key = request.headers.get("Sec-WebSocket-Key").map(str::trim).unwrap_or("");
+    match BASE64.decode(key) {
+        Ok(ref bytes) if bytes.len() == 16 => Ok(accept_key(key)),
+        _ => Err(Response::from_status(Status::BadRequest).with_body("Bad Sec-WebSocket-Key")),
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+//A frame the client broke the rules with, and the code we close the connection with for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Violation {
+    code: u16,
+    reason: &'static str,
+}
+
+fn violation(code: u16, reason: &'static str) -> Violation {
+    Violation { code, reason }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+//Takes one frame off the front of `buf` if all of it is there, returning it along with how
+//many bytes it took up.
+fn parse_frame(buf: &[u8], max_message: usize) -> Result<Option<(Frame, usize)>, Violation> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    //The RSV bits are for extensions, and we haven't agreed on any.
+    if buf[0] & 0x70 != 0 {
+        return Err(violation(close_code::PROTOCOL_ERROR, "reserved bits set"));
+    }
+    match opcode {
+        CONTINUATION | TEXT | BINARY | CLOSE | PING | PONG => {}
+        _ => return Err(violation(close_code::PROTOCOL_ERROR, "unknown opcode")),
+    }
+    if buf[1] & 0x80 == 0 {
+        return Err(violation(close_code::PROTOCOL_ERROR, "client frames have to be masked"));
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+        127 if buf.len() >= 10 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (u64::from(len), 2),
+    };
+    if opcode >= CLOSE && (!fin || len > 125) {
+        return Err(violation(close_code::PROTOCOL_ERROR, "control frames can't be fragmented or longer than 125 bytes"));
+    }
+    //Checked before waiting for the payload so nobody gets to make us buffer it.
+    if len > max_message as u64 {
+        return Err(violation(close_code::TOO_BIG, "message too big"));
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buf[pos..pos + 4]);
+    pos += 4;
+    let payload = buf[pos..pos + len].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+    Ok(Some((Frame { fin, opcode, payload }, pos + len)))
+}
+
+//Server frames go out in one piece and unmasked.
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+//The codes a client may send us. 1005 and 1006 only exist for APIs to report, never on the wire.
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// A WebSocket connection, handed to a `WebSocketHandler`'s function.
+///
+/// Dropping it closes the connection with 1000 Normal Closure if it's still open.
+pub struct WebSocket<'a> {
+    socket: Upgraded<'a>,
+    max_message: usize,
+    buf: Vec<u8>,
+    //The opcode and data of a message that's come in some of its frames so far.
+    partial: Option<(u8, Vec<u8>)>,
+    //The read timeout the socket has right now, so it's only changed when it has to be.
+    timeout: Option<Option<Duration>>,
+    closed: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    fn new(socket: Upgraded<'a>, max_message: usize) -> WebSocket<'a> {
+        WebSocket {
+            socket,
+            max_message,
+            buf: Vec::new(),
+            partial: None,
+            timeout: None,
+            closed: false,
+        }
+    }
+
+    /// Wait for the next message.
+    ///
+    /// Returns `Ok(None)` once the connection has been closed, by either side. An error
+    /// means the connection broke or the client broke the protocol, and it's closed too.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        self.recv_until(None)
+    }
+
+    /// Like `recv`, but gives up with a `TimedOut` error if no message has come in
+    /// after `timeout`. The connection stays open then, and a message that was only
+    /// partly in is picked up by the next call.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    /// An iterator over the messages until the connection closes.
+    pub fn messages<'b>(&'b mut self) -> Messages<'b, 'a> {
+        Messages { socket: self }
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match *message {
+            Message::Text(ref text) => self.send_frame(TEXT, text.as_bytes()),
+            Message::Binary(ref bytes) => self.send_frame(BINARY, bytes),
+        }
+    }
+
+    /// Send a ping, whose pong will be ignored. Handy to keep proxies from giving up on a
+    /// quiet connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `payload` is longer than 125 bytes, which is as much as a ping can carry.
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        assert!(payload.len() <= 125, "a ping can carry at most 125 bytes");
+        self.send_frame(PING, payload)
+    }
+
+    /// Start the closing handshake with `code` and wait a little while for the client to
+    /// answer it. Messages that arrive in the meantime are thrown away.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reason` is longer than 123 bytes, which is as much as fits after the code.
+    pub fn close(mut self, code: u16, reason: &str) -> io::Result<()> {
+        assert!(reason.len() <= 123, "a close reason can be at most 123 bytes");
+        self.send_close(code, reason)?;
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            match self.recv_until(Some(deadline)) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(()),
+                //The client doesn't have to answer, and it's all over either way.
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the WebSocket is closed"));
+        }
+        write_frame(&mut self.socket, opcode, payload)
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let sent = self.send_frame(CLOSE, &payload);
+        self.closed = true;
+        sent
+    }
+
+    //Closes the connection on a client that broke the rules, and turns that into an error.
+    fn fail(&mut self, violation: Violation) -> io::Error {
+        let _ = self.send_close(violation.code, violation.reason);
+        io::Error::new(io::ErrorKind::InvalidData, violation.reason)
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> io::Result<Option<Message>> {
+        let mut chunk = [0; 4096];
+        loop {
+            if self.closed {
+                return Ok(None);
+            }
+            let parsed = parse_frame(&self.buf, self.max_message);
+            match parsed {
+                Ok(Some((frame, used))) => {
+                    self.buf.drain(..used);
+                    match self.frame(frame) {
+                        Ok(Some(message)) => return Ok(Some(message)),
+                        Ok(None) => continue,
+                        Err(Ok(e)) => return Err(e),
+                        Err(Err(violation)) => return Err(self.fail(violation)),
+                    }
+                }
+                Ok(None) => {}
+                Err(violation) => return Err(self.fail(violation)),
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::from_secs(0) {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "no message before the timeout"));
+                    }
+                    Some(left)
+                }
+                None => None,
+            };
+            if self.timeout != Some(timeout) {
+                self.socket.set_read_timeout(timeout)?;
+                self.timeout = Some(timeout);
+            }
+            let n = match self.socket.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                //The deadline check above turns these into TimedOut.
+                Err(ref e) if deadline.is_some() && (e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e);
+                }
+            };
+            if n == 0 {
+                //Gone without a Close frame. There's nobody left to send one to either.
+                self.closed = true;
+                return Ok(None);
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    //Deals with one frame, giving back a message if that frame finished one. Writing
+    //errors and protocol violations are told apart since only the latter get a Close.
+    fn frame(&mut self, frame: Frame) -> Result<Option<Message>, Result<io::Error, Violation>> {
+        match frame.opcode {
+            PING => self.send_frame(PONG, &frame.payload).map(|_| None).map_err(Ok),
+            PONG => Ok(None),
+            CLOSE => {
+                let code = match frame.payload.len() {
+                    0 => None,
+                    1 => return Err(Err(violation(close_code::PROTOCOL_ERROR, "a close payload can't be a single byte"))),
+                    _ => Some(u16::from_be_bytes([frame.payload[0], frame.payload[1]])),
+                };
+                if code.is_some_and(|code| !valid_close_code(code)) {
+                    return Err(Err(violation(close_code::PROTOCOL_ERROR, "invalid close code")));
+                }
+                if frame.payload.len() > 2 && str::from_utf8(&frame.payload[2..]).is_err() {
+                    return Err(Err(violation(close_code::INVALID_DATA, "close reason isn't UTF-8")));
+                }
+                //Answering with the same code finishes the closing handshake.
+                let sent = self.send_close(code.unwrap_or(close_code::NORMAL), "");
+                sent.map(|_| None).map_err(Ok)
+            }
+            CONTINUATION => {
+                let (opcode, mut data) = match self.partial.take() {
+                    Some(partial) => partial,
+                    None => return Err(Err(violation(close_code::PROTOCOL_ERROR, "continuation without a message to continue"))),
+                };
+                if data.len() + frame.payload.len() > self.max_message {
+                    return Err(Err(violation(close_code::TOO_BIG, "message too big")));
+                }
+                data.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    message(opcode, data).map(Some).map_err(Err)
+                } else {
+                    self.partial = Some((opcode, data));
+                    Ok(None)
+                }
+            }
+            opcode => {
+                if self.partial.is_some() {
+                    return Err(Err(violation(close_code::PROTOCOL_ERROR, "new message before the last one was finished")));
+                }
+                if frame.fin {
+                    message(opcode, frame.payload).map(Some).map_err(Err)
+                } else {
+                    self.partial = Some((opcode, frame.payload));
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+fn message(opcode: u8, data: Vec<u8>) -> Result<Message, Violation> {
+    if opcode == BINARY {
+        return Ok(Message::Binary(data));
+    }
+    String::from_utf8(data).map(Message::Text).map_err(|_| violation(close_code::INVALID_DATA, "text message isn't UTF-8"))
+}
+
+impl<'a> Drop for WebSocket<'a> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.send_close(close_code::NORMAL, "");
+        }
+    }
+}
+
+impl<'a> fmt::Debug for WebSocket<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocket").field("closed", &self.closed).finish()
+    }
+}
+
+/// The messages on a WebSocket, see `WebSocket::messages`.
+pub struct Messages<'b, 'a: 'b> {
+    socket: &'b mut WebSocket<'a>,
+}
+
+impl<'b, 'a> Iterator for Messages<'b, 'a> {
+    type Item = io::Result<Message>;
+
+    fn next(&mut self) -> Option<io::Result<Message>> {
+        self.socket.recv().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::Limits;
+
+    fn request(raw: &str) -> Request {
+        Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0
+    }
+
+    //A client frame, masked the way clients have to.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accepts_the_rfc_example() {
+        let request = request(
+            "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        );
+        assert_eq!(handshake(&request).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn turns_down_bad_handshakes() {
+        let status = |raw: &str| handshake(&request(raw)).unwrap_err().status;
+        let key = "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        assert_eq!(status("GET / HTTP/1.1\r\nHost: a\r\n\r\n"), 426);
+        assert_eq!(status(&format!("GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{}\r\n", key)), 426);
+        let wrong_version = handshake(&request(&format!(
+            "GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{}Sec-WebSocket-Version: 8\r\n\r\n",
+            key
+        )))
+        .unwrap_err();
+        assert_eq!(wrong_version.headers.get("Sec-WebSocket-Version"), Some("13"));
+        let short_key = "GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                         Sec-WebSocket-Key: c2hvcnQ=\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(status(short_key), 400);
+        assert_eq!(status("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n"), 405);
+    }
+
+    #[test]
+    fn parses_masked_frames() {
+        let mut frame = client_frame(0x81, b"Hello");
+        assert_eq!(parse_frame(&frame[..4], 100), Ok(None));
+        let (parsed, used) = parse_frame(&frame, 100).unwrap().unwrap();
+        assert_eq!(parsed, Frame { fin: true, opcode: TEXT, payload: b"Hello".to_vec() });
+        assert_eq!(used, frame.len());
+
+        //A 16 bit length, and another frame right behind it.
+        let long = vec![7; 300];
+        let mut two = client_frame(0x02, &long);
+        two.extend_from_slice(&client_frame(0x80, b""));
+        let (parsed, used) = parse_frame(&two, 1000).unwrap().unwrap();
+        assert_eq!((parsed.fin, parsed.opcode, parsed.payload), (false, BINARY, long));
+        assert_eq!(parse_frame(&two[used..], 1000).unwrap().unwrap().0.opcode, CONTINUATION);
+
+        //Too big is noticed before the payload is there.
+        assert_eq!(parse_frame(&two[..4], 100).unwrap_err().code, close_code::TOO_BIG);
+
+        frame[1] &= 0x7F;
+        assert_eq!(parse_frame(&frame, 100).unwrap_err().code, close_code::PROTOCOL_ERROR);
+        assert_eq!(parse_frame(&client_frame(0xC1, b"x"), 100).unwrap_err().code, close_code::PROTOCOL_ERROR);
+        assert_eq!(parse_frame(&client_frame(0x83, b"x"), 100).unwrap_err().code, close_code::PROTOCOL_ERROR);
+        assert_eq!(parse_frame(&client_frame(0x09, b"x"), 100).unwrap_err().code, close_code::PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn writes_unmasked_frames() {
+        let mut out = Vec::new();
+        write_frame(&mut out, TEXT, b"Hi").unwrap();
+        assert_eq!(out, b"\x81\x02Hi");
+        out.clear();
+        write_frame(&mut out, BINARY, &[0; 200]).unwrap();
+        assert_eq!(&out[..4], &[0x82, 126, 0, 200]);
+        assert_eq!(out.len(), 204);
+    }
+}
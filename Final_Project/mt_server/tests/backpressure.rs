@@ -0,0 +1,109 @@
+//A server with a tiny queue should turn clients away with 503 instead of queueing them forever.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{Request, Response, Router, Server, ShutdownHandle};
+
+//One worker and room for one more in the queue. Requests hold the worker until they're released.
+fn start() -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>, mpsc::Sender<()>) {
+    let (release, wait) = mpsc::channel::<()>();
+    let wait = Arc::new(Mutex::new(wait));
+    let mut router = Router::new();
+    router.get("/", move |_: Request| {
+        wait.lock().unwrap().recv().unwrap();
+        Response::ok().with_body("done")
+    });
+
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(1).queue_capacity(1);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    (addr, handle, thread::spawn(move || server.run().unwrap()), release)
+}
+
+#[test]
+fn full_queue_gets_503() {
+    let (addr, handle, running, release) = start();
+
+    let connect = || {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        //Let the accept loop get to it before the next one arrives.
+        thread::sleep(Duration::from_millis(100));
+        client
+    };
+    //One being worked on, one waiting in the queue and one too many.
+    let mut working = connect();
+    let mut queued = connect();
+    let mut rejected = connect();
+
+    let mut out = String::new();
+    rejected.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(out.contains("Retry-After: 1\r\n"));
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    for client in &mut [&mut working, &mut queued] {
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\ndone"));
+    }
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn turned_away_clients_dont_hold_up_accepting() {
+    let (addr, handle, running, release) = start();
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+    let connect = || {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        client
+    };
+    let mut working = connect();
+    let mut queued = connect();
+
+    //This one never stops sending, a little at a time.
+    let mut slow = TcpStream::connect(addr).unwrap();
+    let trickle = thread::spawn(move || {
+        for _ in 0..40 {
+            if slow.write_all(b"X-Slow: yes\r\n").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let started = Instant::now();
+    let mut next = TcpStream::connect(addr).unwrap();
+    next.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    next.write_all(request).unwrap();
+    let mut out = String::new();
+    next.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    for client in &mut [&mut working, &mut queued] {
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\ndone"));
+    }
+    trickle.join().unwrap();
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
@@ -0,0 +1,133 @@
+//CGI programs run by the real server: what they get to see, what they write back, and what
+//happens to ones that hang or write nonsense. The programs are little shell scripts.
+#![cfg(unix)]
+extern crate mt_server;
+
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{Cgi, Router, Server, ShutdownHandle};
+
+//A directory of scripts that's removed again when the test is done.
+struct Scripts(PathBuf);
+
+impl Scripts {
+    fn new(name: &str) -> Scripts {
+        let dir = env::temp_dir().join(format!("mt_server_cgi_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scripts(dir)
+    }
+
+    fn add(&self, name: &str, body: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+}
+
+impl Drop for Scripts {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn start(router: Router) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>) {
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(2);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    (addr, handle, thread::spawn(move || server.run().unwrap()))
+}
+
+fn fetch(addr: SocketAddr, request: &str) -> String {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(request.as_bytes()).unwrap();
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn runs_programs_with_the_request() {
+    let scripts = Scripts::new("env");
+    let env = scripts.add(
+        "env.sh",
+        "printf 'Content-Type: text/plain\\r\\nX-Script: env\\r\\n\\r\\n'\n\
+         echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING\"\n\
+         echo \"$CONTENT_LENGTH $CONTENT_TYPE $HTTP_X_THING $REMOTE_ADDR\"\n\
+         echo \"[$HTTP_AUTHORIZATION]\"\n\
+         cat\n",
+    );
+    let status = scripts.add("status.sh", "echo 'Status: 418 Short and Stout'\necho 'Content-Type: text/plain'\necho\nprintf teapot\n");
+    let redirect = scripts.add("redirect.sh", "echo 'Location: /elsewhere'\necho\n");
+    let mut router = Router::new();
+    router.any("/env/*path_info", Cgi::new(env));
+    router.get("/status", Cgi::new(status));
+    router.get("/redirect", Cgi::new(redirect));
+    let (addr, handle, running) = start(router);
+
+    let out = fetch(
+        addr,
+        "POST /env/a/b?x=1 HTTP/1.1\r\nHost: a\r\nX-Thing: thing\r\nAuthorization: secret\r\n\
+         Content-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+    );
+    let (head, body) = out.split_at(out.find("\r\n\r\n").unwrap() + 4);
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", out);
+    assert!(head.contains("X-Script: env\r\n"));
+    assert!(head.contains("Content-Type: text/plain\r\n"));
+    assert_eq!(body, "POST /env /a/b x=1\n5 text/plain thing 127.0.0.1\n[]\nhello");
+
+    let out = fetch(addr, "GET /status HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert!(out.starts_with("HTTP/1.1 418 "), "{}", out);
+    assert!(!out.contains("Status:"));
+    assert!(out.ends_with("\r\n\r\nteapot"));
+
+    let out = fetch(addr, "GET /redirect HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert!(out.starts_with("HTTP/1.1 302 "), "{}", out);
+    assert!(out.contains("Location: /elsewhere\r\n"));
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn runaway_and_broken_programs() {
+    let scripts = Scripts::new("broken");
+    //Whatever the script started is killed along with it, so the marker never shows up.
+    let marker = scripts.0.join("still running");
+    let slow = scripts.add("slow.sh", &format!("(sleep 1; touch '{}') &\nsleep 10\n", marker.display()));
+    let garbage = scripts.add("garbage.sh", "echo 'this is not a header'\n");
+    let chatty = scripts.add("chatty.sh", "echo 'Content-Type: text/plain'\necho\nwhile true; do echo lots; done\n");
+    let mut router = Router::new();
+    router.get("/slow", Cgi::new(slow).timeout(Duration::from_millis(200)));
+    router.get("/garbage", Cgi::new(garbage));
+    router.get("/chatty", Cgi::new(chatty).max_output(64 * 1024));
+    router.get("/missing", Cgi::new(scripts.0.join("missing.sh")));
+    let (addr, handle, running) = start(router);
+
+    let status = |path: &str| {
+        let out = fetch(addr, &format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path));
+        out.lines().next().unwrap().to_string()
+    };
+    let started = Instant::now();
+    assert_eq!(status("/slow"), "HTTP/1.1 504 Gateway Timeout");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(status("/garbage"), "HTTP/1.1 502 Bad Gateway");
+    assert_eq!(status("/chatty"), "HTTP/1.1 502 Bad Gateway");
+    assert_eq!(status("/missing"), "HTTP/1.1 500 Internal Server Error");
+
+    thread::sleep(Duration::from_millis(1200));
+    assert!(!marker.exists());
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
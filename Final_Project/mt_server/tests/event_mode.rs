@@ -0,0 +1,286 @@
+//In the event mode idle keep-alive connections don't take up workers, so a server with two
+//workers can keep answering lots of clients that all stay connected. Requests are read before
+//they're queued, so urgent paths can go ahead of the rest.
+#![cfg(target_os = "linux")]
+extern crate mt_server;
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{ConnectionConfig, IoMode, Priority, Request, Response, Router, Server, ShutdownHandle, Upgraded};
+
+fn start(config: ConnectionConfig) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>) {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello"));
+    router.get("/:name", |req: Request| Response::ok().with_body(req.params.get("name").unwrap().to_string()));
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .workers(2)
+        .io_mode(IoMode::Event)
+        .connection_config(config);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    (addr, handle, thread::spawn(move || server.run().unwrap()))
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+//Reads one response off of a connection that stays open. Every body in here is short.
+fn read_response(client: &mut TcpStream) -> String {
+    let mut out = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        if let Some(end) = out.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&out[..end]).into_owned();
+            let len: usize = head
+                .lines()
+                .filter_map(|line| line.strip_prefix("Content-Length: "))
+                .map(|len| len.parse().unwrap())
+                .next()
+                .unwrap_or(0);
+            if out.len() >= end + 4 + len {
+                return String::from_utf8(out).unwrap();
+            }
+        }
+        let n = client.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed early: {}", String::from_utf8_lossy(&out));
+        out.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[test]
+fn idle_connections_dont_hold_workers() {
+    let (addr, handle, running) = start(ConnectionConfig::default());
+
+    //Far more open connections than workers, all of them kept alive.
+    let mut clients: Vec<TcpStream> = (0..50).map(|_| connect(addr)).collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        write!(client, "GET /first{} HTTP/1.1\r\nHost: a\r\n\r\n", i).unwrap();
+        assert!(read_response(client).ends_with(&format!("\r\n\r\nfirst{}", i)));
+    }
+    //Going back over them the other way round, every one still gets an answer.
+    for (i, client) in clients.iter_mut().enumerate().rev() {
+        write!(client, "GET /second{} HTTP/1.1\r\nHost: a\r\n\r\n", i).unwrap();
+        assert!(read_response(client).ends_with(&format!("\r\n\r\nsecond{}", i)));
+    }
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn pipelined_and_split_requests() {
+    let (addr, handle, running) = start(ConnectionConfig::default());
+    let mut client = connect(addr);
+
+    //Two requests in one go are answered in order. The answers can come back in one read too.
+    write!(client, "GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let mut out = read_response(&mut client);
+    if !out.ends_with("\r\n\r\nb") {
+        out += &read_response(&mut client);
+    }
+    assert_eq!(out.matches("HTTP/1.1 200 OK\r\n").count(), 2, "{}", out);
+    let first = out.find("\r\n\r\na").unwrap();
+    assert!(out[first..].ends_with("\r\n\r\nb"));
+
+    //A request that trickles in is only answered once it's all there.
+    client.write_all(b"GET /c HTTP/1.1\r\n").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    client.write_all(b"Host: a\r\nConnection: close\r\n\r\n").unwrap();
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    assert!(out.contains("Connection: close\r\n"));
+    assert!(out.ends_with("\r\n\r\nc"));
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn idle_and_stalled_connections_time_out() {
+    let config = ConnectionConfig { idle_timeout: Some(Duration::from_millis(200)), ..ConnectionConfig::default() };
+    let (addr, handle, running) = start(config);
+
+    //Nothing sent at all: closed without a word.
+    let mut idle = connect(addr);
+    let started = Instant::now();
+    let mut out = String::new();
+    idle.read_to_string(&mut out).unwrap();
+    assert_eq!(out, "");
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    //Half a request: 408.
+    let mut stalled = connect(addr);
+    stalled.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    let mut out = String::new();
+    stalled.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", out);
+
+    //A request that keeps trickling in still has to be done within the timeout.
+    let mut trickling = connect(addr);
+    trickling.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(2) {
+        if trickling.write_all(b"X").is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut out = String::new();
+    let _ = trickling.read_to_string(&mut out);
+    assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", out);
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    //Garbage: 400.
+    let mut bad = connect(addr);
+    bad.write_all(b"NOT HTTP\r\n\r\n").unwrap();
+    let mut out = String::new();
+    bad.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", out);
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn urgent_paths_skip_the_queue() {
+    let mut router = Router::new();
+    router.get("/slow", |_: Request| {
+        thread::sleep(Duration::from_millis(200));
+        Response::ok().with_body("slow")
+    });
+    router.get("/health", |_: Request| Response::ok().with_body("ok"));
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .workers(1)
+        .io_mode(IoMode::Event)
+        .priority("/health", Priority::High);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    //One slow request keeps the only worker busy and two more wait behind it, then the
+    //health check comes in last.
+    let answered = Arc::new(Mutex::new(Vec::new()));
+    let clients: Vec<_> = ["/slow", "/slow", "/slow", "/health"]
+        .iter()
+        .map(|&path| {
+            let mut client = connect(addr);
+            write!(client, "GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path).unwrap();
+            //Gives the event loop time to queue them in this order.
+            thread::sleep(Duration::from_millis(30));
+            let answered = Arc::clone(&answered);
+            thread::spawn(move || {
+                let out = read_response(&mut client);
+                assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{}", out);
+                answered.lock().unwrap().push(path);
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    assert_eq!(*answered.lock().unwrap(), ["/slow", "/health", "/slow", "/slow"]);
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn full_queue_gets_503_with_requests_still_unread() {
+    let (release, wait) = mpsc::channel::<()>();
+    let wait = Mutex::new(wait);
+    let mut router = Router::new();
+    router.get("/", move |_: Request| {
+        let _ = wait.lock().unwrap().recv();
+        Response::ok().with_body("done")
+    });
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .workers(1)
+        .queue_capacity(1)
+        .io_mode(IoMode::Event);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    //One being worked on and one waiting in the queue.
+    let mut clients: Vec<TcpStream> = (0..2)
+        .map(|_| {
+            let mut client = connect(addr);
+            client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+            thread::sleep(Duration::from_millis(100));
+            client
+        })
+        .collect();
+
+    //This one is turned away with more requests pipelined behind the first than the server
+    //has read yet. Closing on those unread bytes would reset the connection under the 503.
+    let mut rejected = connect(addr);
+    rejected.write_all("GET / HTTP/1.1\r\nHost: a\r\n\r\n".repeat(1000).as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let out = read_response(&mut rejected);
+    assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", out);
+    //And then it ends properly instead of with a reset.
+    assert_eq!(rejected.read(&mut [0; 16]).unwrap(), 0);
+
+    for client in &mut clients {
+        release.send(()).unwrap();
+        assert!(read_response(client).ends_with("\r\n\r\ndone"));
+    }
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn priorities_need_the_event_mode() {
+    let server = Server::bind("127.0.0.1:0", Router::new())
+        .unwrap()
+        .io_mode(IoMode::Blocking)
+        .priority("/health", Priority::High);
+    assert_eq!(server.run().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn upgraded_connections_have_the_idle_timeout() {
+    let mut router = Router::new();
+    //Tells the client how its first read went.
+    router.get("/upgrade", |_: Request| {
+        Response::new(101).with_header("Upgrade", "test").with_upgrade(|mut upgraded: Upgraded| {
+            let mut buf = [0; 16];
+            let answer: &[u8] = match upgraded.read(&mut buf) {
+                Ok(_) => b"read",
+                Err(_) => b"timed out",
+            };
+            let _ = upgraded.write_all(answer);
+        })
+    });
+    let config = ConnectionConfig { idle_timeout: Some(Duration::from_millis(200)), ..ConnectionConfig::default() };
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .workers(1)
+        .io_mode(IoMode::Event)
+        .connection_config(config);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    let mut client = connect(addr);
+    write!(client, "GET /upgrade HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: test\r\n\r\n").unwrap();
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", out);
+    assert!(out.ends_with("\r\n\r\ntimed out"), "{}", out);
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
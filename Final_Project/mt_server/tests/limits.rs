@@ -0,0 +1,140 @@
+//One client can't hold more connections than it's allowed or go over a route's rate limit.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{Chain, IoMode, RateLimit, Request, Response, Router, Server};
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+//Sends a request on a connection that's kept open and reads the response to it.
+fn get(client: &mut TcpStream, path: &str) -> String {
+    write!(client, "GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path).unwrap();
+    let mut out = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        //Done once the head is in and the body is as long as the head says.
+        if let Some(end) = out.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&out[..end]).into_owned();
+            let len: usize = head
+                .lines()
+                .filter_map(|line| line.strip_prefix("Content-Length: "))
+                .map(|len| len.parse().unwrap())
+                .next()
+                .unwrap_or(0);
+            if out.len() >= end + 4 + len {
+                return String::from_utf8(out).unwrap();
+            }
+        }
+        let n = client.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed early: {}", String::from_utf8_lossy(&out));
+        out.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[test]
+fn limits_connections_and_requests_per_client() {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello\n"));
+    router.get("/slow", Chain::new(|_: Request| Response::ok().with_body("slow\n")).with(RateLimit::new(1.0, 2)));
+
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(4).max_connections_per_ip(2);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    //Two connections are fine, the third one is turned away.
+    let mut first = connect(addr);
+    let mut second = connect(addr);
+    assert!(get(&mut first, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(get(&mut second, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    let mut third = connect(addr);
+    write!(third, "GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let mut out = String::new();
+    third.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{}", out);
+    assert!(out.contains("Retry-After: 1\r\n"));
+
+    //The route's bucket holds two requests, and the rest of the site isn't limited.
+    assert!(get(&mut first, "/slow").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(get(&mut first, "/slow").starts_with("HTTP/1.1 200 OK\r\n"));
+    let limited = get(&mut second, "/slow");
+    assert!(limited.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{}", limited);
+    assert!(limited.contains("Retry-After: 1\r\n"));
+    assert!(get(&mut second, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+
+    //Once a connection closes there's room for another one.
+    drop(first);
+    let started = Instant::now();
+    loop {
+        let mut client = connect(addr);
+        write!(client, "GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        if out.starts_with("HTTP/1.1 200 OK\r\n") {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "the closed connection was never let go");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    drop(second);
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+//A client over its limit that keeps sending can't hold up the thread that turned it away.
+fn rejects_without_waiting(mode: IoMode) {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello\n"));
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(2).max_connections_per_ip(1).io_mode(mode);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    let mut first = connect(addr);
+    assert!(get(&mut first, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+
+    let mut slow = connect(addr);
+    let trickle = thread::spawn(move || {
+        for _ in 0..40 {
+            if slow.write_all(b"X-Slow: yes\r\n").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let started = Instant::now();
+    let mut next = connect(addr);
+    write!(next, "GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let mut out = String::new();
+    next.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{}", out);
+    assert!(get(&mut first, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+
+    trickle.join().unwrap();
+    drop(first);
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn turning_clients_away_doesnt_block_accepting() {
+    rejects_without_waiting(IoMode::Blocking);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn turning_clients_away_doesnt_block_the_event_loop() {
+    rejects_without_waiting(IoMode::Event);
+}
@@ -0,0 +1,46 @@
+//The server can serve its pool's metrics in the Prometheus text format.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use mt_server::{Request, Response, Router, Server};
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(client, "GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn metrics_endpoint_reports_the_pool() {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello"));
+
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(3).metrics_path("/metrics");
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    //Other paths still go to the router.
+    assert!(get(addr, "/").ends_with("\r\n\r\nhello"));
+    //The worker records the job as finished just after the connection closes.
+    thread::sleep(Duration::from_millis(100));
+
+    let out = get(addr, "/metrics");
+    assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(out.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(out.contains("\nmt_server_pool_workers 3\n"));
+    //The request for / has finished, and the one asking for the metrics is still running.
+    assert!(out.contains("\nmt_server_pool_jobs_completed_total 1\n"));
+    assert!(out.contains("\nmt_server_pool_active_workers 1\n"));
+    assert!(out.contains("\nmt_server_pool_job_run_seconds_count 1\n"));
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
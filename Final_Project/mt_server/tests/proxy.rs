@@ -0,0 +1,159 @@
+//The proxy against stub upstreams: what the upstream gets to see, bodies both ways, taking
+//turns between upstreams and what the client gets when an upstream is down or too slow.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use mt_server::{Proxy, Request, Response, Router, Server, ShutdownHandle};
+
+//A stub upstream that answers every connection with `respond(request head, request body)`
+//and closes it.
+fn upstream<F>(respond: F) -> SocketAddr
+    where
+        F: Fn(&str, &[u8]) -> Vec<u8> + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                if reader.read_line(&mut head).unwrap() == 0 {
+                    break;
+                }
+            }
+            let len = head
+                .lines()
+                .filter_map(|line| line.strip_prefix("Content-Length: "))
+                .map(|len| len.parse().unwrap())
+                .next()
+                .unwrap_or(0);
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            let _ = (&stream).write_all(&respond(&head, &body));
+        }
+    });
+    addr
+}
+
+fn start(router: Router) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>) {
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(2);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    (addr, handle, thread::spawn(move || server.run().unwrap()))
+}
+
+fn fetch(addr: SocketAddr, request: &str) -> String {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(request.as_bytes()).unwrap();
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn forwards_requests_and_responses() {
+    //Sends back what it got, so the test can see it.
+    let echo = upstream(|head, body| {
+        let body = format!("{}{}", head, String::from_utf8_lossy(body));
+        format!("HTTP/1.1 201 Created\r\nContent-Length: {}\r\nConnection: close\r\nX-Upstream: echo\r\n\r\n{}", body.len(), body).into_bytes()
+    });
+    //Answers in chunks.
+    let chunked = upstream(|_, _| {
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n".to_vec()
+    });
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("local"));
+    router.any("/api/*rest", Proxy::new(echo.to_string()));
+    router.any("/stream", Proxy::new(chunked.to_string()));
+    let (addr, handle, running) = start(router);
+
+    let out = fetch(
+        addr,
+        "POST /api/items?x=1 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\
+         Content-Length: 5\r\nConnection: close\r\n\r\nhello",
+    );
+    let (head, body) = out.split_at(out.find("\r\n\r\n").unwrap() + 4);
+    assert!(head.starts_with("HTTP/1.1 201 Created\r\n"), "{}", out);
+    assert!(head.contains("X-Upstream: echo\r\n"));
+    //The upstream's Connection: close was its own business, ours comes from the client.
+    assert_eq!(head.matches("Connection:").count(), 1);
+    assert!(body.starts_with("POST /api/items?x=1 HTTP/1.1\r\n"), "{}", body);
+    assert!(body.contains(&format!("Host: {}\r\n", echo)));
+    assert!(body.contains("X-Forwarded-For: 10.0.0.1, 127.0.0.1\r\n"));
+    assert!(body.contains("X-Forwarded-Host: example.com\r\n"));
+    assert!(body.ends_with("Content-Length: 5\r\nConnection: close\r\n\r\nhello"));
+
+    //The chunks are taken apart and put back together for our own client.
+    let out = fetch(addr, "GET /stream HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert!(out.contains("Transfer-Encoding: chunked\r\n"), "{}", out);
+    assert!(out.ends_with("\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n"), "{}", out);
+    //An HTTP/1.0 client gets the same body without chunks.
+    let out = fetch(addr, "GET /stream HTTP/1.0\r\n\r\n");
+    assert!(out.ends_with("\r\n\r\nhello, world"), "{}", out);
+
+    //Routes that aren't proxied are still ours.
+    assert!(fetch(addr, "GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").ends_with("\r\n\r\nlocal"));
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn takes_turns_and_skips_dead_upstreams() {
+    let named = |name: &'static str| upstream(move |_, _| format!("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n{}", name).into_bytes());
+    let (a, b) = (named("a"), named("b"));
+    //A port nothing listens on anymore.
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let mut router = Router::new();
+    router.any("/*path", Proxy::new(a.to_string()).upstream(dead.to_string()).upstream(b.to_string()));
+    let (addr, handle, running) = start(router);
+
+    let answers: String = (0..6)
+        .map(|_| {
+            let out = fetch(addr, "GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+            assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{}", out);
+            out[out.len() - 1..].to_string()
+        })
+        .collect();
+    //The dead upstream's turns go to the one after it.
+    assert_eq!(answers, "abbabb");
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn upstream_failures_are_502_and_504() {
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let slow = upstream(|_, _| {
+        thread::sleep(Duration::from_millis(500));
+        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+    });
+    let garbage = upstream(|_, _| b"hello there\r\n\r\n".to_vec());
+
+    let mut router = Router::new();
+    router.any("/dead", Proxy::new(dead.to_string()));
+    router.any("/slow", Proxy::new(slow.to_string()).timeout(Duration::from_millis(100)));
+    router.any("/garbage", Proxy::new(garbage.to_string()));
+    let (addr, handle, running) = start(router);
+
+    let status = |path: &str| {
+        let out = fetch(addr, &format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path));
+        out.lines().next().unwrap().to_string()
+    };
+    assert_eq!(status("/dead"), "HTTP/1.1 502 Bad Gateway");
+    assert_eq!(status("/slow"), "HTTP/1.1 504 Gateway Timeout");
+    assert_eq!(status("/garbage"), "HTTP/1.1 502 Bad Gateway");
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
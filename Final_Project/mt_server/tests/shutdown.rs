@@ -0,0 +1,95 @@
+//These tests run a real Server on an ephemeral port and shut it down through its handle.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mt_server::{Request, Response, Router, Server, ShutdownHandle};
+
+fn start(grace_period: Duration) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>) {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello"));
+    router.get("/slow/:ms", |req: Request| {
+        let ms = req.param("ms").unwrap().parse().unwrap();
+        thread::sleep(Duration::from_millis(ms));
+        Response::ok().with_body("done")
+    });
+
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(2).grace_period(grace_period);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+    (addr, handle, running)
+}
+
+fn send(addr: SocketAddr, raw: &str) -> TcpStream {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(raw.as_bytes()).unwrap();
+    client
+}
+
+fn read_all(client: &mut TcpStream) -> String {
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn stops_accepting_after_shutdown() {
+    let (addr, handle, running) = start(Duration::from_secs(5));
+
+    let mut client = send(addr, "GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert!(read_all(&mut client).ends_with("\r\n\r\nhello"));
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn in_flight_requests_finish() {
+    let (addr, handle, running) = start(Duration::from_secs(5));
+
+    let mut client = send(addr, "GET /slow/300 HTTP/1.1\r\nHost: a\r\n\r\n");
+    //Give the worker time to pick the request up before shutting down.
+    thread::sleep(Duration::from_millis(100));
+    handle.shutdown();
+
+    let out = read_all(&mut client);
+    assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(out.ends_with("\r\n\r\ndone"));
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn idle_keep_alive_connections_are_closed() {
+    let (addr, handle, running) = start(Duration::from_secs(5));
+
+    let mut client = send(addr, "GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    let mut first = [0; 17];
+    client.read_exact(&mut first).unwrap();
+    assert_eq!(&first, b"HTTP/1.1 200 OK\r\n");
+
+    //The connection is idle and would otherwise stay open for the 5 second idle timeout.
+    let start = Instant::now();
+    handle.shutdown();
+    assert!(running.join().unwrap());
+    assert!(start.elapsed() < Duration::from_secs(2));
+    read_all(&mut client);
+}
+
+#[test]
+fn gives_up_on_requests_after_the_grace_period() {
+    let (addr, handle, running) = start(Duration::from_millis(100));
+
+    let _client = send(addr, "GET /slow/3000 HTTP/1.1\r\nHost: a\r\n\r\n");
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    handle.shutdown();
+
+    assert!(!running.join().unwrap());
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,17 @@
+//The handler is installed for the whole process, so this gets a test binary of its own.
+//Anywhere else it would swallow a SIGTERM meant for every other test in the binary.
+#![cfg(unix)]
+extern crate libc;
+extern crate mt_server;
+
+use mt_server::signal;
+
+#[test]
+fn remembers_sigterm() {
+    signal::install().unwrap();
+    assert!(!signal::received());
+    unsafe {
+        libc::raise(libc::SIGTERM);
+    }
+    assert!(signal::received());
+}
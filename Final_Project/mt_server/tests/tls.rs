@@ -0,0 +1,91 @@
+//The server can answer HTTPS requests next to plain ones, using a certificate made up on the spot.
+extern crate mt_server;
+extern crate rcgen;
+extern crate rustls;
+
+use std::convert::TryFrom;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use mt_server::{Request, Response, Router, Server, TlsConfig};
+
+fn client_config(cert: &rcgen::Certificate) -> Arc<ClientConfig> {
+    //The client only trusts our self-signed certificate.
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.der().clone()).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+fn connect(addr: SocketAddr, config: &Arc<ClientConfig>) -> StreamOwned<ClientConnection, TcpStream> {
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    StreamOwned::new(ClientConnection::new(Arc::clone(config), name).unwrap(), socket)
+}
+
+#[test]
+fn serves_https_with_a_self_signed_certificate() {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let tls = TlsConfig::from_pem(generated.cert.pem().as_bytes(), generated.signing_key.serialize_pem().as_bytes()).unwrap();
+
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello over tls"));
+    let server = Server::bind("127.0.0.1:0", router).unwrap().https("127.0.0.1:0", tls).unwrap();
+    let plain = server.local_addr().unwrap();
+    let https = server.https_addr().unwrap().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    let config = client_config(&generated.cert);
+    let mut client = connect(https, &config);
+    //Two requests on the same session to make sure keep-alive works through TLS too.
+    write!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    //Keep-alive means there's no end of stream to read to, so we read until the body is in.
+    let mut first = String::new();
+    let mut buf = [0; 1024];
+    while !first.ends_with("hello over tls") {
+        let n = client.read(&mut buf).unwrap();
+        assert!(n > 0);
+        first.push_str(&String::from_utf8_lossy(&buf[..n]));
+    }
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{}", first);
+    assert!(first.ends_with("\r\n\r\nhello over tls"), "{}", first);
+    //We only offer HTTP/1.1, so that's what gets picked over h2.
+    assert_eq!(client.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    write!(client, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut second = String::new();
+    client.read_to_string(&mut second).unwrap();
+    assert!(second.contains("Connection: close\r\n"));
+    assert!(second.ends_with("hello over tls"));
+
+    //The plain listener still works.
+    let mut client = TcpStream::connect(plain).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(client, "GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    let mut out = String::new();
+    client.read_to_string(&mut out).unwrap();
+    assert!(out.ends_with("\r\n\r\nhello over tls"));
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn bad_pem_files_are_rejected() {
+    assert!(TlsConfig::from_pem(b"not a certificate", b"not a key").is_err());
+    assert!(TlsConfig::from_pem_files("/no/such/cert.pem", "/no/such/key.pem").is_err());
+}
@@ -0,0 +1,191 @@
+//A WebSocket client against the real server: the handshake, echoing messages, pings,
+//fragments, pushing updates from the server, and closing in both I/O modes.
+extern crate mt_server;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use mt_server::websocket::Message;
+use mt_server::{IoMode, Request, Response, Router, Server, ShutdownHandle, WebSocket, WebSocketHandler};
+
+fn start(mode: IoMode) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>) {
+    let mut router = Router::new();
+    router.get("/", |_: Request| Response::ok().with_body("hello"));
+    //Echoes everything back until the client closes.
+    router.get(
+        "/echo",
+        WebSocketHandler::new(|_: Request, mut ws: WebSocket| {
+            while let Some(Ok(message)) = ws.messages().next() {
+                ws.send(&message).unwrap();
+            }
+        }),
+    );
+    //Sends a tick every 50ms, and the name it was given when asked.
+    router.get(
+        "/ticks/:name",
+        WebSocketHandler::new(|req: Request, mut ws: WebSocket| {
+            let name = req.param("name").unwrap().to_string();
+            let mut tick = 0;
+            loop {
+                match ws.recv_timeout(Duration::from_millis(50)) {
+                    Ok(Some(_)) => ws.send(&Message::text(name.clone())).unwrap(),
+                    Ok(None) => return,
+                    Err(_) => {
+                        tick += 1;
+                        ws.send(&Message::text(format!("tick {}", tick))).unwrap();
+                    }
+                }
+            }
+        })
+        .max_message_size(16),
+    );
+    let server = Server::bind("127.0.0.1:0", router).unwrap().workers(2).io_mode(mode);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    (addr, handle, thread::spawn(move || server.run().unwrap()))
+}
+
+//Does the opening handshake and hands back the connection along with the response head.
+fn open(addr: SocketAddr, path: &str) -> (TcpStream, String) {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(
+        client,
+        "GET {} HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path
+    )
+    .unwrap();
+    //Read a byte at a time so none of the frames after the head are swallowed.
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    (client, String::from_utf8(head).unwrap())
+}
+
+fn send(client: &mut TcpStream, first: u8, payload: &[u8]) {
+    assert!(payload.len() < 126);
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![first, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    client.write_all(&frame).unwrap();
+}
+
+//Reads a server frame, which is never masked.
+fn recv(client: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    client.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames aren't masked");
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            client.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    client.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+fn echoes_messages(mode: IoMode) {
+    let (addr, handle, running) = start(mode);
+    let (mut client, head) = open(addr, "/echo");
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", head);
+    assert!(head.contains("Connection: Upgrade\r\n"));
+
+    send(&mut client, 0x81, b"hello");
+    assert_eq!(recv(&mut client), (0x81, b"hello".to_vec()));
+    send(&mut client, 0x82, &[0, 1, 2]);
+    assert_eq!(recv(&mut client), (0x82, vec![0, 1, 2]));
+
+    //A ping in the middle of a fragmented message is answered right away.
+    send(&mut client, 0x01, b"frag");
+    send(&mut client, 0x89, b"are you there");
+    send(&mut client, 0x00, b"men");
+    send(&mut client, 0x80, b"ted");
+    assert_eq!(recv(&mut client), (0x8A, b"are you there".to_vec()));
+    assert_eq!(recv(&mut client), (0x81, b"fragmented".to_vec()));
+
+    //The server answers a close with the same code and hangs up.
+    send(&mut client, 0x88, &[0x03, 0xE8]);
+    assert_eq!(recv(&mut client), (0x88, vec![0x03, 0xE8]));
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+
+    //Plain requests are still plain requests.
+    let mut plain = TcpStream::connect(addr).unwrap();
+    plain.write_all(b"GET /echo HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    let mut out = String::new();
+    plain.read_to_string(&mut out).unwrap();
+    assert!(out.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", out);
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn echoes_messages_blocking() {
+    echoes_messages(IoMode::Blocking);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn echoes_messages_event() {
+    echoes_messages(IoMode::Event);
+}
+
+#[test]
+fn pushes_updates_between_messages() {
+    let (addr, handle, running) = start(IoMode::Blocking);
+    let (mut client, _) = open(addr, "/ticks/dash");
+    assert_eq!(recv(&mut client), (0x81, b"tick 1".to_vec()));
+    assert_eq!(recv(&mut client), (0x81, b"tick 2".to_vec()));
+
+    //A message split over two writes with a tick in between still arrives whole.
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x81, 0x80 | 2];
+    frame.extend_from_slice(&mask);
+    frame.extend(b"hi".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    client.write_all(&frame[..3]).unwrap();
+    thread::sleep(Duration::from_millis(80));
+    client.write_all(&frame[3..]).unwrap();
+    let mut answer = recv(&mut client);
+    while answer.1.starts_with(b"tick") {
+        answer = recv(&mut client);
+    }
+    assert_eq!(answer, (0x81, b"dash".to_vec()));
+
+    //Too big for this endpoint.
+    send(&mut client, 0x81, &[b'a'; 20]);
+    let mut answer = recv(&mut client);
+    while answer.0 == 0x81 {
+        answer = recv(&mut client);
+    }
+    assert_eq!(answer.0, 0x88);
+    assert_eq!(&answer.1[..2], &1009u16.to_be_bytes());
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}
+
+#[test]
+fn unmasked_frames_are_a_protocol_error() {
+    let (addr, handle, running) = start(IoMode::Blocking);
+    let (mut client, _) = open(addr, "/echo");
+    client.write_all(b"\x81\x02hi").unwrap();
+    let (opcode, payload) = recv(&mut client);
+    assert_eq!(opcode, 0x88);
+    assert_eq!(&payload[..2], &1002u16.to_be_bytes());
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+
+    handle.shutdown();
+    assert!(running.join().unwrap());
+}